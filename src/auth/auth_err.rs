@@ -0,0 +1,64 @@
+use std::fmt::{Display, Formatter};
+use crate::tcp::response::ResponseError;
+
+#[derive(Debug)]
+pub enum AuthError {
+    NotAuthenticated,
+    InvalidCredentials,
+    Forbidden,
+    UserAlreadyExists,
+    UserDoesntExist,
+    CannotDropSelf,
+    InvalidUser(String),
+    FailedHash,
+    FailedWriteUsers,
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let err_msg: String = match self {
+            AuthError::NotAuthenticated => "Connection must authenticate with the 'auth' command first".to_string(),
+            AuthError::InvalidCredentials => "User name or password was incorrect".to_string(),
+            AuthError::Forbidden => "Only admin users can manage other users".to_string(),
+            AuthError::UserAlreadyExists => "Tried to create a user that already exists".to_string(),
+            AuthError::UserDoesntExist => "Tried to operate on a user that does not exist".to_string(),
+            AuthError::CannotDropSelf => "A user can't drop the user they are authenticated as".to_string(),
+            AuthError::InvalidUser(reason) => format!("Invalid user: {}", reason),
+            AuthError::FailedHash => "Failed to hash password".to_string(),
+            AuthError::FailedWriteUsers => "Failed to write user accounts".to_string(),
+        };
+        write!(f, "{}", err_msg)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl ResponseError for AuthError {
+    fn status(&self) -> u16 {
+        match self {
+            AuthError::NotAuthenticated => 401,
+            AuthError::InvalidCredentials => 401,
+            AuthError::Forbidden => 403,
+            AuthError::UserAlreadyExists => 409,
+            AuthError::UserDoesntExist => 404,
+            AuthError::CannotDropSelf => 400,
+            AuthError::InvalidUser(_) => 400,
+            AuthError::FailedHash => 500,
+            AuthError::FailedWriteUsers => 500,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            AuthError::NotAuthenticated => "not_authenticated",
+            AuthError::InvalidCredentials => "invalid_credentials",
+            AuthError::Forbidden => "forbidden",
+            AuthError::UserAlreadyExists => "user_already_exists",
+            AuthError::UserDoesntExist => "user_not_found",
+            AuthError::CannotDropSelf => "cannot_drop_self",
+            AuthError::InvalidUser(_) => "invalid_user",
+            AuthError::FailedHash => "hash_failed",
+            AuthError::FailedWriteUsers => "users_write_failed",
+        }
+    }
+}
@@ -0,0 +1,324 @@
+pub mod auth_err;
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use argon2::password_hash::SaltString;
+use argon2::password_hash::rand_core::OsRng;
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+use auth_err::AuthError;
+use auth_err::AuthError::{CannotDropSelf, FailedHash, FailedWriteUsers, Forbidden, InvalidCredentials, InvalidUser, UserAlreadyExists, UserDoesntExist};
+use crate::State;
+use crate::storage::StorageEngine;
+use crate::tables::table_err::TableError;
+use crate::tcp::frame::Frame;
+
+/*
+    Every connection has to authenticate as a user before it can send any other command:
+
+        {"command": "auth", "data": {"user": "alice", "password": "..."}}
+
+    Users are kept by the storage engine, with their passwords hashed with Argon2 and a random
+    salt per user. Admin users can also create and drop users and change anyone's password, while
+    other users can only change their own:
+
+        {"command": "create_user", "data": {"user": "bob", "password": "...", "admin": false}}
+        {"command": "drop_user", "data": {"user": "bob"}}
+        {"command": "change_password", "data": {"user": "bob", "password": "..."}}
+
+    `change_password` changes the connection's own user's password if `user` is left out. A db
+    without any users gets an `admin` user on startup with the password from the `admin_password`
+    setting.
+*/
+
+/// The name of the admin user created when the db has no users
+pub const INITIAL_ADMIN_USER: &str = "admin";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct User {
+    name: String,
+    // Tells apart users which had the same name at different times. Users stored before IDs
+    // existed are given one when they are loaded.
+    #[serde(default = "new_user_id")]
+    id: String,
+    // A PHC string holding the hash along with the salt and parameters it was made with
+    password_hash: String,
+    admin: bool,
+}
+
+fn new_user_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// The user a connection has authenticated as. A connection stays authenticated only for as long
+/// as this exact user exists, so it isn't carried over to a new user created under the name of
+/// one which was dropped.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub name: String,
+    id: String,
+}
+
+/// Every user account, written back to storage as a whole whenever one changes
+#[derive(Debug)]
+pub struct Users {
+    users: RwLock<BTreeMap<String, User>>,
+}
+
+/// Argon2id with its recommended parameters. Tests use the cheapest parameters allowed instead,
+/// as they authenticate many connections. A hash is always verified with the parameters it was
+/// made with.
+fn argon2() -> Argon2<'static> {
+    #[cfg(not(test))]
+    let params = Params::default();
+    #[cfg(test)]
+    let params = Params::new(Params::MIN_M_COST, Params::MIN_T_COST, Params::MIN_P_COST, None).expect("Minimum Argon2 parameters should be valid");
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+/// Hashing is slow on purpose, so it is run on tokio's blocking threads rather than holding up a
+/// worker thread which is serving other connections
+async fn hash_password(password: &str) -> Result<String, AuthError> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = argon2().hash_password(password.as_bytes(), &salt).map_err(|_| FailedHash)?;
+        Ok(hash.to_string())
+    }).await.map_err(|_| FailedHash)?
+}
+
+async fn verify_password(password: &str, password_hash: String) -> bool {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(password_hash.as_str())
+            .is_ok_and(|hash| argon2().verify_password(password.as_bytes(), &hash).is_ok())
+    }).await.unwrap_or(false)
+}
+
+/// Get a string field from a request, failing if it is missing, empty or holds control characters
+fn parse_string<'a>(data: &'a Map<String, Value>, key: &str) -> Result<&'a str, AuthError> {
+    match data.get(key) {
+        Some(Value::String(value)) if value.is_empty() => Err(InvalidUser(format!("'{}' can't be empty", key))),
+        Some(Value::String(value)) if value.contains(char::is_control) => Err(InvalidUser(format!("'{}' can't hold control characters", key))),
+        Some(Value::String(value)) => Ok(value),
+        _ => Err(InvalidUser(format!("Request was missing its '{}' string", key)))
+    }
+}
+
+impl Users {
+    /// Load every user from storage
+    pub fn load(storage: &dyn StorageEngine) -> Result<Self, TableError> {
+        let users: Vec<User> = match storage.read_users()? {
+            Some(users) => serde_json::from_value(users).map_err(|_| TableError::FailedDiskRead)?,
+            None => Vec::new()
+        };
+        let users = users.into_iter().map(|user| (user.name.clone(), user)).collect();
+        Ok(Self { users: RwLock::new(users) })
+    }
+
+    /// Create the initial admin user if there are no users yet. Without any users nobody could
+    /// authenticate, so a password has to be given for a db without any.
+    pub async fn bootstrap(&self, storage: &dyn StorageEngine, admin_password: Option<&str>) -> Result<(), String> {
+        if !self.users.read().expect("Users lock was poisoned").is_empty() {
+            return Ok(())
+        }
+        let Some(admin_password) = admin_password.filter(|password| !password.is_empty()) else {
+            return Err("There are no users yet, set 'admin_password' to create the initial admin user".to_string())
+        };
+        self.create(storage, INITIAL_ADMIN_USER, admin_password, true).await.map_err(|e| e.to_string())
+    }
+
+    /// Whether the user a connection authenticated as still exists
+    pub fn is_current(&self, identity: &Identity) -> bool {
+        self.users.read().expect("Users lock was poisoned").get(&identity.name).is_some_and(|user| user.id == identity.id)
+    }
+
+    fn is_admin(&self, name: &str) -> bool {
+        self.users.read().expect("Users lock was poisoned").get(name).is_some_and(|user| user.admin)
+    }
+
+    /// Check a user's password, returning who they are. Fails the same way whether or not the
+    /// user exists.
+    async fn verify(&self, name: &str, password: &str) -> Result<Identity, AuthError> {
+        let user = self.users.read().expect("Users lock was poisoned").get(name).cloned();
+        // Hashing is slow on purpose, so it is done without holding the lock. A password is
+        // still hashed for a user who doesn't exist so that they can't be told apart by timing.
+        let Some(user) = user else {
+            hash_password(password).await?;
+            return Err(InvalidCredentials)
+        };
+        match verify_password(password, user.password_hash).await {
+            true => Ok(Identity { name: user.name, id: user.id }),
+            false => Err(InvalidCredentials)
+        }
+    }
+
+    /// Make a change to the users, keeping it only if it is written to storage
+    fn modify<T>(&self, storage: &dyn StorageEngine, f: impl FnOnce(&mut BTreeMap<String, User>) -> Result<T, AuthError>) -> Result<T, AuthError> {
+        let mut users = self.users.write().expect("Users lock was poisoned");
+        let mut modified = users.clone();
+        let result = f(&mut modified)?;
+        let serialized = serde_json::to_value(modified.values().collect::<Vec<&User>>()).map_err(|_| FailedWriteUsers)?;
+        storage.write_users(&serialized).map_err(|_| FailedWriteUsers)?;
+        *users = modified;
+        Ok(result)
+    }
+
+    async fn create(&self, storage: &dyn StorageEngine, name: &str, password: &str, admin: bool) -> Result<(), AuthError> {
+        let password_hash = hash_password(password).await?;
+        self.modify(storage, |users| {
+            if users.contains_key(name) {
+                return Err(UserAlreadyExists)
+            }
+            users.insert(name.to_string(), User { name: name.to_string(), id: new_user_id(), password_hash, admin });
+            Ok(())
+        })
+    }
+}
+
+/// Authenticate a connection, returning the user it is now authenticated as
+pub async fn authenticate(state: &State, frame: Frame) -> Result<Identity, AuthError> {
+    let name = parse_string(&frame.data, "user").map_err(|_| InvalidCredentials)?;
+    let password = parse_string(&frame.data, "password").map_err(|_| InvalidCredentials)?;
+    state.users.verify(name, password).await
+}
+
+pub async fn create_user(state: &State, current_user: &str, frame: Frame) -> Result<(), AuthError> {
+    if !state.users.is_admin(current_user) {
+        return Err(Forbidden)
+    }
+    let name = parse_string(&frame.data, "user")?;
+    let password = parse_string(&frame.data, "password")?;
+    let admin = match frame.data.get("admin") {
+        None => false,
+        Some(Value::Bool(admin)) => *admin,
+        Some(_) => return Err(InvalidUser("'admin' must be a bool".to_string()))
+    };
+    state.users.create(state.storage.as_ref(), name, password, admin).await
+}
+
+pub fn drop_user(state: &State, current_user: &str, frame: Frame) -> Result<(), AuthError> {
+    if !state.users.is_admin(current_user) {
+        return Err(Forbidden)
+    }
+    let name = parse_string(&frame.data, "user")?;
+    if name == current_user {
+        return Err(CannotDropSelf)
+    }
+    state.users.modify(state.storage.as_ref(), |users| {
+        users.remove(name).map(|_| ()).ok_or(UserDoesntExist)
+    })
+}
+
+/// Change a user's password, the current user's own if the request doesn't name one
+pub async fn change_password(state: &State, current_user: &str, frame: Frame) -> Result<(), AuthError> {
+    let name = match frame.data.get("user") {
+        None => current_user,
+        Some(_) => parse_string(&frame.data, "user")?,
+    };
+    if name != current_user && !state.users.is_admin(current_user) {
+        return Err(Forbidden)
+    }
+    let password_hash = hash_password(parse_string(&frame.data, "password")?).await?;
+    state.users.modify(state.storage.as_ref(), |users| {
+        let user = users.get_mut(name).ok_or(UserDoesntExist)?;
+        user.password_hash = password_hash;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::storage::memory::MemoryStorage;
+    use crate::tables::DEFAULT_RECORDS_PER_SUB_TABLE;
+    use crate::tcp::frame::Command;
+
+    fn frame(command: Command, data: Value) -> Frame {
+        let Value::Object(data) = data else {
+            panic!("Request data must be an object")
+        };
+        Frame { command, table: String::new(), data }
+    }
+
+    async fn auth(state: &State, user: &str, password: &str) -> Result<Identity, AuthError> {
+        authenticate(state, frame(Command::Auth, json!({"user": user, "password": password}))).await
+    }
+
+    #[tokio::test]
+    async fn passwords_are_stored_salted_and_hashed() {
+        let storage = MemoryStorage::default();
+        let users = Users::load(&storage).unwrap();
+        assert!(users.bootstrap(&storage, None).await.is_err());
+        users.bootstrap(&storage, Some("hunter2")).await.unwrap();
+        users.create(&storage, "bob", "hunter2", false).await.unwrap();
+
+        let stored = storage.read_users().unwrap().unwrap().to_string();
+        assert!(!stored.contains("hunter2"));
+        let hashes: Vec<String> = serde_json::from_str::<Vec<User>>(&stored).unwrap().into_iter().map(|user| user.password_hash).collect();
+        assert_ne!(hashes[0], hashes[1]);
+
+        // The users are loaded back from storage, and the initial admin isn't created again
+        let users = Users::load(&storage).unwrap();
+        users.bootstrap(&storage, None).await.unwrap();
+        assert!(users.verify("bob", "hunter2").await.is_ok());
+        assert!(matches!(users.verify("bob", "hunter3").await, Err(InvalidCredentials)));
+        assert!(matches!(users.verify("carol", "hunter2").await, Err(InvalidCredentials)));
+    }
+
+    #[tokio::test]
+    async fn only_admins_manage_other_users() {
+        let state = State::initialize(Box::new(MemoryStorage::default()), DEFAULT_RECORDS_PER_SUB_TABLE);
+        state.users.bootstrap(state.storage.as_ref(), Some("admin password")).await.unwrap();
+        let admin = auth(&state, INITIAL_ADMIN_USER, "admin password").await.unwrap();
+
+        create_user(&state, admin.name.as_str(), frame(Command::CreateUser, json!({"user": "bob", "password": "old"}))).await.unwrap();
+        let result = create_user(&state, admin.name.as_str(), frame(Command::CreateUser, json!({"user": "bob", "password": "old"}))).await;
+        assert!(matches!(result, Err(UserAlreadyExists)));
+        let bob = auth(&state, "bob", "old").await.unwrap();
+
+        let result = create_user(&state, bob.name.as_str(), frame(Command::CreateUser, json!({"user": "carol", "password": "pw"}))).await;
+        assert!(matches!(result, Err(Forbidden)));
+        let result = change_password(&state, bob.name.as_str(), frame(Command::ChangePassword, json!({"user": "admin", "password": "pw"}))).await;
+        assert!(matches!(result, Err(Forbidden)));
+        let result = drop_user(&state, bob.name.as_str(), frame(Command::DropUser, json!({"user": "admin"})));
+        assert!(matches!(result, Err(Forbidden)));
+
+        // Anyone can change their own password, and admins can change anyone's
+        change_password(&state, bob.name.as_str(), frame(Command::ChangePassword, json!({"password": "new"}))).await.unwrap();
+        assert!(auth(&state, "bob", "old").await.is_err());
+        change_password(&state, admin.name.as_str(), frame(Command::ChangePassword, json!({"user": "bob", "password": "newer"}))).await.unwrap();
+        assert!(auth(&state, "bob", "newer").await.is_ok());
+
+        let result = drop_user(&state, admin.name.as_str(), frame(Command::DropUser, json!({"user": "admin"})));
+        assert!(matches!(result, Err(CannotDropSelf)));
+        drop_user(&state, admin.name.as_str(), frame(Command::DropUser, json!({"user": "bob"}))).unwrap();
+        assert!(!state.users.is_current(&bob));
+        assert!(auth(&state, "bob", "newer").await.is_err());
+        let result = drop_user(&state, admin.name.as_str(), frame(Command::DropUser, json!({"user": "bob"})));
+        assert!(matches!(result, Err(UserDoesntExist)));
+
+        // A new user under the same name doesn't bring back the dropped user's connections
+        create_user(&state, admin.name.as_str(), frame(Command::CreateUser, json!({"user": "bob", "password": "pw"}))).await.unwrap();
+        assert!(!state.users.is_current(&bob));
+        assert!(state.users.is_current(&auth(&state, "bob", "pw").await.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn users_stored_without_ids_are_given_one() {
+        let storage = MemoryStorage::default();
+        let users = Users::load(&storage).unwrap();
+        users.bootstrap(&storage, Some("hunter2")).await.unwrap();
+        let mut stored = storage.read_users().unwrap().unwrap();
+        stored[0].as_object_mut().unwrap().remove("id");
+        storage.write_users(&stored).unwrap();
+
+        let users = Users::load(&storage).unwrap();
+        let admin = users.verify(INITIAL_ADMIN_USER, "hunter2").await.unwrap();
+        assert!(!admin.id.is_empty());
+        assert!(users.is_current(&admin));
+    }
+}
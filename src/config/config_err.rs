@@ -0,0 +1,27 @@
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    UnknownFlag(String),
+    MissingValue(String),
+    UnknownSetting(String),
+    InvalidValue(String, String),
+    FailedReadConfigFile(String),
+    MalformedConfigFile(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let err_msg: String = match self {
+            ConfigError::UnknownFlag(flag) => format!("Unknown flag '{}'", flag),
+            ConfigError::MissingValue(flag) => format!("Flag '{}' is missing its value", flag),
+            ConfigError::UnknownSetting(key) => format!("Unknown setting '{}' in config file", key),
+            ConfigError::InvalidValue(key, reason) => format!("Invalid value for '{}': {}", key, reason),
+            ConfigError::FailedReadConfigFile(path) => format!("Failed to read config file '{}'", path),
+            ConfigError::MalformedConfigFile(reason) => format!("Config file is not valid TOML: {}", reason),
+        };
+        write!(f, "{}", err_msg)
+    }
+}
+
+impl std::error::Error for ConfigError {}
@@ -0,0 +1,309 @@
+pub mod config_err;
+
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use crate::logging::LogLevel;
+use crate::storage::durability::Durability;
+use crate::tables::DEFAULT_RECORDS_PER_SUB_TABLE;
+use crate::tcp::codec;
+use config_err::ConfigError;
+use config_err::ConfigError::{FailedReadConfigFile, InvalidValue, MalformedConfigFile, MissingValue, UnknownFlag, UnknownSetting};
+
+/*
+    Every setting can be given in three places. From highest to lowest precedence:
+
+    1. Command line flags, eg. `--listen 0.0.0.0:7000` or `--listen=0.0.0.0:7000`
+    2. Environment variables, eg. `ETCH_LISTEN=0.0.0.0:7000`
+    3. A TOML config file, eg. `listen = "0.0.0.0:7000"`
+
+    A setting given in more than one place takes its value from the one with the highest
+    precedence, and a setting given nowhere falls back to its default. The config file is read
+    from the path given by `--config` or `ETCH_CONFIG`, or from `etch.toml` in the working
+    directory if neither is set and that file exists.
+
+    Secrets have no flag, as the command line of a process can be read by anyone who can list
+    processes on the machine. They can only be given by environment variable or config file.
+*/
+
+const CONFIG_FLAG: &str = "--config";
+const CONFIG_ENV_VAR: &str = "ETCH_CONFIG";
+const DEFAULT_CONFIG_FILE: &str = "etch.toml";
+
+/// A setting along with the names it is given by in each source
+struct Setting {
+    key: &'static str,
+    // `None` for secrets, which can't be given on the command line
+    flag: Option<&'static str>,
+    env_var: &'static str,
+    description: &'static str,
+}
+
+/// The config file path given on the command line, if any, and every other setting given
+struct Args {
+    config_file: Option<String>,
+    flags: Vec<(&'static Setting, String)>,
+}
+
+const SETTINGS: &[Setting] = &[
+    Setting { key: "listen", flag: Some("--listen"), env_var: "ETCH_LISTEN", description: "Address to listen for connections on" },
+    Setting { key: "data_dir", flag: Some("--data-dir"), env_var: "ETCH_DATA_DIR", description: "Directory to store the db's files in" },
+    Setting { key: "storage", flag: Some("--storage"), env_var: "ETCH_STORAGE", description: "Where to keep tables, 'file' or 'memory'" },
+    Setting {
+        key: "records_per_sub_table",
+        flag: Some("--records-per-sub-table"),
+        env_var: "ETCH_RECORDS_PER_SUB_TABLE",
+        description: "How many rows a sub_table of a new table holds",
+    },
+    Setting { key: "max_frame_size", flag: Some("--max-frame-size"), env_var: "ETCH_MAX_FRAME_SIZE", description: "Largest frame accepted, in bytes" },
+    Setting { key: "durability", flag: Some("--durability"), env_var: "ETCH_DURABILITY", description: "'always', 'never' or a flush interval like '100ms'" },
+    Setting { key: "log_level", flag: Some("--log-level"), env_var: "ETCH_LOG_LEVEL", description: "'off', 'error', 'info' or 'debug'" },
+    Setting {
+        key: "admin_password",
+        flag: None,
+        env_var: "ETCH_ADMIN_PASSWORD",
+        description: "Password of the 'admin' user created when the db has no users",
+    },
+];
+
+/// Which storage engine tables are kept in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    File,
+    Memory,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(StorageKind::File),
+            "memory" => Ok(StorageKind::Memory),
+            _ => Err(format!("'{}' is not a storage engine, expected 'file' or 'memory'", s))
+        }
+    }
+}
+
+impl Display for StorageKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageKind::File => write!(f, "file"),
+            StorageKind::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen: String,
+    pub data_dir: PathBuf,
+    pub storage: StorageKind,
+    // Only used for tables created from here on, existing tables keep the size they were created with
+    pub records_per_sub_table: usize,
+    pub max_frame_size: usize,
+    pub durability: Durability,
+    pub log_level: LogLevel,
+    // Only used when the db has no users yet
+    pub admin_password: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:6379".to_string(),
+            data_dir: PathBuf::from("db_files"),
+            storage: StorageKind::File,
+            records_per_sub_table: DEFAULT_RECORDS_PER_SUB_TABLE,
+            max_frame_size: codec::DEFAULT_MAX_FRAME_SIZE,
+            durability: Durability::Always,
+            log_level: LogLevel::Info,
+            admin_password: None,
+        }
+    }
+}
+
+/// Parse a number which has to be at least 1
+fn parse_count(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("'{}' is not a positive number", value))
+    }
+}
+
+impl Config {
+    /// Build the config from command line arguments (without the program name), environment
+    /// variables looked up with `env_var`, and the config file
+    pub fn load(args: impl IntoIterator<Item = String>, env_var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let Args { config_file, flags } = Self::parse_args(args)?;
+        let mut config = Self::default();
+
+        // Sources are applied from lowest to highest precedence, each one overriding the last
+        match config_file.or_else(|| env_var(CONFIG_ENV_VAR)) {
+            Some(path) => config.apply_file(Path::new(&path))?,
+            None if Path::new(DEFAULT_CONFIG_FILE).is_file() => config.apply_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => ()
+        }
+        for setting in SETTINGS {
+            if let Some(value) = env_var(setting.env_var) {
+                config.set(setting.key, setting.env_var, value.as_str())?;
+            }
+        }
+        for (setting, value) in flags {
+            config.set(setting.key, setting.flag.expect("Only settings with a flag are given as flags"), value.as_str())?;
+        }
+        Ok(config)
+    }
+
+    fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, ConfigError> {
+        let mut config_file = None;
+        let mut flags = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // Flags can be given as either `--flag value` or `--flag=value`
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None)
+            };
+            let setting = SETTINGS.iter().find(|setting| setting.flag == Some(flag.as_str()));
+            if setting.is_none() && flag != CONFIG_FLAG {
+                return Err(UnknownFlag(flag))
+            }
+            let value = match value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(MissingValue(flag))
+            };
+            match setting {
+                Some(setting) => flags.push((setting, value)),
+                None => config_file = Some(value)
+            }
+        }
+        Ok(Args { config_file, flags })
+    }
+
+    fn apply_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|_| FailedReadConfigFile(path.display().to_string()))?;
+        let table: toml::Table = contents.parse().map_err(|e: toml::de::Error| MalformedConfigFile(e.message().to_string()))?;
+        for (key, value) in table {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                other => return Err(InvalidValue(key, format!("expected a string or a number, got a {}", other.type_str())))
+            };
+            self.set(key.as_str(), key.as_str(), value.as_str())?;
+        }
+        Ok(())
+    }
+
+    /// Set a setting by its config file key. `name` is what the setting was called where it was
+    /// given, for error messages.
+    fn set(&mut self, key: &str, name: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = |reason: String| InvalidValue(name.to_string(), reason);
+        match key {
+            "listen" | "data_dir" if value.is_empty() => return Err(invalid("can't be empty".to_string())),
+            "listen" => self.listen = value.to_string(),
+            "data_dir" => self.data_dir = PathBuf::from(value),
+            "storage" => self.storage = value.parse().map_err(invalid)?,
+            "records_per_sub_table" => self.records_per_sub_table = parse_count(value).map_err(invalid)?,
+            "max_frame_size" => self.max_frame_size = parse_count(value).map_err(invalid)?,
+            "durability" => self.durability = value.parse().map_err(invalid)?,
+            "log_level" => self.log_level = value.parse().map_err(invalid)?,
+            "admin_password" => self.admin_password = Some(value.to_string()),
+            _ => return Err(UnknownSetting(key.to_string()))
+        }
+        Ok(())
+    }
+
+    /// Get a setting as it would be written in the config file
+    fn get(&self, key: &str) -> String {
+        match key {
+            "listen" => self.listen.clone(),
+            "data_dir" => self.data_dir.display().to_string(),
+            "storage" => self.storage.to_string(),
+            "records_per_sub_table" => self.records_per_sub_table.to_string(),
+            "max_frame_size" => self.max_frame_size.to_string(),
+            "durability" => self.durability.to_string(),
+            "log_level" => self.log_level.to_string(),
+            "admin_password" => self.admin_password.as_ref().map_or("none", |_| "hidden").to_string(),
+            _ => unreachable!("'{}' is not a setting", key)
+        }
+    }
+
+    /// Describe every setting and where it can be given
+    pub fn usage() -> String {
+        let defaults = Self::default();
+        let mut usage = String::from("Usage: etch [FLAGS]\n\nFlags:\n");
+        usage.push_str(&format!("  {} <PATH>\n      Config file to read (env {}, default {} if it exists)\n", CONFIG_FLAG, CONFIG_ENV_VAR, DEFAULT_CONFIG_FILE));
+        for setting in SETTINGS {
+            let Some(flag) = setting.flag else {
+                continue
+            };
+            usage.push_str(&format!(
+                "  {} <VALUE>\n      {} (env {}, config key '{}', default {})\n",
+                flag, setting.description, setting.env_var, setting.key, defaults.get(setting.key)
+            ));
+        }
+        usage.push_str("\nSecrets, which can't be given as flags so that they don't show up in the process list:\n");
+        for setting in SETTINGS.iter().filter(|setting| setting.flag.is_none()) {
+            usage.push_str(&format!(
+                "  env {}, config key '{}'\n      {} (default {})\n",
+                setting.env_var, setting.key, setting.description, defaults.get(setting.key)
+            ));
+        }
+        usage.push_str("\nFlags take precedence over environment variables, which take precedence over the config file.\n");
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn flags_override_env_vars_which_override_the_config_file() {
+        let path = std::env::temp_dir().join(format!("etch_config_{}.toml", uuid::Uuid::new_v4()));
+        fs::write(&path, "listen = \"file:1\"\ndata_dir = \"from_file\"\nrecords_per_sub_table = 10\nlog_level = \"debug\"\n").unwrap();
+
+        let config = Config::load(
+            args(&["--config", path.to_str().unwrap(), "--listen=flag:1"]),
+            env(&[("ETCH_LISTEN", "env:1"), ("ETCH_DATA_DIR", "from_env")]),
+        ).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.listen, "flag:1");
+        assert_eq!(config.data_dir, PathBuf::from("from_env"));
+        assert_eq!(config.records_per_sub_table, 10);
+        assert_eq!(config.log_level, LogLevel::Debug);
+        // Settings given nowhere keep their default
+        assert_eq!(config.max_frame_size, codec::DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(config.durability, Durability::Always);
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(matches!(Config::load(args(&["--lisen", "a"]), env(&[])), Err(UnknownFlag(_))));
+        assert!(matches!(Config::load(args(&["--listen"]), env(&[])), Err(MissingValue(_))));
+        assert!(matches!(Config::load(args(&["--durability", "sometimes"]), env(&[])), Err(InvalidValue(_, _))));
+        assert!(matches!(Config::load(args(&[]), env(&[("ETCH_RECORDS_PER_SUB_TABLE", "0")])), Err(InvalidValue(_, _))));
+        assert!(matches!(Config::load(args(&["--config", "/nonexistent/etch.toml"]), env(&[])), Err(FailedReadConfigFile(_))));
+    }
+
+    #[test]
+    fn secrets_are_not_taken_from_flags() {
+        assert!(matches!(Config::load(args(&["--admin-password", "hunter2"]), env(&[])), Err(UnknownFlag(_))));
+        let config = Config::load(args(&[]), env(&[("ETCH_ADMIN_PASSWORD", "hunter2")])).unwrap();
+        assert_eq!(config.admin_password.as_deref(), Some("hunter2"));
+        assert!(!Config::usage().contains("--admin-password"));
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/*
+    How much the server prints, set once on startup. Each level includes everything printed by
+    the levels before it.
+
+    - `off` prints nothing.
+    - `error` prints failed requests and anything which went wrong in the background.
+    - `info` also prints startup and recovery progress. This is the default.
+    - `debug` also prints every response sent.
+
+    Errors are printed to stderr and everything else to stdout.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Info,
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(LogLevel::Off),
+            "error" => Ok(LogLevel::Error),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("'{}' is not a log level, expected 'off', 'error', 'info' or 'debug'", s))
+        }
+    }
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogLevel::Off => write!(f, "off"),
+            LogLevel::Error => write!(f, "error"),
+            LogLevel::Info => write!(f, "info"),
+            LogLevel::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+static LOG_LEVEL: OnceLock<LogLevel> = OnceLock::new();
+
+/// Set the log level. Can only be called once, before anything is logged.
+pub fn init(level: LogLevel) {
+    LOG_LEVEL.set(level).expect("Log level was already set");
+}
+
+/// Whether messages at `level` should be printed
+pub fn enabled(level: LogLevel) -> bool {
+    level <= *LOG_LEVEL.get_or_init(|| LogLevel::Info)
+}
+
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Error) {
+            eprintln!($($arg)*)
+        }
+    };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Info) {
+            println!($($arg)*)
+        }
+    };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Debug) {
+            println!($($arg)*)
+        }
+    };
+}
+
+pub(crate) use {log_debug, log_error, log_info};
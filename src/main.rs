@@ -1,130 +1,478 @@
 mod tcp;
 mod tables;
 mod rows;
-mod file_reader;
+mod storage;
+mod config;
+mod logging;
+mod transactions;
+mod auth;
 
 use std::collections::HashMap;
-use serde_json::json;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use auth::{Identity, Users};
+use auth::auth_err::AuthError;
+use serde_json::{json, Value};
+use config::{Config, StorageKind};
+use logging::{log_debug, log_error, log_info};
+use storage::StorageEngine;
+use storage::durability;
+use storage::file::FileStorage;
+use storage::memory::MemoryStorage;
 use tables::Table;
+use transactions::Transaction;
+use transactions::transaction_err::TransactionError;
+use transactions::versions::VersionStore;
 
 use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use crate::tcp::TCPError;
 use crate::tcp::connection::Connection;
-use crate::tcp::frame::Command;
+use crate::tcp::frame::{Command, Frame};
+use crate::tcp::response::Response;
 
+/// How long a connection may sit without sending a frame before it is closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How often versions of rows which no transaction can see are thrown away
+const GC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shared database state. Every table sits behind its own lock so that work on one table never
+/// has to wait on work being done to another.
 #[derive(Debug)]
 pub struct State {
-    tables: HashMap<String, Table>,
+    tables: RwLock<HashMap<String, Arc<RwLock<Table>>>>,
+    storage: Box<dyn StorageEngine>,
+    // Versions of rows replaced by commits, kept for transactions which began before them
+    versions: Arc<VersionStore>,
+    users: Users,
+    // How many rows each sub_table of a newly created table holds
+    records_per_sub_table: usize,
 }
 
 impl State {
-    fn initialize() -> Self {
-        let tables = match file_reader::load_tables_from_disk() {
+    fn initialize(storage: Box<dyn StorageEngine>, records_per_sub_table: usize) -> Self {
+        let tables = match storage.load_tables() {
             Ok(tables) => tables,
             Err(e) => panic!("Failed to load tables with error: {}", e)
         };
-        Self{ tables }
+        let tables = tables.into_iter()
+            .map(|mut table| {
+                if let Err(e) = table.load_indexes(storage.as_ref()) {
+                    panic!("Failed to load indexes for table '{}' with error: {}", table.name, e)
+                }
+                (table.name.clone(), Arc::new(RwLock::new(table)))
+            })
+            .collect();
+        let users = match Users::load(storage.as_ref()) {
+            Ok(users) => users,
+            Err(e) => panic!("Failed to load users with error: {}", e)
+        };
+        Self{ tables: RwLock::new(tables), storage, versions: Arc::default(), users, records_per_sub_table }
+    }
+
+    /// Get a handle to a table's lock. The lock on the table map is only held long enough to
+    /// clone the handle.
+    pub fn get_table(&self, table_name: &str) -> Option<Arc<RwLock<Table>>> {
+        self.tables.read().expect("Table map lock was poisoned").get(table_name).cloned()
     }
 }
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", Config::usage());
+        return
+    }
+    let config = match Config::load(args, |name| std::env::var(name).ok()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, Config::usage());
+            std::process::exit(2)
+        }
+    };
+    logging::init(config.log_level);
+    durability::init(config.durability);
+
     // Bind a listener for TCP requests
-    let listener = TcpListener::bind("127.0.0.1:6379")
+    let listener = TcpListener::bind(config.listen.as_str())
         .await
-        .expect("Failed to bind a TCP listener");
+        .unwrap_or_else(|e| panic!("Failed to bind a TCP listener to '{}' with error: {}", config.listen, e));
 
-    file_reader::check_for_db_dir();
+    let storage: Box<dyn StorageEngine> = match config.storage {
+        StorageKind::File => {
+            let storage = FileStorage::open(config.data_dir.clone());
+            Box::new(storage.unwrap_or_else(|e| panic!("Failed to open the db files with error: {}", e)))
+        },
+        StorageKind::Memory => Box::new(MemoryStorage::default())
+    };
 
     // Load db state
-    let mut state = State::initialize();
+    let state = Arc::new(State::initialize(storage, config.records_per_sub_table));
+    if let Err(e) = state.users.bootstrap(state.storage.as_ref(), config.admin_password.as_deref()).await {
+        eprintln!("{}", e);
+        std::process::exit(2)
+    }
+    log_info!("Listening on {}", config.listen);
 
-    // Loop and listen for connection requests
-    loop {
-        // TODO: This breaks the connection after response, not allowing the sender to make another
-        //       request without them creating a new connection
+    serve(listener, state, config.max_frame_size).await
+}
 
+/// Loop and listen for connection requests, handling each connection on its own task
+async fn serve(listener: TcpListener, state: Arc<State>, max_frame_size: usize) {
+    tokio::spawn(collect_garbage(Arc::clone(&state)));
+    loop {
         // TODO: Should print or log rather than panic
         let (stream, _address) = match listener.accept().await {
             Ok(res) => res,
             Err(e) => panic!("Failed to accept a connection with error: {:?}", e)
         };
-        process(&mut state, stream).await;
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            process(&state, stream, max_frame_size).await
+        });
     }
 }
 
-async fn process(state: &mut State, stream: TcpStream) {
-    let mut connection = Connection::new(stream);
-    match connection.read_frame().await {
-        Ok(frame) => {
-            // TODO: Response should be an actual struct and constructed better
-            let res_data = match frame.command {
-                Command::Insert => {
-                    match rows::insert_data(state, frame.table.as_str(), frame.data) {
-                        Ok(id) => {
-                            json!({
-                                "code": 201,
-                                "data": {
-                                    "id": id
-                                }
-                            })
-                        },
-                        Err(e) => {
-                            eprintln!("Error while processing insert row command: {}", e);
-                            json!({
-                                "code": 500,
-                                "data": {
-                                    "msg": "Error while processing insert row"
-                                }
-                            })
-                        }
-                    }
+/// Periodically throw away versions of rows which no open transaction can see anymore
+async fn collect_garbage(state: Arc<State>) {
+    let mut interval = tokio::time::interval(GC_INTERVAL);
+    loop {
+        interval.tick().await;
+        let collected = state.versions.collect_garbage();
+        if collected > 0 {
+            log_debug!("Collected {} superseded row versions", collected);
+        }
+    }
+}
+
+/// What a connection has set up for itself, thrown away when it closes
+#[derive(Debug, Default)]
+struct Session {
+    // The user the connection has authenticated as
+    user: Option<Identity>,
+    // The connection's open transaction, thrown away if the connection closes before it commits
+    transaction: Option<Transaction>,
+}
+
+async fn process(state: &State, stream: TcpStream, max_frame_size: usize) {
+    let mut connection = Connection::new(stream, max_frame_size);
+    let mut session = Session::default();
+    loop {
+        let (response, keep_open) = match timeout(IDLE_TIMEOUT, connection.read_frame()).await {
+            Ok(Ok(Some(frame))) => (handle_frame(state, &mut session, frame).await, true),
+            // The client closed the connection
+            Ok(Ok(None)) => return,
+            Ok(Err(e)) => {
+                log_error!("Failed to read frame with error: {}", e);
+                match e {
+                    // The whole frame was read, so the connection is still usable
+                    TCPError::MalformedJSON | TCPError::ParseFrame(_) => (Response::error(&e), true),
+                    // It isn't known where the next frame starts, let the client know why it is
+                    // being cut off and close the connection
+                    TCPError::InvalidStart
+                    | TCPError::UnsupportedVersion(_)
+                    | TCPError::UnsupportedFlags(_)
+                    | TCPError::FrameTooLarge(_, _) => (Response::error(&e), false),
+                    // The client has gone away and there is nobody to respond to
+                    _ => return
+                }
+            },
+            Err(_elapsed) => {
+                log_info!("Closing connection after being idle for {} seconds", IDLE_TIMEOUT.as_secs());
+                return
+            }
+        };
+        let res = match connection.respond(response).await {
+            Err(e @ TCPError::FrameTooLarge(_, _)) => {
+                log_error!("Failed to respond to requester with error: {}", e);
+                connection.respond(Response::error(&e)).await
+            },
+            res => res
+        };
+        match res {
+            Ok(written_bytes) => log_debug!("Responded to request with {} bytes", written_bytes),
+            Err(e) => {
+                log_error!("Failed to respond to requester with error: {}", e);
+                return
+            }
+        }
+        if !keep_open {
+            return
+        }
+    }
+}
+
+async fn handle_frame(state: &State, session: &mut Session, frame: Frame) -> Response {
+    // Nothing but `auth` is accepted until the connection has authenticated, and a connection
+    // whose user has since been dropped has to authenticate again
+    let authenticated = session.user.as_ref().is_some_and(|user| state.users.is_current(user));
+    if !authenticated && !matches!(frame.command, Command::Auth) {
+        *session = Session::default();
+        let e = AuthError::NotAuthenticated;
+        log_error!("Error while processing command: {}", e);
+        return Response::error(&e)
+    }
+    let current_user = session.user.as_ref().map(|user| user.name.clone()).unwrap_or_default();
+    let transaction = &mut session.transaction;
+
+    if transaction.is_some() && frame.command.changes_schema() {
+        let e = TransactionError::SchemaChange;
+        log_error!("Error while processing command: {}", e);
+        return Response::error(&e)
+    }
+    match frame.command {
+        Command::Insert => {
+            match rows::insert_data(state, transaction.as_mut(), frame.table.as_str(), frame.data) {
+                Ok(id) => Response::created(json!({ "id": id })),
+                Err(e) => {
+                    log_error!("Error while processing insert row command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::Read => {
+            match rows::read_data_by_id(state, transaction.as_ref(), frame.table.as_str(), frame.data) {
+                Ok(data) => Response::ok(data),
+                Err(e) => {
+                    log_error!("Error while processing read row command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::Query => {
+            match rows::query_data(state, transaction.as_ref(), frame.table.as_str(), frame.data) {
+                Ok(rows) => Response::ok(Value::Array(rows)),
+                Err(e) => {
+                    log_error!("Error while processing query command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::Aggregate => {
+            match rows::aggregate_data(state, transaction.as_ref(), frame.table.as_str(), frame.data) {
+                Ok(data) => Response::ok(data),
+                Err(e) => {
+                    log_error!("Error while processing aggregate command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::Update => {
+            match rows::update_data(state, transaction.as_mut(), frame.table.as_str(), frame.data) {
+                Ok(data) => Response::ok(data),
+                Err(e) => {
+                    log_error!("Error while processing update row command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::Delete => {
+            match rows::delete_data(state, transaction.as_mut(), frame.table.as_str(), frame.data) {
+                Ok(data) => Response::ok(data),
+                Err(e) => {
+                    log_error!("Error while processing delete row command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::CreateTable => {
+            match Table::create_table(state, frame) {
+                Ok(()) => Response::created(json!({})),
+                Err(e) => {
+                    log_error!("Error while processing create table command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::CreateIndex => {
+            match Table::create_index(state, frame) {
+                Ok(()) => Response::created(json!({})),
+                Err(e) => {
+                    log_error!("Error while processing create index command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::DropIndex => {
+            match Table::drop_index(state, frame) {
+                Ok(()) => Response::ok(json!({})),
+                Err(e) => {
+                    log_error!("Error while processing drop index command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::DropTable => {
+            match Table::drop_table(state, frame) {
+                Ok(()) => Response::ok(json!({})),
+                Err(e) => {
+                    log_error!("Error while processing drop table command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::Begin => {
+            match transaction {
+                Some(_) => {
+                    let e = TransactionError::AlreadyOpen;
+                    log_error!("Error while processing begin command: {}", e);
+                    Response::error(&e)
                 },
-                Command::Read => {
-                    // TODO: Access by means other than ID?
-                    match rows::read_data_by_id(state, frame.table.as_str(), frame.data) {
-                        Ok(data) => {
-                            json!({
-                                "code": 200,
-                                "data": data
-                            })
-                        },
-                        Err(e) => {
-                            eprintln!("Error while processing read row command: {}", e);
-                            json!({
-                                "code": 500,
-                                "data": {
-                                    "msg": "Error while processing read row"
-                                }
-                            })
-                        }
+                None => {
+                    *transaction = Some(Transaction::begin(state));
+                    Response::ok(json!({}))
+                }
+            }
+        },
+        Command::Commit => {
+            // The transaction is over whether or not the commit succeeds
+            match transaction.take() {
+                Some(transaction) => match transaction.commit(state) {
+                    Ok(()) => Response::ok(json!({})),
+                    Err(e) => {
+                        log_error!("Error while processing commit command: {}", e);
+                        Response::error(&e)
                     }
                 },
-                Command::Update => todo!("Update command"),
-                Command::Delete => todo!("Delete command"),
-                Command::CreateTable => {
-                    match Table::create_table(state, frame) {
-                        Ok(()) => json!({
-                            "code": 201,
-                            "data": {}
-                        }),
-                        Err(e) => {
-                            eprintln!("Error while processing create table command: {}", e);
-                            json!({
-                                "code": 500,
-                                "data": {
-                                    "msg": "Error while creating table"
-                                }
-                            })
-                        }
-                    }
+                None => {
+                    let e = TransactionError::NotOpen;
+                    log_error!("Error while processing commit command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::Rollback => {
+            match transaction.take() {
+                Some(_) => Response::ok(json!({})),
+                None => {
+                    let e = TransactionError::NotOpen;
+                    log_error!("Error while processing rollback command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::Auth => {
+            match auth::authenticate(state, frame).await {
+                Ok(user) => {
+                    session.user = Some(user);
+                    Response::ok(json!({}))
                 },
-                Command::DropTable => todo!("DropTable command"),
-            };
-            match connection.respond(res_data).await {
-                Ok(written_bytes) => println!("Responded to request with {} bytes", written_bytes),
-                Err(e) => eprintln!("Failed to respond to requester with error: {}", e)
+                Err(e) => {
+                    log_error!("Error while processing auth command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::CreateUser => {
+            match auth::create_user(state, current_user.as_str(), frame).await {
+                Ok(()) => Response::created(json!({})),
+                Err(e) => {
+                    log_error!("Error while processing create user command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+        Command::DropUser => {
+            match auth::drop_user(state, current_user.as_str(), frame) {
+                Ok(()) => Response::ok(json!({})),
+                Err(e) => {
+                    log_error!("Error while processing drop user command: {}", e);
+                    Response::error(&e)
+                }
             }
         },
-        Err(e) => eprintln!("Failed to read frame with error: {}", e)
+        Command::ChangePassword => {
+            match auth::change_password(state, current_user.as_str(), frame).await {
+                Ok(()) => Response::ok(json!({})),
+                Err(e) => {
+                    log_error!("Error while processing change password command: {}", e);
+                    Response::error(&e)
+                }
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tables::DEFAULT_RECORDS_PER_SUB_TABLE;
+    use bytes::BytesMut;
+    use crate::tcp::codec;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn send_request(stream: &mut TcpStream, request: Value) -> Value {
+        let mut buffer = BytesMut::new();
+        codec::encode(&request, codec::ProtocolVersion::V1, codec::DEFAULT_MAX_FRAME_SIZE, &mut buffer).unwrap();
+        stream.write_all(&buffer).await.unwrap();
+
+        buffer.clear();
+        loop {
+            if let Some((version, response)) = codec::decode(&mut buffer, codec::DEFAULT_MAX_FRAME_SIZE).unwrap() {
+                assert_eq!(version, codec::ProtocolVersion::V1);
+                return response
+            }
+            assert!(stream.read_buf(&mut buffer).await.unwrap() > 0, "Connection closed before a response was sent");
+        }
+    }
+
+    async fn authenticate(stream: &mut TcpStream) {
+        let res = send_request(stream, json!({"command": "auth", "data": {"user": "admin", "password": "password"}})).await;
+        assert_eq!(res["code"], 200);
+    }
+
+    /// Create two tables, then insert and read back rows on many connections at once
+    async fn stress_concurrent_connections(storage: Box<dyn StorageEngine>) {
+        const CONNECTIONS: usize = 300;
+
+        let state = Arc::new(State::initialize(storage, DEFAULT_RECORDS_PER_SUB_TABLE));
+        state.users.bootstrap(state.storage.as_ref(), Some("password")).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::clone(&state), codec::DEFAULT_MAX_FRAME_SIZE));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let res = send_request(&mut stream, json!({"command": "create_table", "table": "a", "data": {}})).await;
+        assert_eq!(res["code"], 401);
+        authenticate(&mut stream).await;
+        for table in ["a", "b"] {
+            let res = send_request(&mut stream, json!({"command": "create_table", "table": table, "data": {}})).await;
+            assert_eq!(res["code"], 201);
+        }
+
+        let mut handles = Vec::new();
+        for i in 0..CONNECTIONS {
+            handles.push(tokio::spawn(async move {
+                let table = if i % 2 == 0 { "a" } else { "b" };
+                let mut stream = TcpStream::connect(address).await.unwrap();
+                authenticate(&mut stream).await;
+                let res = send_request(&mut stream, json!({"command": "insert", "table": table, "data": {"n": i}})).await;
+                assert_eq!(res["code"], 201);
+                let id = res["data"]["id"].clone();
+                let res = send_request(&mut stream, json!({"command": "read", "table": table, "data": {"_id": id}})).await;
+                assert_eq!(res["code"], 200);
+                assert_eq!(res["data"]["n"], i);
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let metadata = state.storage.read_table_metadata("a").unwrap();
+        assert_eq!(metadata.sub_tables.iter().sum::<usize>(), CONNECTIONS / 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn stress_file_storage() {
+        let dir = std::env::temp_dir().join(format!("etch_stress_{}", uuid::Uuid::new_v4()));
+        stress_concurrent_connections(Box::new(FileStorage::open(dir.clone()).unwrap())).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn stress_memory_storage() {
+        stress_concurrent_connections(Box::new(MemoryStorage::default())).await;
     }
 }
@@ -0,0 +1,375 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use serde_json::{Map, Number, Value};
+use crate::rows::query::{get_path, total_order, Filter};
+use crate::rows::row_err::RowError;
+
+/*
+    An aggregate request computes values over every row matching a filter:
+        {
+            "filter": { ... },
+            "group_by": ["country"],
+            "aggregates": [
+                { "op": "count" },
+                { "op": "sum", "field": "amount", "as": "revenue" },
+                { "op": "count_distinct", "field": "customer.id" }
+            ]
+        }
+
+    The ops are `count`, `sum`, `avg`, `min`, `max` and `count_distinct`. `count` counts matching
+    rows, or only rows where `field` is not null if a field is given. `sum` and `avg` skip values
+    which are not numbers, `min` and `max` skip nulls and use the same ordering as sorting does.
+    Each result is named by `as`, defaulting to `count` or `<op>_<field>`.
+
+    Without `group_by` the response is a single object of results. With it the response is a list
+    with one `{"group": {...}, "values": {...}}` entry per distinct group, ordered by group.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    CountDistinct,
+}
+
+#[derive(Debug)]
+struct Aggregate {
+    op: Op,
+    field: Option<Vec<String>>,
+    name: String,
+}
+
+/// The running state of a single aggregate over one group
+#[derive(Debug)]
+enum Accumulator {
+    Count(u64),
+    // Integer sums are kept exact for as long as every value is an integer and nothing overflows
+    Sum { int_sum: Option<i64>, float_sum: f64, count: u64 },
+    Extreme(Option<Value>),
+    Distinct(HashSet<String>),
+}
+
+fn new_accumulators(aggregates: &[Aggregate]) -> Vec<Accumulator> {
+    aggregates.iter().map(|aggregate| match aggregate.op {
+        Op::Count => Accumulator::Count(0),
+        Op::Sum | Op::Avg => Accumulator::Sum { int_sum: Some(0), float_sum: 0.0, count: 0 },
+        Op::Min | Op::Max => Accumulator::Extreme(None),
+        Op::CountDistinct => Accumulator::Distinct(HashSet::new()),
+    }).collect()
+}
+
+#[derive(Debug)]
+pub struct AggregateRequest {
+    pub filter: Filter,
+    has_filter: bool,
+    group_by: Vec<Vec<String>>,
+    aggregates: Vec<Aggregate>,
+}
+
+fn parse_path(field: &str) -> Vec<String> {
+    field.split('.').map(|segment| segment.to_string()).collect()
+}
+
+impl AggregateRequest {
+    pub fn from_request(data: &Map<String, Value>) -> Result<Self, RowError> {
+        let (filter, has_filter) = match data.get("filter") {
+            Some(Value::Object(map)) if map.is_empty() => (Filter::And(Vec::new()), false),
+            Some(filter) => (Filter::from_value(filter)?, true),
+            None => (Filter::And(Vec::new()), false),
+        };
+        let group_by = match data.get("group_by") {
+            None => Vec::new(),
+            Some(Value::Array(fields)) => fields.iter().map(|field| match field {
+                Value::String(field) => Ok(parse_path(field)),
+                _ => Err(RowError::InvalidAggregate("'group_by' must be a list of strings".to_string()))
+            }).collect::<Result<Vec<Vec<String>>, RowError>>()?,
+            Some(_) => return Err(RowError::InvalidAggregate("'group_by' must be a list of strings".to_string()))
+        };
+        let aggregates = match data.get("aggregates") {
+            Some(Value::Array(aggregates)) if !aggregates.is_empty() => {
+                aggregates.iter().map(Self::parse_aggregate).collect::<Result<Vec<Aggregate>, RowError>>()?
+            },
+            _ => return Err(RowError::InvalidAggregate("'aggregates' must be a non-empty list".to_string()))
+        };
+        Ok(Self { filter, has_filter, group_by, aggregates })
+    }
+
+    fn parse_aggregate(value: &Value) -> Result<Aggregate, RowError> {
+        let op = match value.get("op") {
+            Some(Value::String(op)) => match op.as_str() {
+                "count" => Op::Count,
+                "sum" => Op::Sum,
+                "avg" => Op::Avg,
+                "min" => Op::Min,
+                "max" => Op::Max,
+                "count_distinct" => Op::CountDistinct,
+                _ => return Err(RowError::InvalidAggregate(format!("Unknown aggregate op '{}'", op)))
+            },
+            _ => return Err(RowError::InvalidAggregate("Each aggregate must have an 'op' string".to_string()))
+        };
+        let field = match value.get("field") {
+            None => None,
+            Some(Value::String(field)) => Some(field.clone()),
+            Some(_) => return Err(RowError::InvalidAggregate("An aggregate's 'field' must be a string".to_string()))
+        };
+        if field.is_none() && op != Op::Count {
+            return Err(RowError::InvalidAggregate("Only 'count' can be used without a 'field'".to_string()))
+        }
+        let name = match (value.get("as"), &field) {
+            (Some(Value::String(name)), _) => name.clone(),
+            (Some(_), _) => return Err(RowError::InvalidAggregate("An aggregate's 'as' must be a string".to_string())),
+            (None, None) => "count".to_string(),
+            (None, Some(field)) => format!("{}_{}", value["op"].as_str().expect("Op was checked to be a string"), field),
+        };
+        Ok(Aggregate { op, field: field.as_deref().map(parse_path), name })
+    }
+
+    /// Whether this request is only counting every row of the table, which can be answered
+    /// without reading any rows
+    pub fn is_plain_count(&self) -> bool {
+        !self.has_filter
+            && self.group_by.is_empty()
+            && self.aggregates.iter().all(|aggregate| aggregate.op == Op::Count && aggregate.field.is_none())
+    }
+
+    /// Build the response for a plain count, given the number of rows in the table
+    pub fn plain_count(&self, rows: usize) -> Value {
+        let values: Map<String, Value> = self.aggregates.iter()
+            .map(|aggregate| (aggregate.name.clone(), Value::from(rows)))
+            .collect();
+        Value::Object(values)
+    }
+
+    /// Start aggregating rows. Rows should be passed to `Aggregator::add` after they have been
+    /// checked against the filter.
+    pub fn aggregator(&self) -> Aggregator<'_> {
+        Aggregator { request: self, groups: HashMap::new() }
+    }
+}
+
+pub struct Aggregator<'a> {
+    request: &'a AggregateRequest,
+    groups: HashMap<String, (Vec<Value>, Vec<Accumulator>)>,
+}
+
+impl Aggregator<'_> {
+    pub fn add(&mut self, row: &Value) {
+        let group: Vec<Value> = self.request.group_by.iter()
+            .map(|path| get_path(row, path).cloned().unwrap_or(Value::Null))
+            .collect();
+        let key = serde_json::to_string(&group).expect("serde_json Value should impl Serialize");
+        let (_group, accumulators) = self.groups.entry(key)
+            .or_insert_with(|| (group, new_accumulators(&self.request.aggregates)));
+
+        for (aggregate, accumulator) in self.request.aggregates.iter().zip(accumulators.iter_mut()) {
+            let value = match &aggregate.field {
+                Some(path) => get_path(row, path).unwrap_or(&Value::Null),
+                None => row,
+            };
+            match accumulator {
+                Accumulator::Count(count) => {
+                    if !value.is_null() {
+                        *count += 1;
+                    }
+                },
+                Accumulator::Sum { int_sum, float_sum, count } => {
+                    if let Value::Number(number) = value {
+                        *count += 1;
+                        *float_sum += number.as_f64().unwrap_or(0.0);
+                        *int_sum = match (*int_sum, number.as_i64()) {
+                            (Some(sum), Some(number)) => sum.checked_add(number),
+                            _ => None
+                        };
+                    }
+                },
+                Accumulator::Extreme(current) => {
+                    if value.is_null() {
+                        continue
+                    }
+                    let replace = match current {
+                        None => true,
+                        Some(current) => {
+                            let ordering = total_order(value, current);
+                            if aggregate.op == Op::Min { ordering.is_lt() } else { ordering.is_gt() }
+                        }
+                    };
+                    if replace {
+                        *current = Some(value.clone());
+                    }
+                },
+                Accumulator::Distinct(seen) => {
+                    if !value.is_null() {
+                        seen.insert(serde_json::to_string(value).expect("serde_json Value should impl Serialize"));
+                    }
+                },
+            }
+        }
+    }
+
+    fn values(&self, accumulators: &[Accumulator]) -> Value {
+        let mut values = Map::new();
+        for (aggregate, accumulator) in self.request.aggregates.iter().zip(accumulators) {
+            let value = match accumulator {
+                Accumulator::Count(count) => Value::from(*count),
+                Accumulator::Sum { int_sum, float_sum, count } => match aggregate.op {
+                    Op::Avg if *count == 0 => Value::Null,
+                    Op::Avg => Number::from_f64(*float_sum / *count as f64).map(Value::Number).unwrap_or(Value::Null),
+                    _ => match int_sum {
+                        Some(sum) => Value::from(*sum),
+                        None => Number::from_f64(*float_sum).map(Value::Number).unwrap_or(Value::Null),
+                    }
+                },
+                Accumulator::Extreme(value) => value.clone().unwrap_or(Value::Null),
+                Accumulator::Distinct(seen) => Value::from(seen.len()),
+            };
+            values.insert(aggregate.name.clone(), value);
+        }
+        Value::Object(values)
+    }
+
+    pub fn finish(self) -> Value {
+        if self.request.group_by.is_empty() {
+            return match self.groups.values().next() {
+                Some((_group, accumulators)) => self.values(accumulators),
+                // No rows matched, so report the aggregates of an empty group
+                None => self.values(&new_accumulators(&self.request.aggregates)),
+            }
+        }
+
+        let mut groups: Vec<&(Vec<Value>, Vec<Accumulator>)> = self.groups.values().collect();
+        groups.sort_by(|(a, _), (b, _)| {
+            a.iter().zip(b)
+                .map(|(a, b)| total_order(a, b))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(Ordering::Equal)
+        });
+        let results = groups.into_iter().map(|(group, accumulators)| {
+            let mut group_values = Map::new();
+            for (path, value) in self.request.group_by.iter().zip(group) {
+                group_values.insert(path.join("."), value.clone());
+            }
+            serde_json::json!({
+                "group": group_values,
+                "values": self.values(accumulators),
+            })
+        }).collect();
+        Value::Array(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(value: Value) -> AggregateRequest {
+        match value {
+            Value::Object(map) => AggregateRequest::from_request(&map).unwrap(),
+            _ => panic!("Request data must be an object")
+        }
+    }
+
+    fn aggregate(request: &AggregateRequest, rows: &[Value]) -> Value {
+        let mut aggregator = request.aggregator();
+        rows.iter().filter(|row| request.filter.matches(row)).for_each(|row| aggregator.add(row));
+        aggregator.finish()
+    }
+
+    fn orders() -> Vec<Value> {
+        vec![
+            json!({"_id": "0.a", "country": "fr", "amount": 10, "customer": {"id": 1}}),
+            json!({"_id": "0.b", "country": "fr", "amount": 2.5, "customer": {"id": 1}}),
+            json!({"_id": "0.c", "country": "it", "amount": "n/a", "customer": {"id": 2}}),
+            json!({"_id": "0.d", "amount": 4, "customer": {"id": 3}}),
+        ]
+    }
+
+    #[test]
+    fn aggregates_over_every_matching_row() {
+        let request = parse(json!({"aggregates": [
+            {"op": "count"},
+            {"op": "count", "field": "country", "as": "with_country"},
+            {"op": "sum", "field": "amount"},
+            {"op": "avg", "field": "amount"},
+            {"op": "min", "field": "amount"},
+            {"op": "max", "field": "amount"},
+            {"op": "count_distinct", "field": "customer.id"},
+        ]}));
+        assert_eq!(aggregate(&request, &orders()), json!({
+            "count": 4,
+            "with_country": 3,
+            "sum_amount": 16.5,
+            "avg_amount": 5.5,
+            "min_amount": 2.5,
+            "max_amount": "n/a",
+            "count_distinct_customer.id": 3,
+        }));
+    }
+
+    #[test]
+    fn integer_sums_stay_exact() {
+        let request = parse(json!({"aggregates": [{"op": "sum", "field": "n"}]}));
+        let rows = [json!({"n": i64::MAX - 1}), json!({"n": 1})];
+        assert_eq!(aggregate(&request, &rows), json!({"sum_n": i64::MAX}));
+    }
+
+    #[test]
+    fn empty_groups_have_null_averages() {
+        let request = parse(json!({"filter": {"country": "de"}, "aggregates": [
+            {"op": "count"}, {"op": "sum", "field": "amount"}, {"op": "avg", "field": "amount"}, {"op": "min", "field": "amount"},
+        ]}));
+        assert_eq!(aggregate(&request, &orders()), json!({"count": 0, "sum_amount": 0, "avg_amount": null, "min_amount": null}));
+
+        // A group whose rows have no numbers to average also has a null average
+        let request = parse(json!({"filter": {"country": "it"}, "aggregates": [{"op": "avg", "field": "amount"}]}));
+        assert_eq!(aggregate(&request, &orders()), json!({"avg_amount": null}));
+    }
+
+    #[test]
+    fn groups_are_listed_in_order() {
+        let request = parse(json!({"group_by": ["country"], "aggregates": [{"op": "count"}, {"op": "sum", "field": "amount", "as": "revenue"}]}));
+        assert_eq!(aggregate(&request, &orders()), json!([
+            {"group": {"country": null}, "values": {"count": 1, "revenue": 4}},
+            {"group": {"country": "fr"}, "values": {"count": 2, "revenue": 12.5}},
+            {"group": {"country": "it"}, "values": {"count": 1, "revenue": 0}},
+        ]));
+
+        let request = parse(json!({"group_by": ["country", "customer.id"], "filter": {"country": "fr"}, "aggregates": [{"op": "count"}]}));
+        assert_eq!(aggregate(&request, &orders()), json!([{"group": {"country": "fr", "customer.id": 1}, "values": {"count": 2}}]));
+
+        // No rows means no groups
+        let request = parse(json!({"group_by": ["country"], "filter": {"country": "de"}, "aggregates": [{"op": "count"}]}));
+        assert_eq!(aggregate(&request, &orders()), json!([]));
+    }
+
+    #[test]
+    fn only_unfiltered_ungrouped_counts_are_plain() {
+        assert!(parse(json!({"aggregates": [{"op": "count"}, {"op": "count", "as": "rows"}]})).is_plain_count());
+        assert!(parse(json!({"filter": {}, "aggregates": [{"op": "count"}]})).is_plain_count());
+        assert!(!parse(json!({"filter": {"country": "fr"}, "aggregates": [{"op": "count"}]})).is_plain_count());
+        assert!(!parse(json!({"group_by": ["country"], "aggregates": [{"op": "count"}]})).is_plain_count());
+        assert!(!parse(json!({"aggregates": [{"op": "count", "field": "country"}]})).is_plain_count());
+        assert_eq!(parse(json!({"aggregates": [{"op": "count", "as": "rows"}]})).plain_count(7), json!({"rows": 7}));
+    }
+
+    #[test]
+    fn malformed_requests_are_rejected() {
+        let requests = [
+            json!({}),
+            json!({"aggregates": []}),
+            json!({"aggregates": [{"op": "median", "field": "amount"}]}),
+            json!({"aggregates": [{"op": "sum"}]}),
+            json!({"aggregates": [{"op": "sum", "field": 1}]}),
+            json!({"aggregates": [{"op": "count", "as": 1}]}),
+            json!({"group_by": "country", "aggregates": [{"op": "count"}]}),
+        ];
+        for value in requests {
+            let Value::Object(map) = &value else { unreachable!() };
+            let error = AggregateRequest::from_request(map).unwrap_err();
+            assert!(matches!(error, RowError::InvalidAggregate(_)), "{} gave {:?}", value, error);
+        }
+    }
+}
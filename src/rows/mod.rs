@@ -1,12 +1,19 @@
+pub mod aggregate;
+pub mod query;
 pub mod row_err;
 
+use std::collections::{BTreeMap, BTreeSet};
 use uuid::Uuid;
 
 use serde_json::{Map, Value};
+use crate::rows::aggregate::AggregateRequest;
+use crate::rows::query::{Filter, Projection, Query};
 use crate::rows::row_err::RowError;
 use crate::rows::row_err::RowError::{FailedInsert, TableDoesntExist};
 use crate::State;
-use crate::file_reader;
+use crate::tables::Table;
+use crate::tables::constraints::ConstraintViolation;
+use crate::transactions::Transaction;
 
 /*
     Rows are stored in sub_table files. A row has an ID that takes the form of `{usize}.{uuid}` where
@@ -15,6 +22,12 @@ use crate::file_reader;
     `4.ABC-123-456` then the record will be in the /db_files/foo/sub_table_4.etch file. This schema
     works fine when working with objects by ID or without many concurrent requests but this does not
     scale or work if access is made by means other than ID
+
+    Every row also has a `_version`, which is 1 when it is inserted and goes up by one each time
+    it is updated. An update or delete can give the version it expects the row to be at, and is
+    rejected with the row's current version if the row has been changed since:
+
+        {"_id": "0.ABC-123", "_version": 3, "data": {...}}
 */
 
 fn generate_new_id(sub_table_index: usize) -> String {
@@ -22,81 +35,533 @@ fn generate_new_id(sub_table_index: usize) -> String {
     format!("{}.{}", sub_table_index, id)
 }
 
+/// The version of a row. Rows stored before rows had versions count as version 0.
+fn version_of(row: &Value) -> u64 {
+    row.get("_version").and_then(Value::as_u64).unwrap_or(0)
+}
+
 // TODO: The error handling of this file is abysmal
 
-pub fn insert_data(state: &mut State, table_name: &str, mut data: Map<String, Value>) -> Result<String, RowError> {
-    if !state.tables.contains_key(table_name) {
-        return Err(TableDoesntExist)
+/// Make writes in the connection's open transaction, or in one of their own which is committed
+/// straight away if there isn't one. A failed commit is reported as `failed`. A transaction of
+/// their own which conflicts with another commit is retried, as nobody has seen what it read.
+fn in_transaction<T>(
+    state: &State,
+    transaction: Option<&mut Transaction>,
+    failed: RowError,
+    mut f: impl FnMut(&mut Transaction) -> Result<T, RowError>,
+) -> Result<T, RowError> {
+    if let Some(transaction) = transaction {
+        return f(transaction)
     }
-
-    // Get the index of the first sub_table which has space for a new record
-    let mut table_metadata = file_reader::read_table_metadata(table_name).map_err(|_| FailedInsert)?;
-    let mut sub_table_index: Option<usize> = None;
-    for (index, value) in table_metadata.sub_tables.iter().enumerate() {
-        if *value < table_metadata.records_per_sub_table {
-            sub_table_index = Some(index);
+    loop {
+        let mut transaction = Transaction::begin(state);
+        let result = f(&mut transaction)?;
+        match transaction.commit(state) {
+            Ok(()) => return Ok(result),
+            Err(RowError::WriteConflict(_) | RowError::TableConflict(_)) => continue,
+            Err(RowError::FailedCommit) => return Err(failed),
+            Err(e) => return Err(e)
         }
     }
+}
 
-    // Create a new sub_table if none of the existing ones have space
-    if sub_table_index.is_none() {
-        let new_index = table_metadata.sub_tables.len();
-        // TODO: This is not ACID, if anything fails after the sub_table is updated with a 1 here then the db is in a bad state
-        table_metadata.sub_tables.push(1);
-        file_reader::replace_table_metadata(table_name, &table_metadata).map_err(|_| FailedInsert)?;
-        file_reader::create_table_sub_table(table_name, new_index).map_err(|_| FailedInsert)?;
-        sub_table_index = Some(new_index);
+/// Check that a row can be written without breaking any of the table's constraints. A clash with
+/// a row the transaction has written to may be gone by the time it commits, so those are left to
+/// be checked on commit.
+fn check_constraints(table: &Table, transaction: &Transaction, row: &Value, own_id: Option<&str>) -> Result<(), RowError> {
+    match table.check_constraints(row, own_id) {
+        Err(ConstraintViolation::Unique { conflicting_id, .. }) if transaction.written_row(table.name.as_str(), conflicting_id.as_str()).is_some() => Ok(()),
+        result => result.map_err(RowError::ConstraintViolation)
     }
+}
 
-    // Insert a record into the current sub_table
-    let sub_table_index = sub_table_index.expect("Sub table index must be Some at this point");
-    let id = generate_new_id(sub_table_index);
-    data.insert("_id".to_string(), Value::String(id.clone()));
-    let serialized = serde_json::to_string(&data).map_err(|_| FailedInsert)?;
-    table_metadata.sub_tables[sub_table_index] += 1;
-    file_reader::replace_table_metadata(table_name, &table_metadata).map_err(|_| FailedInsert)?;
-    file_reader::insert_record_to_sub_table(table_name, sub_table_index, serialized).map_err(|_| FailedInsert)?;
+pub fn insert_data(state: &State, transaction: Option<&mut Transaction>, table_name: &str, data: Map<String, Value>) -> Result<String, RowError> {
+    in_transaction(state, transaction, FailedInsert, |transaction| {
+        let table = state.get_table(table_name).ok_or(TableDoesntExist)?;
+        let table = table.read().expect("Table lock was poisoned");
+        table.validate_row(&data).map_err(RowError::SchemaViolation)?;
+        let mut row = Value::Object(data.clone());
+        check_constraints(&table, transaction, &row, None)?;
 
-    Ok(id)
+        // Take a slot in the first sub_table which has space for a new record, or in a new
+        // sub_table if none of the existing ones have space. Rows inserted but not committed yet,
+        // by this or any other transaction, count towards how full a sub_table is.
+        let reserved_slots = table.reserved_slots();
+        let sub_table_index = reserved_slots.reserve(|| state.storage.read_table_metadata(table_name).map_err(|_| FailedInsert))?;
+        transaction.hold_slot(reserved_slots, sub_table_index);
+
+        let id = generate_new_id(sub_table_index);
+        row["_id"] = Value::String(id.clone());
+        row["_version"] = Value::from(1);
+        transaction.write(table_name, id.as_str(), sub_table_index, false, Some(row));
+        Ok(id)
+    })
 }
 
-pub fn read_data_by_id(_state: &State, table_name: &str, data: Map<String, Value>) -> Result<Value, RowError> {
-    // Read which sub_table the record is in from the ID
+/// Get the `_id` a request is targeting, along with the index of the sub_table that its row is
+/// stored in
+fn parse_target_id(data: &Map<String, Value>) -> Result<(&str, usize), RowError> {
     let target_id = match data.get("_id") {
         Some(Value::String(string_field)) => string_field,
-        _ => return Err(RowError::ReadMissingKey("_id".to_string(), "string".to_string())),
+        _ => return Err(RowError::MissingKey("_id".to_string(), "string".to_string())),
     };
     let index_as_str = target_id.split(".").next().ok_or(RowError::MalformedID)?;
     let sub_table_index: usize = index_as_str.parse().map_err(|_| RowError::MalformedID)?;
+    Ok((target_id, sub_table_index))
+}
 
+/// Get the version a request expects the row it targets to be at, if it gave one
+fn parse_expected_version(data: &Map<String, Value>) -> Result<Option<u64>, RowError> {
+    match data.get("_version") {
+        None => Ok(None),
+        Some(version) => version.as_u64().map(Some).ok_or(RowError::MalformedVersion)
+    }
+}
 
-    // TODO: De-serializing an entire file to search for a record seems pretty inefficient
-    // TODO: This is really poor code, but it was written at 1am
-    // Read the sub-table containing our record
-    let sub_table_contents = file_reader::read_sub_table(table_name, sub_table_index).map_err(|_| RowError::FailedRead)?;
-    match sub_table_contents {
-        Value::Array(contents) => {
-            for item in &contents {
-                match item {
-                    Value::Object(obj) => {
-                        // This error is not meaningful and should return a malformed table file error instead
-                        match obj.get("_id").ok_or(RowError::FailedRead)? {
-                            Value::String(obj_key) => {
-                                if obj_key == target_id {
-                                    return Ok(item.clone())
-                                }
-                            },
-                            // This error is not meaningful and should return a malformed table file error instead
-                            _ => return Err(RowError::FailedRead)
-                        }
-                    },
-                    // This error is not meaningful and should return a malformed table file error instead
-                    _ => return Err(RowError::FailedRead)
+/// Check that a row is at the version a request expects it to be at
+fn check_version(row: &Value, expected: Option<u64>) -> Result<(), RowError> {
+    match expected {
+        Some(expected) if expected != version_of(row) => Err(RowError::VersionConflict(expected, version_of(row))),
+        _ => Ok(())
+    }
+}
+
+/// Read a single row by ID as a transaction sees it, seeking straight to it in its sub_table
+fn read_row(state: &State, transaction: Option<&Transaction>, table_name: &str, sub_table_index: usize, id: &str) -> Result<Option<Value>, RowError> {
+    if let Some(transaction) = transaction {
+        transaction.check_table(state, table_name)?;
+    }
+    if let Some(written) = transaction.and_then(|transaction| transaction.written_row(table_name, id)) {
+        return Ok(written.cloned())
+    }
+    // An ID pointing past the last sub_table can't belong to any stored row, though it can
+    // belong to one the transaction inserted into a new sub_table
+    let table_metadata = state.storage.read_table_metadata(table_name).map_err(|_| RowError::FailedRead)?;
+    if sub_table_index >= table_metadata.sub_tables.len() {
+        return Ok(None)
+    }
+    let stored = state.storage.read_row(table_name, sub_table_index, id).map_err(|_| RowError::FailedRead)?;
+    match transaction {
+        Some(transaction) => Ok(state.versions.visible(transaction.snapshot(), table_name, id, stored)),
+        None => Ok(stored)
+    }
+}
+
+/// Call `f` on every row of a table as a transaction sees it, stopping early if `f` returns
+/// false. The stored rows picked out by `scan` are read from storage, rows changed since the
+/// transaction began are seen as they were when it began, and every row the transaction has
+/// written to is seen as the transaction left it.
+fn for_each_row(
+    state: &State,
+    transaction: Option<&Transaction>,
+    table_name: &str,
+    scan: RowsToScan,
+    mut f: impl FnMut(Value) -> bool,
+) -> Result<(), RowError> {
+    // Rows whose stored version isn't the one the transaction sees, along with the one it does
+    let mut overlay = BTreeMap::new();
+    if let Some(transaction) = transaction {
+        transaction.check_table(state, table_name)?;
+        overlay.extend(state.versions.visible_changes(transaction.snapshot(), table_name));
+        overlay.extend(transaction.written_rows(table_name).map(|(id, row)| (id.to_string(), row.cloned())));
+    }
+    for row in overlay.values().flatten() {
+        if !f(row.clone()) {
+            return Ok(())
+        }
+    }
+    match scan {
+        RowsToScan::SubTables(sub_table_count) => {
+            for sub_table_index in 0..sub_table_count {
+                let rows = state.storage.read_sub_table(table_name, sub_table_index).map_err(|_| RowError::FailedRead)?;
+                for row in rows {
+                    let overlaid = row.get("_id").and_then(Value::as_str).is_some_and(|id| overlay.contains_key(id));
+                    if !overlaid && !f(row) {
+                        return Ok(())
+                    }
+                }
+            }
+        },
+        RowsToScan::Ids(ids, sub_table_count) => {
+            for id in ids.iter().filter(|id| !overlay.contains_key(id.as_str())) {
+                let Some(sub_table_index) = id.split(".").next().and_then(|index| index.parse::<usize>().ok()) else {
+                    continue
+                };
+                if sub_table_index >= sub_table_count {
+                    continue
+                }
+                let row = state.storage.read_row(table_name, sub_table_index, id.as_str()).map_err(|_| RowError::FailedRead)?;
+                if let Some(row) = row && !f(row) {
+                    return Ok(())
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn read_data_by_id(state: &State, transaction: Option<&Transaction>, table_name: &str, data: Map<String, Value>) -> Result<Value, RowError> {
+    let table = state.get_table(table_name).ok_or(TableDoesntExist)?;
+    // Held so that the row can't be written to while it is being read
+    let _table = table.read().expect("Table lock was poisoned");
+
+    // Read which sub_table the record is in from the ID
+    let (target_id, sub_table_index) = parse_target_id(&data)?;
+    let projection = Projection::from_request(&data)?;
+
+    // Seek straight to the record in its sub_table
+    let row = read_row(state, transaction, table_name, sub_table_index, target_id)?;
+    Ok(projection.apply(row.ok_or(RowError::FailedToFindRecord)?))
+}
+
+/// Apply `patch` on top of `target` as a JSON merge patch (RFC 7396). Objects are merged key by
+/// key, a `null` removes the key, and any other value replaces what was there.
+fn merge_patch(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            let target = target.as_object_mut().expect("Target was just made an object");
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(&key);
+                } else {
+                    merge_patch(target.entry(key).or_insert(Value::Null), value);
                 }
             }
         },
-        // This error is not meaningful and should return a malformed table file error instead
-        _ => return Err(RowError::FailedRead)
+        patch => *target = patch
+    }
+}
+
+/// Update a row by `_id`. Update requests look like
+/// `{"_id": "<id>", "data": {...}, "mode": "merge" | "replace", "_version": <version>}`. A `merge`
+/// (the default) applies `data` to the row as a JSON merge patch, while a `replace` swaps out
+/// every field of the row. The optional `_version` is the version the row is expected to be at.
+/// Returns the row as it is after the update.
+pub fn update_data(state: &State, transaction: Option<&mut Transaction>, table_name: &str, data: Map<String, Value>) -> Result<Value, RowError> {
+    let (target_id, sub_table_index) = parse_target_id(&data)?;
+    let expected_version = parse_expected_version(&data)?;
+    let new_data = match data.get("data") {
+        Some(Value::Object(new_data)) => new_data.clone(),
+        _ => return Err(RowError::MissingKey("data".to_string(), "object".to_string())),
+    };
+    // The ID can be given again in the new data, but it can't be changed
+    match new_data.get("_id") {
+        None => (),
+        Some(Value::String(new_id)) if new_id == target_id => (),
+        Some(_) => return Err(RowError::CannotModifyID),
+    }
+    let replace = match data.get("mode") {
+        None => false,
+        Some(Value::String(mode)) if mode == "merge" => false,
+        Some(Value::String(mode)) if mode == "replace" => true,
+        Some(other) => return Err(RowError::InvalidUpdateMode(other.to_string())),
+    };
+
+    in_transaction(state, transaction, RowError::FailedUpdate, |transaction| {
+        let table = state.get_table(table_name).ok_or(TableDoesntExist)?;
+        let table = table.read().expect("Table lock was poisoned");
+
+        let mut updated = read_row(state, Some(transaction), table_name, sub_table_index, target_id)?
+            .ok_or(RowError::FailedToFindRecord)?;
+        check_version(&updated, expected_version)?;
+        let version = version_of(&updated) + 1;
+        if replace {
+            updated = Value::Object(new_data.clone());
+        } else {
+            merge_patch(&mut updated, Value::Object(new_data.clone()));
+        }
+        // Re-insert the ID and version last so that neither mode can drop or change them
+        let updated_row = updated.as_object_mut().expect("A row is always an object after an update");
+        updated_row.insert("_id".to_string(), Value::String(target_id.to_string()));
+        updated_row.insert("_version".to_string(), Value::from(version));
+        table.validate_row(updated.as_object().expect("A row is always an object after an update")).map_err(RowError::SchemaViolation)?;
+        check_constraints(&table, transaction, &updated, Some(target_id))?;
+
+        transaction.write(table_name, target_id, sub_table_index, true, Some(updated.clone()));
+        Ok(updated)
+    })
+}
+
+/// Delete a row by `_id`, returning the deleted row. A `_version` can be given as the version
+/// the row is expected to be at. The row's sub_table has its record count decremented so that
+/// later inserts can fill the space back in.
+pub fn delete_data(state: &State, transaction: Option<&mut Transaction>, table_name: &str, data: Map<String, Value>) -> Result<Value, RowError> {
+    let (target_id, sub_table_index) = parse_target_id(&data)?;
+    let expected_version = parse_expected_version(&data)?;
+
+    in_transaction(state, transaction, RowError::FailedDelete, |transaction| {
+        let table = state.get_table(table_name).ok_or(TableDoesntExist)?;
+        let _table = table.read().expect("Table lock was poisoned");
+
+        let deleted = read_row(state, Some(transaction), table_name, sub_table_index, target_id)?
+            .ok_or(RowError::FailedToFindRecord)?;
+        check_version(&deleted, expected_version)?;
+        transaction.write(table_name, target_id, sub_table_index, true, None);
+        Ok(deleted)
+    })
+}
+
+/// Which stored rows of a table need to be read to find every row matching a filter
+enum RowsToScan {
+    /// Every row, read a whole sub_table at a time, out of this many sub_tables
+    SubTables(usize),
+    /// Only the rows with these IDs, each read on its own, out of this many sub_tables
+    Ids(BTreeSet<String>, usize),
+}
+
+/// Work out which stored rows need to be read to find every row matching a filter. If one of the
+/// fields the filter requires has an index, only the rows found by the index are read. They still
+/// need to be checked against the filter.
+fn rows_to_scan(table: &Table, filter: &Filter, sub_table_count: usize) -> RowsToScan {
+    match table.candidate_ids(filter) {
+        Some(ids) => RowsToScan::Ids(ids, sub_table_count),
+        None => RowsToScan::SubTables(sub_table_count)
+    }
+}
+
+/// Find every row in a table matching a query. Query requests look like `{"filter": {...}}`, see
+/// `query` for what a filter can hold and how the returned rows can be sorted, paginated and
+/// projected. A missing filter matches every row.
+pub fn query_data(state: &State, transaction: Option<&Transaction>, table_name: &str, data: Map<String, Value>) -> Result<Vec<Value>, RowError> {
+    let table = state.get_table(table_name).ok_or(TableDoesntExist)?;
+    let table = table.read().expect("Table lock was poisoned");
+
+    let query = Query::from_request(&data)?;
+    let rows_needed = query.rows_needed();
+
+    let table_metadata = state.storage.read_table_metadata(table_name).map_err(|_| RowError::FailedRead)?;
+    let mut matching = Vec::new();
+    let scan = rows_to_scan(&table, &query.filter, table_metadata.sub_tables.len());
+    for_each_row(state, transaction, table_name, scan, |row| {
+        if query.filter.matches(&row) {
+            matching.push(row);
+        }
+        rows_needed.is_none_or(|needed| matching.len() < needed)
+    })?;
+    Ok(query.shape(matching))
+}
+
+/// Compute aggregates over the rows of a table, see `aggregate` for what a request looks like.
+pub fn aggregate_data(state: &State, transaction: Option<&Transaction>, table_name: &str, data: Map<String, Value>) -> Result<Value, RowError> {
+    let table = state.get_table(table_name).ok_or(TableDoesntExist)?;
+    let table = table.read().expect("Table lock was poisoned");
+
+    let request = AggregateRequest::from_request(&data)?;
+    if let Some(transaction) = transaction {
+        transaction.check_table(state, table_name)?;
+    }
+    let table_metadata = state.storage.read_table_metadata(table_name).map_err(|_| RowError::FailedRead)?;
+
+    // The metadata already tracks how many rows are in each sub_table, though not the rows
+    // written by a transaction which hasn't committed yet, or as they were when it began
+    let overlaid = transaction.is_some_and(|transaction| {
+        transaction.written_rows(table_name).next().is_some() || state.versions.table_changed_since(transaction.snapshot(), table_name)
+    });
+    if request.is_plain_count() && !overlaid {
+        return Ok(request.plain_count(table_metadata.sub_tables.iter().sum()))
+    }
+
+    let mut aggregator = request.aggregator();
+    let scan = rows_to_scan(&table, &request.filter, table_metadata.sub_tables.len());
+    for_each_row(state, transaction, table_name, scan, |row| {
+        if request.filter.matches(&row) {
+            aggregator.add(&row);
+        }
+        true
+    })?;
+    Ok(aggregator.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::storage::memory::MemoryStorage;
+    use crate::tables::DEFAULT_RECORDS_PER_SUB_TABLE;
+    use crate::rows::query::Condition;
+    use crate::tables::index::FieldIndex;
+    use crate::tcp::frame::{Command, Frame};
+    use crate::tcp::response::ResponseError;
+
+    fn data(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("Request data must be an object")
+        }
+    }
+
+    fn new_state() -> State {
+        let state = State::initialize(Box::new(MemoryStorage::default()), DEFAULT_RECORDS_PER_SUB_TABLE);
+        let frame = Frame { command: Command::CreateTable, table: "people".to_string(), data: Map::new() };
+        Table::create_table(&state, frame).unwrap();
+        state
+    }
+
+    #[test]
+    fn ids_past_the_last_sub_table_are_not_found() {
+        let state = new_state();
+        let id = "7.abc";
+        let errors = [
+            read_data_by_id(&state, None, "people", data(json!({"_id": id}))).unwrap_err(),
+            update_data(&state, None, "people", data(json!({"_id": id, "data": {"name": "alice"}}))).unwrap_err(),
+            delete_data(&state, None, "people", data(json!({"_id": id}))).unwrap_err(),
+        ];
+        for error in errors {
+            assert_eq!((error.status(), error.code()), (404, "row_not_found"));
+        }
+    }
+
+    #[test]
+    fn indexed_queries_fetch_rows_by_id() {
+        let state = State::initialize(Box::new(MemoryStorage::default()), 2);
+        Table::create_table(&state, Frame { command: Command::CreateTable, table: "people".to_string(), data: Map::new() }).unwrap();
+        let mut ids = Vec::new();
+        for (name, city) in [("alice", "paris"), ("bob", "rome"), ("carol", "paris"), ("dave", "oslo"), ("erin", "paris")] {
+            ids.push(insert_data(&state, None, "people", data(json!({"name": name, "city": city}))).unwrap());
+        }
+        Table::create_index(&state, Frame { command: Command::CreateIndex, table: "people".to_string(), data: data(json!({"field": "city"})) }).unwrap();
+
+        update_data(&state, None, "people", data(json!({"_id": ids[1], "data": {"name": "bob", "city": "paris"}}))).unwrap();
+        delete_data(&state, None, "people", data(json!({"_id": ids[2]}))).unwrap();
+
+        let names = |city: &str| -> Vec<Value> {
+            let rows = query_data(&state, None, "people", data(json!({"filter": {"city": city}, "sort": [{"field": "name"}]}))).unwrap();
+            rows.into_iter().map(|row| row["name"].clone()).collect()
+        };
+        assert_eq!(names("paris"), vec![json!("alice"), json!("bob"), json!("erin")]);
+        assert_eq!(names("rome"), Vec::<Value>::new());
+
+        // The changes appended to the stored index bring it up to date with the one in memory
+        let (index, changes) = state.storage.read_index("people", "city").unwrap();
+        assert_eq!(changes.len(), 2);
+        let stored = FieldIndex::from_stored("city", index, changes).unwrap();
+        let paris = stored.lookup(&Condition::Eq(json!("paris"))).unwrap();
+        assert_eq!(paris, BTreeSet::from([ids[0].clone(), ids[1].clone(), ids[4].clone()]));
+        assert!(stored.lookup(&Condition::Eq(json!("rome"))).unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_inserts_never_overfill_a_sub_table() {
+        const THREADS: usize = 4;
+        const ROWS: usize = 50;
+        let state = State::initialize(Box::new(MemoryStorage::default()), 3);
+        Table::create_table(&state, Frame { command: Command::CreateTable, table: "people".to_string(), data: Map::new() }).unwrap();
+
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let state = &state;
+                scope.spawn(move || {
+                    for n in 0..ROWS {
+                        // Every other row goes through an explicit transaction, holding its slot
+                        // for a while before committing
+                        if n % 2 == 0 {
+                            insert_data(state, None, "people", data(json!({"thread": thread, "n": n}))).unwrap();
+                        } else {
+                            let mut transaction = Transaction::begin(state);
+                            insert_data(state, Some(&mut transaction), "people", data(json!({"thread": thread, "n": n}))).unwrap();
+                            std::thread::yield_now();
+                            transaction.commit(state).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        let metadata = state.storage.read_table_metadata("people").unwrap();
+        assert!(metadata.sub_tables.iter().all(|count| *count <= 3), "{:?}", metadata.sub_tables);
+        assert_eq!(metadata.sub_tables.iter().sum::<usize>(), THREADS * ROWS);
+        assert_eq!(query_data(&state, None, "people", Map::new()).unwrap().len(), THREADS * ROWS);
+    }
+
+    #[test]
+    fn slots_are_given_back_when_a_transaction_ends() {
+        let state = State::initialize(Box::new(MemoryStorage::default()), 2);
+        Table::create_table(&state, Frame { command: Command::CreateTable, table: "people".to_string(), data: Map::new() }).unwrap();
+        let insert = |transaction: Option<&mut Transaction>| insert_data(&state, transaction, "people", data(json!({"name": "alice"}))).unwrap();
+
+        // Rows which haven't been committed still take up their sub_table
+        let mut transaction = Transaction::begin(&state);
+        assert!(insert(Some(&mut transaction)).starts_with("0."));
+        assert!(insert(Some(&mut transaction)).starts_with("0."));
+        assert!(insert(None).starts_with("1."));
+
+        // Until they are rolled back
+        drop(transaction);
+        assert!(insert(None).starts_with("0."));
+        assert!(insert(None).starts_with("0."));
+        assert!(insert(None).starts_with("1."));
+        assert_eq!(state.storage.read_table_metadata("people").unwrap().sub_tables, vec![2, 2]);
+    }
+
+    #[test]
+    fn versions_go_up_with_each_update() {
+        let state = new_state();
+        let id = insert_data(&state, None, "people", data(json!({"name": "alice"}))).unwrap();
+        let read = || read_data_by_id(&state, None, "people", data(json!({"_id": id}))).unwrap();
+        assert_eq!(read()["_version"], 1);
+
+        let updated = update_data(&state, None, "people", data(json!({"_id": id, "_version": 1, "data": {"name": "bob"}}))).unwrap();
+        assert_eq!(updated["_version"], 2);
+        // Neither mode lets the version be set by the request
+        let updated = update_data(&state, None, "people", data(json!({"_id": id, "data": {"_version": 40}, "mode": "replace"}))).unwrap();
+        assert_eq!(updated, json!({"_id": id, "_version": 3}));
+        update_data(&state, None, "people", data(json!({"_id": id, "data": {"_version": null}}))).unwrap();
+        assert_eq!(read()["_version"], 4);
+    }
+
+    #[test]
+    fn stale_versions_are_rejected() {
+        let state = new_state();
+        let id = insert_data(&state, None, "people", data(json!({"name": "alice"}))).unwrap();
+        update_data(&state, None, "people", data(json!({"_id": id, "data": {"name": "bob"}}))).unwrap();
+
+        let errors = [
+            update_data(&state, None, "people", data(json!({"_id": id, "_version": 1, "data": {"name": "carol"}}))).unwrap_err(),
+            delete_data(&state, None, "people", data(json!({"_id": id, "_version": 1}))).unwrap_err(),
+        ];
+        for error in errors {
+            assert_eq!((error.status(), error.code()), (409, "version_conflict"));
+            assert_eq!(error.details(), Some(json!({"expected_version": 1, "current_version": 2})));
+        }
+        let row = read_data_by_id(&state, None, "people", data(json!({"_id": id}))).unwrap();
+        assert_eq!((&row["name"], &row["_version"]), (&json!("bob"), &json!(2)));
+
+        let error = delete_data(&state, None, "people", data(json!({"_id": id, "_version": "2"}))).unwrap_err();
+        assert_eq!(error.code(), "malformed_version");
+        delete_data(&state, None, "people", data(json!({"_id": id, "_version": 2}))).unwrap();
+    }
+
+    #[test]
+    fn only_one_of_two_racing_writers_wins() {
+        let state = new_state();
+        for _round in 0..50 {
+            let id = insert_data(&state, None, "people", data(json!({"name": "alice"}))).unwrap();
+            let barrier = std::sync::Barrier::new(2);
+            let results: Vec<Result<Value, RowError>> = std::thread::scope(|scope| {
+                let writers: Vec<_> = ["bob", "carol"].into_iter().map(|name| {
+                    let (state, id, barrier) = (&state, id.as_str(), &barrier);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        update_data(state, None, "people", data(json!({"_id": id, "_version": 1, "data": {"name": name}})))
+                    })
+                }).collect();
+                writers.into_iter().map(|writer| writer.join().unwrap()).collect()
+            });
+
+            let (won, lost): (Vec<_>, Vec<_>) = results.into_iter().partition(Result::is_ok);
+            assert_eq!((won.len(), lost.len()), (1, 1));
+            let error = lost.into_iter().next().unwrap().unwrap_err();
+            assert_eq!((error.status(), error.code()), (409, "version_conflict"));
+            assert_eq!(error.details(), Some(json!({"expected_version": 1, "current_version": 2})));
+
+            let winner = won.into_iter().next().unwrap().unwrap();
+            let row = read_data_by_id(&state, None, "people", data(json!({"_id": id}))).unwrap();
+            assert_eq!(row, winner);
+            assert_eq!(row["_version"], 2);
+        }
     }
-    Err(RowError::FailedToFindRecord)
 }
@@ -0,0 +1,497 @@
+use std::cmp::Ordering;
+use serde_json::{Map, Value};
+use crate::rows::row_err::RowError;
+
+/*
+    A filter is a JSON object which rows are matched against. Each key is either a field path, or
+    one of the `$and` / `$or` logical operators which take a list of filters. A field path may use
+    dots to reach into nested objects, `address.city` matches against the `city` field of a row's
+    `address` object. Every key of a filter must match for a row to match.
+
+    A field is matched against either a plain value, which is checked for equality, or an object of
+    operators which must all hold:
+        { "age": { "$gt": 18, "$lt": 65 }, "status": { "$in": ["active", "trial"] } }
+
+    A field missing from a row is treated as `null`, so `{"deleted_at": null}` matches rows which do
+    not have a `deleted_at` field.
+
+    Alongside the filter, a query can shape the rows it returns:
+        {
+            "filter": { ... },
+            "fields": ["name", "address.city"],
+            "exclude_id": false,
+            "sort": [{ "field": "age", "order": "desc" }, { "field": "name" }],
+            "offset": 20,
+            "limit": 10
+        }
+
+    `fields` picks which fields of each row are returned, `_id` is always included unless
+    `exclude_id` is set. Rows are sorted by each sort key in turn, ascending unless `order` is
+    "desc". Sorting works across JSON types, with values ordered
+    null (or missing) < bools < numbers < strings < arrays < objects.
+*/
+
+#[derive(Debug)]
+pub enum Condition {
+    Eq(Value),
+    Ne(Value),
+    Gt(Value),
+    Lt(Value),
+    In(Vec<Value>),
+}
+
+#[derive(Debug)]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Field(Vec<String>, Condition),
+}
+
+impl Filter {
+    /// Parse a filter document. An empty document matches every row.
+    pub fn from_value(value: &Value) -> Result<Self, RowError> {
+        match value {
+            Value::Object(map) => Self::from_map(map),
+            _ => Err(RowError::InvalidFilter("A filter must be an object".to_string()))
+        }
+    }
+
+    fn from_map(map: &Map<String, Value>) -> Result<Self, RowError> {
+        let mut filters = Vec::with_capacity(map.len());
+        for (key, value) in map {
+            let filter = match key.as_str() {
+                "$and" => Self::And(Self::from_list(key, value)?),
+                "$or" => Self::Or(Self::from_list(key, value)?),
+                _ if key.starts_with('$') => return Err(RowError::InvalidFilter(format!("Unknown logical operator '{}'", key))),
+                _ => Self::from_field(key, value)?,
+            };
+            filters.push(filter);
+        }
+        Ok(Self::And(filters))
+    }
+
+    fn from_list(key: &str, value: &Value) -> Result<Vec<Self>, RowError> {
+        match value {
+            Value::Array(list) => list.iter().map(Self::from_value).collect(),
+            _ => Err(RowError::InvalidFilter(format!("'{}' must be given a list of filters", key)))
+        }
+    }
+
+    fn from_field(key: &str, value: &Value) -> Result<Self, RowError> {
+        let path: Vec<String> = key.split('.').map(|segment| segment.to_string()).collect();
+        // An object with operator keys holds conditions, any other value is matched by equality
+        let operators = match value {
+            Value::Object(map) if map.keys().next().is_some_and(|key| key.starts_with('$')) => map,
+            _ => return Ok(Self::Field(path, Condition::Eq(value.clone())))
+        };
+
+        let mut conditions = Vec::with_capacity(operators.len());
+        for (operator, operand) in operators {
+            let condition = match operator.as_str() {
+                "$eq" => Condition::Eq(operand.clone()),
+                "$ne" => Condition::Ne(operand.clone()),
+                "$gt" => Condition::Gt(operand.clone()),
+                "$lt" => Condition::Lt(operand.clone()),
+                "$in" => match operand {
+                    Value::Array(list) => Condition::In(list.clone()),
+                    _ => return Err(RowError::InvalidFilter(format!("'$in' on '{}' must be given a list", key)))
+                },
+                _ => return Err(RowError::InvalidFilter(format!("Unknown operator '{}' on '{}'", operator, key)))
+            };
+            conditions.push(Self::Field(path.clone(), condition));
+        }
+        Ok(Self::And(conditions))
+    }
+
+    /// Every field condition which must hold for a row to match, found by flattening the `$and`s
+    /// at the top of the filter
+    pub fn required_conditions(&self) -> Vec<(&[String], &Condition)> {
+        match self {
+            Self::And(filters) => filters.iter().flat_map(|filter| filter.required_conditions()).collect(),
+            Self::Or(_) => Vec::new(),
+            Self::Field(path, condition) => vec![(path.as_slice(), condition)],
+        }
+    }
+
+    pub fn matches(&self, row: &Value) -> bool {
+        match self {
+            Self::And(filters) => filters.iter().all(|filter| filter.matches(row)),
+            Self::Or(filters) => filters.iter().any(|filter| filter.matches(row)),
+            Self::Field(path, condition) => {
+                let field = get_path(row, path).unwrap_or(&Value::Null);
+                match condition {
+                    Condition::Eq(value) => values_equal(field, value),
+                    Condition::Ne(value) => !values_equal(field, value),
+                    Condition::Gt(value) => compare_values(field, value) == Some(Ordering::Greater),
+                    Condition::Lt(value) => compare_values(field, value) == Some(Ordering::Less),
+                    Condition::In(values) => values.iter().any(|value| values_equal(field, value)),
+                }
+            }
+        }
+    }
+}
+
+/// Follow a dotted path into a row
+pub fn get_path<'a>(row: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(row, |value, segment| value.get(segment))
+}
+
+/// Equality which treats numbers as equal by value, so that `1` and `1.0` are the same
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(_), Value::Number(_)) => compare_values(a, b) == Some(Ordering::Equal),
+        _ => a == b
+    }
+}
+
+/// Compare two values of the same type. Values of different types are not comparable.
+fn compare_values(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64()) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => a.as_f64()?.partial_cmp(&b.as_f64()?)
+        },
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        _ => None
+    }
+}
+
+/// Order two values of any type, see the type ordering at the top of this file. Arrays are ordered
+/// element by element, and objects by their sorted keys and then values.
+pub fn total_order(a: &Value, b: &Value) -> Ordering {
+    fn type_rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Array(_) => 4,
+            Value::Object(_) => 5,
+        }
+    }
+
+    match (a, b) {
+        (Value::Array(a), Value::Array(b)) => {
+            a.iter().zip(b.iter())
+                .map(|(a, b)| total_order(a, b))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| a.len().cmp(&b.len()))
+        },
+        (Value::Object(a), Value::Object(b)) => {
+            // serde_json's Map keeps its keys sorted
+            a.iter().zip(b.iter())
+                .map(|((a_key, a_value), (b_key, b_value))| a_key.cmp(b_key).then_with(|| total_order(a_value, b_value)))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| a.len().cmp(&b.len()))
+        },
+        // NaN can't be stored in a serde_json Number, so values of the same scalar type always compare
+        _ => compare_values(a, b).unwrap_or_else(|| type_rank(a).cmp(&type_rank(b)))
+    }
+}
+
+/// Which fields of a row to return
+#[derive(Debug)]
+pub struct Projection {
+    fields: Option<Vec<Vec<String>>>,
+    exclude_id: bool,
+}
+
+impl Projection {
+    pub fn from_request(data: &Map<String, Value>) -> Result<Self, RowError> {
+        let fields = match data.get("fields") {
+            None => None,
+            Some(Value::Array(fields)) => {
+                let paths = fields.iter().map(|field| match field {
+                    Value::String(field) => Ok(field.split('.').map(|segment| segment.to_string()).collect()),
+                    _ => Err(RowError::InvalidQuery("'fields' must be a list of strings".to_string()))
+                });
+                Some(paths.collect::<Result<Vec<Vec<String>>, RowError>>()?)
+            },
+            Some(_) => return Err(RowError::InvalidQuery("'fields' must be a list of strings".to_string()))
+        };
+        let exclude_id = match data.get("exclude_id") {
+            None => false,
+            Some(Value::Bool(exclude_id)) => *exclude_id,
+            Some(_) => return Err(RowError::InvalidQuery("'exclude_id' must be a bool".to_string()))
+        };
+        Ok(Self { fields, exclude_id })
+    }
+
+    pub fn apply(&self, row: Value) -> Value {
+        let mut projected = match &self.fields {
+            None => row.clone(),
+            Some(fields) => {
+                let mut projected = Value::Object(Map::new());
+                for path in fields {
+                    if let Some(value) = get_path(&row, path) {
+                        set_path(&mut projected, path, value.clone());
+                    }
+                }
+                if let Some(id) = row.get("_id") {
+                    set_path(&mut projected, &["_id".to_string()], id.clone());
+                }
+                projected
+            }
+        };
+        if self.exclude_id && let Value::Object(map) = &mut projected {
+            map.remove("_id");
+        }
+        projected
+    }
+}
+
+/// Set the value at a dotted path, creating nested objects along the way
+fn set_path(target: &mut Value, path: &[String], value: Value) {
+    let Some((last, parents)) = path.split_last() else {
+        return
+    };
+    let mut current = target;
+    for segment in parents {
+        let map = current.as_object_mut().expect("Projected values are only ever built out of objects");
+        current = map.entry(segment.clone()).or_insert_with(|| Value::Object(Map::new()));
+        if !current.is_object() {
+            return
+        }
+    }
+    if let Value::Object(map) = current {
+        map.insert(last.clone(), value);
+    }
+}
+
+#[derive(Debug)]
+struct SortKey {
+    path: Vec<String>,
+    descending: bool,
+}
+
+/// A full query, a filter along with how the matching rows should be sorted, paginated and
+/// projected
+#[derive(Debug)]
+pub struct Query {
+    pub filter: Filter,
+    projection: Projection,
+    sort: Vec<SortKey>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl Query {
+    pub fn from_request(data: &Map<String, Value>) -> Result<Self, RowError> {
+        let filter = match data.get("filter") {
+            Some(filter) => Filter::from_value(filter)?,
+            None => Filter::And(Vec::new()),
+        };
+        let sort = match data.get("sort") {
+            None => Vec::new(),
+            Some(Value::Array(keys)) => keys.iter().map(Self::parse_sort_key).collect::<Result<Vec<SortKey>, RowError>>()?,
+            Some(_) => return Err(RowError::InvalidQuery("'sort' must be a list of sort keys".to_string()))
+        };
+        Ok(Self {
+            filter,
+            projection: Projection::from_request(data)?,
+            sort,
+            offset: Self::parse_count(data, "offset")?.unwrap_or(0),
+            limit: Self::parse_count(data, "limit")?,
+        })
+    }
+
+    fn parse_sort_key(value: &Value) -> Result<SortKey, RowError> {
+        let path = match value.get("field") {
+            Some(Value::String(field)) => field.split('.').map(|segment| segment.to_string()).collect(),
+            _ => return Err(RowError::InvalidQuery("Each sort key must have a 'field' string".to_string()))
+        };
+        let descending = match value.get("order") {
+            None => false,
+            Some(Value::String(order)) if order == "asc" => false,
+            Some(Value::String(order)) if order == "desc" => true,
+            Some(_) => return Err(RowError::InvalidQuery("A sort key's 'order' must be 'asc' or 'desc'".to_string()))
+        };
+        Ok(SortKey { path, descending })
+    }
+
+    fn parse_count(data: &Map<String, Value>, key: &str) -> Result<Option<usize>, RowError> {
+        match data.get(key) {
+            None => Ok(None),
+            Some(value) => value.as_u64()
+                .map(|count| Some(count as usize))
+                .ok_or(RowError::InvalidQuery(format!("'{}' must be a non-negative integer", key)))
+        }
+    }
+
+    /// How many matching rows need to be found before the rest can be skipped, if the query can
+    /// stop early. Rows can't be skipped when sorting, as any of them could sort first.
+    pub fn rows_needed(&self) -> Option<usize> {
+        match self.sort.is_empty() {
+            true => self.limit.map(|limit| self.offset.saturating_add(limit)),
+            false => None
+        }
+    }
+
+    /// Sort, paginate and project a list of rows which matched the filter
+    pub fn shape(&self, mut rows: Vec<Value>) -> Vec<Value> {
+        if !self.sort.is_empty() {
+            rows.sort_by(|a, b| {
+                self.sort.iter()
+                    .map(|key| {
+                        let a = get_path(a, &key.path).unwrap_or(&Value::Null);
+                        let b = get_path(b, &key.path).unwrap_or(&Value::Null);
+                        let ordering = total_order(a, b);
+                        if key.descending { ordering.reverse() } else { ordering }
+                    })
+                    .find(|ordering| ordering.is_ne())
+                    .unwrap_or(Ordering::Equal)
+            });
+        }
+        rows.into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|row| self.projection.apply(row))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filter(value: Value) -> Filter {
+        Filter::from_value(&value).unwrap()
+    }
+
+    #[test]
+    fn values_are_ordered_across_types() {
+        let ordered = [
+            json!(null), json!(false), json!(true), json!(-1), json!(0.5), json!(2), json!(""), json!("a"), json!("b"),
+            json!([]), json!([1]), json!([1, 2]), json!([2]), json!({}), json!({"a": 1}), json!({"a": 2}), json!({"b": 0}),
+        ];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(total_order(a, b), i.cmp(&j), "{} against {}", a, b);
+            }
+        }
+        assert_eq!(total_order(&json!(1), &json!(1.0)), Ordering::Equal);
+    }
+
+    #[test]
+    fn conditions_on_dotted_paths() {
+        let row = json!({"_id": "0.a", "age": 30, "address": {"city": "paris", "zip": 75001}});
+        assert!(filter(json!({"address.city": "paris"})).matches(&row));
+        assert!(filter(json!({"address.city": {"$in": ["rome", "paris"]}})).matches(&row));
+        assert!(!filter(json!({"address.city": {"$in": ["rome", "oslo"]}})).matches(&row));
+        assert!(filter(json!({"address.zip": {"$gt": 75000, "$lt": 75002}})).matches(&row));
+        assert!(!filter(json!({"address.zip": {"$gt": "75000"}})).matches(&row));
+        assert!(filter(json!({"address.zip": 75001.0})).matches(&row));
+
+        // A missing field, or a path running through a value which isn't an object, is null
+        assert!(filter(json!({"address.country": null})).matches(&row));
+        assert!(filter(json!({"age.years": null})).matches(&row));
+        assert!(!filter(json!({"address.country": {"$ne": null}})).matches(&row));
+    }
+
+    #[test]
+    fn and_or_combine_filters() {
+        let row = json!({"_id": "0.a", "age": 30, "address": {"city": "paris"}});
+        assert!(filter(json!({"$and": [{"address.city": "paris"}, {"age": {"$gt": 18}}]})).matches(&row));
+        assert!(!filter(json!({"$and": [{"address.city": "paris"}, {"age": {"$lt": 18}}]})).matches(&row));
+        assert!(filter(json!({"$or": [{"address.city": "rome"}, {"age": {"$in": [29, 30]}}]})).matches(&row));
+        assert!(!filter(json!({"$or": [{"address.city": "rome"}, {"age": 31}]})).matches(&row));
+        assert!(!filter(json!({"$or": []})).matches(&row));
+        assert!(filter(json!({})).matches(&row));
+        assert!(filter(json!({"$or": [{"address.city": "rome"}, {"$and": [{"age": 30}, {"address.city": {"$ne": "oslo"}}]}]})).matches(&row));
+    }
+
+    #[test]
+    fn only_conditions_under_ands_are_required() {
+        let parsed = filter(json!({"$and": [{"age": 30}, {"$or": [{"name": "a"}, {"name": "b"}]}], "address.city": "paris"}));
+        let mut paths: Vec<String> = parsed.required_conditions().iter().map(|(path, _condition)| path.join(".")).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["address.city", "age"]);
+    }
+
+    #[test]
+    fn malformed_filters_are_rejected() {
+        for value in [json!([]), json!({"$not": []}), json!({"$or": {}}), json!({"age": {"$in": 1}}), json!({"age": {"$gte": 1}})] {
+            let error = Filter::from_value(&value).unwrap_err();
+            assert!(matches!(error, RowError::InvalidFilter(_)), "{} gave {:?}", value, error);
+        }
+    }
+
+    fn query(value: Value) -> Query {
+        match value {
+            Value::Object(map) => Query::from_request(&map).unwrap(),
+            _ => panic!("Request data must be an object")
+        }
+    }
+
+    fn people() -> Vec<Value> {
+        vec![
+            json!({"_id": "0.a", "name": "alice", "age": 30, "address": {"city": "paris", "zip": 75001}}),
+            json!({"_id": "0.b", "name": "bob", "age": 25}),
+            json!({"_id": "0.c", "name": "carol", "age": 30, "address": {"city": "oslo"}}),
+            json!({"_id": "0.d", "name": "dave", "age": "unknown"}),
+        ]
+    }
+
+    fn ids(rows: &[Value]) -> Vec<&str> {
+        rows.iter().map(|row| row["_id"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn rows_are_sorted_by_each_key_in_turn() {
+        let sorted = query(json!({"sort": [{"field": "age", "order": "desc"}, {"field": "name"}]})).shape(people());
+        assert_eq!(ids(&sorted), vec!["0.d", "0.a", "0.c", "0.b"]);
+
+        // Rows missing the field sort first, as null
+        let sorted = query(json!({"sort": [{"field": "address.city"}, {"field": "name", "order": "desc"}]})).shape(people());
+        assert_eq!(ids(&sorted), vec!["0.d", "0.b", "0.c", "0.a"]);
+    }
+
+    #[test]
+    fn offset_and_limit_page_through_sorted_rows() {
+        let page = |offset: u64, limit: u64| {
+            let query = query(json!({"sort": [{"field": "name"}], "offset": offset, "limit": limit}));
+            ids(&query.shape(people())).into_iter().map(str::to_string).collect::<Vec<String>>()
+        };
+        assert_eq!(page(0, 2), vec!["0.a", "0.b"]);
+        assert_eq!(page(2, 2), vec!["0.c", "0.d"]);
+        assert_eq!(page(3, 5), vec!["0.d"]);
+        assert!(page(4, 2).is_empty());
+
+        assert_eq!(query(json!({"offset": 1, "limit": 2})).rows_needed(), Some(3));
+        assert_eq!(query(json!({"offset": 1})).rows_needed(), None);
+        assert_eq!(query(json!({"limit": 2, "sort": [{"field": "name"}]})).rows_needed(), None);
+    }
+
+    #[test]
+    fn projection_picks_fields_and_keeps_the_id() {
+        let rows = query(json!({"fields": ["name", "address.city", "missing"]})).shape(people());
+        assert_eq!(rows[0], json!({"_id": "0.a", "name": "alice", "address": {"city": "paris"}}));
+        assert_eq!(rows[1], json!({"_id": "0.b", "name": "bob"}));
+
+        let rows = query(json!({"fields": ["age"], "exclude_id": true})).shape(people());
+        assert_eq!(rows[0], json!({"age": 30}));
+        let rows = query(json!({"exclude_id": true})).shape(people());
+        assert_eq!(rows[1], json!({"name": "bob", "age": 25}));
+    }
+
+    #[test]
+    fn malformed_queries_are_rejected() {
+        let requests = [
+            json!({"sort": {"field": "name"}}),
+            json!({"sort": [{"order": "asc"}]}),
+            json!({"sort": [{"field": "name", "order": "up"}]}),
+            json!({"offset": -1}),
+            json!({"limit": 1.5}),
+            json!({"fields": "name"}),
+            json!({"fields": [1]}),
+            json!({"exclude_id": "yes"}),
+        ];
+        for request in requests {
+            let Value::Object(map) = &request else { unreachable!() };
+            let error = Query::from_request(map).unwrap_err();
+            assert!(matches!(error, RowError::InvalidQuery(_)), "{} gave {:?}", request, error);
+        }
+    }
+}
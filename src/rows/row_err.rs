@@ -1,13 +1,31 @@
 use std::fmt::{Display, Formatter};
+use serde_json::{json, Value};
+use crate::tables::constraints::ConstraintViolation;
+use crate::tables::schema::SchemaViolation;
+use crate::tcp::response::ResponseError;
 
 #[derive(Debug)]
 pub enum RowError {
     TableDoesntExist,
     FailedInsert,
-    ReadMissingKey(String, String),
+    FailedUpdate,
+    FailedDelete,
+    FailedCommit,
+    MissingKey(String, String),
     MalformedID,
+    MalformedVersion,
+    CannotModifyID,
+    InvalidUpdateMode(String),
+    InvalidFilter(String),
+    InvalidQuery(String),
+    InvalidAggregate(String),
+    SchemaViolation(Vec<SchemaViolation>),
+    ConstraintViolation(ConstraintViolation),
     FailedRead, // This error should not exist and is just stubbing actual file operation errors
     FailedToFindRecord,
+    WriteConflict(String),
+    TableConflict(String),
+    VersionConflict(u64, u64),
 }
 
 impl Display for RowError {
@@ -15,13 +33,94 @@ impl Display for RowError {
         let err_msg: String = match self {
             RowError::TableDoesntExist => "Tried to operate on a table that does not exist".to_string(),
             RowError::FailedInsert => "Failed insert row".to_string(),
-            RowError::ReadMissingKey(key, key_type) => format!("Attempted to read record while missing '{}' {} field", key, key_type),
+            RowError::FailedUpdate => "Failed to update row".to_string(),
+            RowError::FailedDelete => "Failed to delete row".to_string(),
+            RowError::FailedCommit => "Failed to commit transaction".to_string(),
+            RowError::MissingKey(key, key_type) => format!("Request was missing its '{}' {} field", key, key_type),
             RowError::MalformedID => "Provided ID was not valid".to_string(),
+            RowError::MalformedVersion => "Provided version was not a non-negative integer".to_string(),
+            RowError::CannotModifyID => "A row's '_id' field cannot be changed".to_string(),
+            RowError::InvalidUpdateMode(mode) => format!("'{}' is not an update mode, expected 'merge' or 'replace'", mode),
+            RowError::InvalidFilter(reason) => format!("Invalid filter: {}", reason),
+            RowError::InvalidQuery(reason) => format!("Invalid query: {}", reason),
+            RowError::InvalidAggregate(reason) => format!("Invalid aggregate: {}", reason),
+            RowError::SchemaViolation(violations) => {
+                let fields: Vec<&str> = violations.iter().map(|violation| violation.field.as_str()).collect();
+                format!("Row does not match the table's schema, offending fields: {}", fields.join(", "))
+            },
+            RowError::ConstraintViolation(violation) => violation.to_string(),
             RowError::FailedRead => "Failed to read data from the db (This error should not exist)".to_string(),
             RowError::FailedToFindRecord => "Failed to find a row with the given criteria".to_string(),
+            RowError::WriteConflict(id) => format!("Row '{}' was changed by another transaction after this one began", id),
+            RowError::TableConflict(table) => format!("Table '{}' was dropped after this transaction began", table),
+            RowError::VersionConflict(expected, current) => format!("Expected the row to be at version {} but it is at version {}", expected, current),
         };
         write!(f, "{}", err_msg)
     }
 }
 
 impl std::error::Error for RowError {}
+
+impl ResponseError for RowError {
+    fn status(&self) -> u16 {
+        match self {
+            RowError::TableDoesntExist => 404,
+            RowError::FailedInsert => 500,
+            RowError::FailedUpdate => 500,
+            RowError::FailedDelete => 500,
+            RowError::FailedCommit => 500,
+            RowError::MissingKey(_, _) => 400,
+            RowError::MalformedID => 400,
+            RowError::MalformedVersion => 400,
+            RowError::CannotModifyID => 400,
+            RowError::InvalidUpdateMode(_) => 400,
+            RowError::InvalidFilter(_) => 400,
+            RowError::InvalidQuery(_) => 400,
+            RowError::InvalidAggregate(_) => 400,
+            RowError::SchemaViolation(_) => 422,
+            RowError::ConstraintViolation(violation) => violation.status(),
+            RowError::FailedRead => 500,
+            RowError::FailedToFindRecord => 404,
+            RowError::WriteConflict(_) => 409,
+            RowError::TableConflict(_) => 409,
+            RowError::VersionConflict(_, _) => 409,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            RowError::TableDoesntExist => "table_not_found",
+            RowError::FailedInsert => "insert_failed",
+            RowError::FailedUpdate => "update_failed",
+            RowError::FailedDelete => "delete_failed",
+            RowError::FailedCommit => "commit_failed",
+            RowError::MissingKey(_, _) => "missing_key",
+            RowError::MalformedID => "malformed_id",
+            RowError::MalformedVersion => "malformed_version",
+            RowError::CannotModifyID => "id_immutable",
+            RowError::InvalidUpdateMode(_) => "invalid_update_mode",
+            RowError::InvalidFilter(_) => "invalid_filter",
+            RowError::InvalidQuery(_) => "invalid_query",
+            RowError::InvalidAggregate(_) => "invalid_aggregate",
+            RowError::SchemaViolation(_) => "schema_violation",
+            RowError::ConstraintViolation(violation) => violation.code(),
+            RowError::FailedRead => "read_failed",
+            RowError::FailedToFindRecord => "row_not_found",
+            RowError::WriteConflict(_) | RowError::TableConflict(_) => "write_conflict",
+            RowError::VersionConflict(_, _) => "version_conflict",
+        }
+    }
+
+    fn details(&self) -> Option<Value> {
+        match self {
+            RowError::SchemaViolation(violations) => {
+                Some(serde_json::to_value(violations).expect("SchemaViolation should impl Serialize"))
+            },
+            RowError::ConstraintViolation(violation) => violation.details(),
+            RowError::VersionConflict(expected, current) => {
+                Some(json!({ "expected_version": expected, "current_version": current }))
+            },
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use crate::logging::log_error;
+
+/*
+    How hard the db tries to make sure that a write survives a power loss, set once on startup.
+
+    - `always` flushes every write to disk before it is acknowledged. This is the default.
+    - `<N>ms` flushes writes in the background at most N milliseconds after they are made, a power
+      loss can lose the last N milliseconds of writes.
+    - `never` leaves flushing up to the OS.
+
+    Writes are still atomic under every policy as far as a crash of the server itself goes, the
+    policy only matters if the OS goes down with it. It only covers appends to the WAL and to
+    record logs. Files replaced by `write_atomic` are always flushed before they are renamed into
+    place, as a rename reaching the disk ahead of the contents would leave an empty file behind.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    Always,
+    Interval(Duration),
+    Never,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "never" => Ok(Durability::Never),
+            _ => {
+                let millis = s.strip_suffix("ms")
+                    .and_then(|millis| millis.parse::<u64>().ok())
+                    .filter(|millis| *millis > 0)
+                    .ok_or(format!("'{}' is not a durability, expected 'always', 'never' or a number of milliseconds like '100ms'", s))?;
+                Ok(Durability::Interval(Duration::from_millis(millis)))
+            }
+        }
+    }
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Durability::Always => write!(f, "always"),
+            Durability::Interval(interval) => write!(f, "{}ms", interval.as_millis()),
+            Durability::Never => write!(f, "never"),
+        }
+    }
+}
+
+// Added to the name of a file while it is being replaced by `write_atomic`
+const TEMP_SUFFIX: &str = ".etch.tmp";
+
+static DURABILITY: OnceLock<Durability> = OnceLock::new();
+
+// Files and directories written to since the background flush last ran
+static DIRTY_PATHS: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// Set the durability policy, starting the background flush if it needs one. Can only be called
+/// once, before anything is written.
+pub fn init(durability: Durability) {
+    DURABILITY.set(durability).expect("Durability was already set");
+    if let Durability::Interval(interval) = durability {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            flush_dirty_paths();
+        });
+    }
+}
+
+fn current() -> Durability {
+    *DURABILITY.get_or_init(|| Durability::Always)
+}
+
+fn flush_dirty_paths() {
+    let dirty = DIRTY_PATHS.lock().expect("Dirty path lock was poisoned").take().unwrap_or_default();
+    for path in dirty {
+        // A file can be deleted or renamed over after it is written, there's nothing left to flush
+        let res = File::open(&path).and_then(|file| file.sync_all());
+        if let Err(e) = res && e.kind() != std::io::ErrorKind::NotFound {
+            log_error!("Failed to flush '{}' to disk with error: {}", path.display(), e);
+        }
+    }
+}
+
+/// Flush a file which was just written to, following the durability policy
+pub fn sync_file(file: &File, path: &Path) -> std::io::Result<()> {
+    current().sync_file(file, path)
+}
+
+/// Flush the directory holding `path`, so that a file created, renamed or deleted in it stays
+/// that way. Follows the durability policy.
+pub fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    current().sync_parent_dir(path)
+}
+
+/// Replace the contents of a file. The new contents are written to a temporary file which is
+/// flushed and renamed over the old one, so a crash leaves either the old or the new contents in
+/// place and never a partially written file. Flushes under every durability policy.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(TEMP_SUFFIX);
+    let temp_path = path.with_file_name(temp_name);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    match path.parent() {
+        Some(dir) => File::open(dir)?.sync_all(),
+        None => Ok(())
+    }
+}
+
+/// Delete every temporary file left under `dir` by a crash part way through `write_atomic`,
+/// returning how many there were. The file each one was replacing still has its old contents.
+pub fn remove_temp_files(dir: &Path) -> std::io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            removed += remove_temp_files(&path)?;
+        } else if path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.ends_with(TEMP_SUFFIX)) {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+impl Durability {
+    fn sync_file(self, file: &File, path: &Path) -> std::io::Result<()> {
+        match self {
+            Durability::Always => file.sync_all(),
+            Durability::Interval(_) => {
+                DIRTY_PATHS.lock().expect("Dirty path lock was poisoned").get_or_insert_default().insert(path.to_path_buf());
+                Ok(())
+            },
+            Durability::Never => Ok(())
+        }
+    }
+
+    fn sync_parent_dir(self, path: &Path) -> std::io::Result<()> {
+        let Some(dir) = path.parent() else {
+            return Ok(())
+        };
+        if self == Durability::Never {
+            return Ok(())
+        }
+        self.sync_file(&File::open(dir)?, dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("etch_durability_{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn is_dirty(path: &Path) -> bool {
+        DIRTY_PATHS.lock().expect("Dirty path lock was poisoned").as_ref().is_some_and(|dirty| dirty.contains(path))
+    }
+
+    #[test]
+    fn durability_is_parsed() {
+        assert_eq!("always".parse(), Ok(Durability::Always));
+        assert_eq!("never".parse(), Ok(Durability::Never));
+        assert_eq!("250ms".parse(), Ok(Durability::Interval(Duration::from_millis(250))));
+        for invalid in ["", "sometimes", "0ms", "-5ms", "250", "250s", "1.5ms"] {
+            assert!(invalid.parse::<Durability>().is_err(), "'{}' should not parse", invalid);
+        }
+        for durability in [Durability::Always, Durability::Never, Durability::Interval(Duration::from_millis(250))] {
+            assert_eq!(durability.to_string().parse(), Ok(durability));
+        }
+    }
+
+    #[test]
+    fn write_atomic_replaces_contents() {
+        let dir = new_dir();
+        let path = dir.join("file.etch");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, ["file.etch"]);
+        // The file was flushed before the rename, whatever the policy, so nothing is left to flush
+        assert!(!is_dirty(&path));
+        assert!(!is_dirty(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interval_policy_flushes_appends_later() {
+        let dir = new_dir();
+        let path = dir.join("file.etch");
+        let file = File::create(&path).unwrap();
+        let interval = Durability::Interval(Duration::from_secs(60));
+        interval.sync_file(&file, &path).unwrap();
+        interval.sync_parent_dir(&path).unwrap();
+        assert!(is_dirty(&path));
+        assert!(is_dirty(&dir));
+        Durability::Never.sync_file(&file, &path).unwrap();
+        Durability::Always.sync_file(&file, &path).unwrap();
+
+        flush_dirty_paths();
+        assert!(!is_dirty(&path));
+        assert!(!is_dirty(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn orphaned_temp_files_are_removed() {
+        let dir = new_dir();
+        fs::create_dir(dir.join("people")).unwrap();
+        fs::write(dir.join("users.etch"), "{}").unwrap();
+        fs::write(dir.join("users.etch.tmp"), "{").unwrap();
+        fs::write(dir.join("people").join("metadata.etch"), "{}").unwrap();
+        fs::write(dir.join("people").join("metadata.etch.tmp"), "").unwrap();
+
+        assert_eq!(remove_temp_files(&dir).unwrap(), 2);
+        assert!(dir.join("users.etch").exists());
+        assert!(!dir.join("users.etch.tmp").exists());
+        assert!(dir.join("people").join("metadata.etch").exists());
+        assert!(!dir.join("people").join("metadata.etch.tmp").exists());
+        assert_eq!(remove_temp_files(&dir).unwrap(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
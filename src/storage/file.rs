@@ -0,0 +1,1032 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use super::durability;
+use super::record_log;
+use super::record_log::{Record, RecordLocation};
+use super::wal::{Wal, WalEntry};
+use super::{RowWrite, StorageEngine};
+use crate::tables::table_err::TableError;
+use crate::tables::{Table, TableMetadata};
+use crate::tables::table_err::TableError::{FailedCreateDir, FailedDiskRead, FailedDiskWrite, FailedOpenTableFile, FailedRemoveDir, InvalidTableName, NotADataDir};
+use crate::logging::{log_error, log_info};
+
+/*
+    Stores everything in files under a root directory, laid out like
+
+        <root>/wal.etch                         write-ahead log, see `wal`
+        <root>/users.etch                       user accounts, see `auth`
+        <root>/tables/tables.etch               name of every table
+        <root>/tables/<table>.etch              definition of a table
+        <root>/<table>/metadata.etch            row counts and indexed fields of a table
+        <root>/<table>/sub_table_<n>.etch       rows, see `record_log`
+        <root>/<table>/sub_table_<n>_offsets.etch
+        <root>/<table>/index_<field>.etch       secondary index on a field, see `tables::index`
+*/
+
+const TABLE_FILE_NAME: &str = "tables.etch";
+const TABLES_DIR_NAME: &str = "tables";
+const WAL_FILE_NAME: &str = "wal.etch";
+const USERS_FILE_NAME: &str = "users.etch";
+
+/// A sub_table is compacted once it holds at least this many dead records, and more dead
+/// records than live ones
+const MIN_DEAD_RECORDS_TO_COMPACT: usize = 64;
+
+#[derive(Debug)]
+pub struct FileStorage {
+    root: PathBuf,
+    wal: Wal,
+    // Where each record sits in its sub_table file by table and sub_table, loaded the first time
+    // a sub_table is read from. Each index has its own lock, so loading one from disk doesn't
+    // hold up reads of the others.
+    offset_indexes: Mutex<HashMap<(String, usize), CachedOffsetIndex>>,
+}
+
+impl FileStorage {
+    /// Open the db stored under `root`, creating the directory if it doesn't exist yet. Fails if
+    /// `root` already holds files but no db, rather than treating them as a db's files.
+    pub fn open(root: PathBuf) -> Result<Self, TableError> {
+        if !Self::is_data_dir(&root) {
+            return Err(NotADataDir(root.display().to_string()))
+        }
+        fs::create_dir_all(&root).map_err(|_| FailedCreateDir)?;
+        let removed = durability::remove_temp_files(&root).map_err(|_| FailedDiskWrite)?;
+        if removed > 0 {
+            log_info!("Removed {} temporary files left by a write which was cut off", removed);
+        }
+        let wal = Wal::open(root.join(WAL_FILE_NAME))?;
+        let storage = Self { root, wal, offset_indexes: Mutex::new(HashMap::new()) };
+        storage.migrate_table_file()?;
+        Ok(storage)
+    }
+
+    /// Whether `root` can be used to store a db. It has to be empty, missing, or hold a db already,
+    /// as startup deletes files under it which look like they were left behind by a crash.
+    fn is_data_dir(root: &Path) -> bool {
+        let Ok(mut entries) = fs::read_dir(root) else {
+            return true
+        };
+        if entries.next().is_none() {
+            return true
+        }
+        let markers = [
+            root.join(TABLES_DIR_NAME).join(TABLE_FILE_NAME),
+            root.join(TABLE_FILE_NAME),
+            root.join(WAL_FILE_NAME),
+        ];
+        markers.iter().any(|marker| marker.is_file())
+    }
+
+    /// Whether a table name would collide with one of the files kept next to table directories
+    fn is_reserved_name(table_name: &str) -> bool {
+        [TABLES_DIR_NAME, TABLE_FILE_NAME, WAL_FILE_NAME, USERS_FILE_NAME].contains(&table_name)
+    }
+}
+
+
+// TABLES
+
+/*
+    Tables are stored under the `tables` directory. `tables/tables.etch` lists the name of every
+    table, one per line, and each table's definition is stored in its own `tables/<name>.etch`
+    file. A table only exists once it is in the list, so a definition is always written before
+    its name is added to the list and only deleted after its name is removed.
+*/
+
+impl FileStorage {
+    fn get_tables_dir(&self) -> PathBuf {
+        self.root.join(TABLES_DIR_NAME)
+    }
+
+    fn get_table_file_path(&self) -> PathBuf {
+        self.get_tables_dir().join(TABLE_FILE_NAME)
+    }
+
+    fn get_table_definition_path(&self, table_name: &str) -> PathBuf {
+        self.get_tables_dir().join(format!("{}.etch", table_name))
+    }
+
+    fn get_table_dir(&self, table_name: &str) -> PathBuf {
+        self.root.join(table_name)
+    }
+
+    /// Create a new table file, initialized to list no tables.
+    fn create_table_file(&self) -> Result<(), TableError> {
+        fs::create_dir_all(self.get_tables_dir()).map_err(|_| FailedCreateDir)?;
+        self.write_table_names(&[])
+    }
+
+    /// Replace the list of table names
+    fn write_table_names(&self, table_names: &[String]) -> Result<(), TableError> {
+        let serialized: String = table_names.iter().map(|name| format!("{}\n", name)).collect();
+        durability::write_atomic(&self.get_table_file_path(), serialized.as_bytes()).map_err(|_| FailedDiskWrite)
+    }
+
+    /// Read the name of every table, in the order they were created
+    fn read_table_names(&self) -> Result<Vec<String>, TableError> {
+        let table_file_path = self.get_table_file_path();
+        if !table_file_path.is_file() {
+            self.create_table_file()?;
+        }
+        let file = fs::read_to_string(table_file_path).map_err(|_| FailedOpenTableFile)?;
+        Ok(file.lines().filter(|name| !name.is_empty()).map(str::to_string).collect())
+    }
+
+    /// Write a table's definition to its own file, replacing whatever was there. Only the one
+    /// table's file is touched.
+    fn write_table_definition(&self, table: &Table) -> Result<(), TableError> {
+        let serialized = serde_json::to_string(table).map_err(|_| FailedDiskWrite)?;
+        durability::write_atomic(&self.get_table_definition_path(table.name.as_str()), serialized.as_bytes()).map_err(|_| FailedDiskWrite)
+    }
+
+    fn read_table_definition(&self, table_name: &str) -> Result<Table, TableError> {
+        let file = fs::read(self.get_table_definition_path(table_name)).map_err(|_| FailedDiskRead)?;
+        serde_json::from_slice(&file).map_err(|_| FailedDiskRead)
+    }
+
+    /// Write a new table to disk, adding its name to the end of the table file.
+    fn write_table_file_to_disk(&self, table: &Table) -> Result<(), TableError> {
+        self.write_table_definition(table)?;
+        let mut table_names = self.read_table_names()?;
+        if !table_names.contains(&table.name) {
+            table_names.push(table.name.clone());
+            self.write_table_names(&table_names)?;
+        }
+        Ok(())
+    }
+
+    /// Move tables from the old table file, which held the definition of every table in one JSON
+    /// list at `<root>/tables.etch`, into the tables directory. The old file is only deleted once
+    /// the new ones are in place, so a migration cut off part way through is redone on the next
+    /// startup.
+    fn migrate_table_file(&self) -> Result<(), TableError> {
+        let old_table_file_path = self.root.join(TABLE_FILE_NAME);
+        if !old_table_file_path.is_file() {
+            return Ok(())
+        }
+
+        if !self.get_table_file_path().is_file() {
+            log_info!("Migrating table file to the '{}' directory", TABLES_DIR_NAME);
+            let file = fs::read(&old_table_file_path).map_err(|_| FailedDiskRead)?;
+            let tables: Vec<Table> = serde_json::from_slice(&file).map_err(|_| FailedDiskRead)?;
+            if let Some(table) = tables.iter().find(|table| Self::is_reserved_name(table.name.as_str())) {
+                return Err(InvalidTableName(format!("'{}' is reserved, its directory has to be moved out of the data directory before migrating", table.name)))
+            }
+            fs::create_dir_all(self.get_tables_dir()).map_err(|_| FailedCreateDir)?;
+            for table in tables.iter() {
+                self.write_table_definition(table)?;
+            }
+            let table_names: Vec<String> = tables.into_iter().map(|table| table.name).collect();
+            self.write_table_names(&table_names)?;
+        }
+        fs::remove_file(&old_table_file_path).map_err(|_| FailedDiskWrite)?;
+        durability::sync_parent_dir(&old_table_file_path).map_err(|_| FailedDiskWrite)
+    }
+
+    fn create_table_metadata(&self, table_name: &str, metadata: &TableMetadata) -> Result<(), TableError> {
+        let new_table_path = self.get_table_dir(table_name);
+        fs::create_dir(new_table_path.as_path()).map_err(|_| FailedCreateDir)?;
+        durability::sync_parent_dir(new_table_path.as_path()).map_err(|_| FailedCreateDir)?;
+        self.replace_table_metadata(table_name, metadata)
+    }
+
+    pub(super) fn create_table_sub_table(&self, table_name: &str, num: usize) -> Result<(), TableError> {
+        let new_table_path = self.get_sub_table_path(table_name, num);
+        let data_length = record_log::create(new_table_path.as_path()).map_err(|_| FailedDiskWrite)?;
+        self.write_offset_index(table_name, num, &OffsetIndex { data_length, records: HashMap::new(), dead_records: 0 })
+    }
+
+    /// Create a table's directory, holding its metadata and first sub_table
+    pub(super) fn create_table_storage(&self, table_name: &str, metadata: &TableMetadata) -> Result<(), TableError> {
+        self.create_table_metadata(table_name, metadata)?;
+        self.create_table_sub_table(table_name, 0)
+    }
+
+    /// Add a table to the table file and create its directory. A crash part way through leaves a
+    /// partly created table, which is finished off by replaying the write-ahead log on startup.
+    pub(super) fn create_new_table_file_data(&self, table: &Table, metadata: &TableMetadata) -> Result<(), TableError> {
+        self.write_table_file_to_disk(table)?;
+        self.create_table_storage(table.name.as_str(), metadata)
+    }
+
+    pub(super) fn load_tables_from_disk(&self) -> Result<HashMap<String, Table>, TableError> {
+        let mut map: HashMap<String, Table> = HashMap::new();
+        for table_name in self.read_table_names()? {
+            let table = self.read_table_definition(table_name.as_str())?;
+            map.insert(table.name.clone(), table);
+        }
+        Ok(map)
+    }
+
+    /// Remove a table from the table file and delete its definition. If the server stops before the
+    /// definition is deleted, the leftover file is cleaned up on the next startup.
+    pub(super) fn remove_table_from_table_file(&self, table_name: &str) -> Result<(), TableError> {
+        let mut table_names = self.read_table_names()?;
+        table_names.retain(|name| name != table_name);
+        self.write_table_names(&table_names)?;
+        fs::remove_file(self.get_table_definition_path(table_name)).map_err(|_| FailedDiskWrite)
+    }
+
+    /// Delete a table's directory, along with its metadata and every sub_table in it.
+    pub(super) fn delete_table_dir(&self, table_name: &str) -> Result<(), TableError> {
+        fs::remove_dir_all(self.get_table_dir(table_name)).map_err(|_| FailedRemoveDir)
+    }
+
+    pub(super) fn table_dir_exists(&self, table_name: &str) -> bool {
+        self.get_table_dir(table_name).is_dir()
+    }
+
+    /// Whether a file in a table's directory could have been written by the db
+    fn is_table_file_name(file_name: &str) -> bool {
+        if file_name == "metadata.etch" {
+            return true
+        }
+        if let Some(num) = file_name.strip_prefix("sub_table_").and_then(|name| name.strip_suffix(".etch")) {
+            return num.strip_suffix("_offsets").unwrap_or(num).parse::<usize>().is_ok()
+        }
+        file_name.starts_with("index_") && file_name.ends_with(".etch")
+    }
+
+    /// Whether a directory is positively a table's, holding a table's metadata and first sub_table
+    /// and nothing the db wouldn't have written there
+    fn is_table_dir(dir: &Path) -> bool {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return false
+        };
+        for entry in entries {
+            let Ok(entry) = entry else {
+                return false
+            };
+            let is_file = entry.file_type().is_ok_and(|file_type| file_type.is_file());
+            if !is_file || !entry.file_name().to_str().is_some_and(Self::is_table_file_name) {
+                return false
+            }
+        }
+        dir.join("metadata.etch").is_file() && dir.join("sub_table_0.etch").is_file()
+    }
+
+    /// Delete any table directory or definition which does not belong to a table in the table file.
+    /// These are left behind if the server stops part way through dropping a table. Anything which
+    /// can't be told apart from a table's files is logged and left alone, in case it was put in
+    /// the data directory by something else.
+    fn remove_orphaned_table_dirs(&self, tables: &HashMap<String, Table>) -> Result<(), TableError> {
+        for entry in fs::read_dir(&self.root).map_err(|_| FailedDiskRead)? {
+            let entry = entry.map_err(|_| FailedDiskRead)?;
+            if !entry.file_type().map_err(|_| FailedDiskRead)?.is_dir() {
+                continue
+            }
+            let dir_name = entry.file_name();
+            let Some(table_name) = dir_name.to_str() else {
+                continue
+            };
+            if tables.contains_key(table_name) || Self::is_reserved_name(table_name) {
+                continue
+            }
+            if Self::is_table_dir(&entry.path()) {
+                log_info!("Removing files left behind by dropped table '{}'", table_name);
+                fs::remove_dir_all(entry.path()).map_err(|_| FailedRemoveDir)?;
+            } else {
+                log_info!("Leaving '{}' in the data directory alone, it isn't a table's directory", entry.path().display());
+            }
+        }
+
+        for entry in fs::read_dir(self.get_tables_dir()).map_err(|_| FailedDiskRead)? {
+            let entry = entry.map_err(|_| FailedDiskRead)?;
+            let file_name = entry.file_name();
+            let Some(table_name) = file_name.to_str().and_then(|name| name.strip_suffix(".etch")) else {
+                continue
+            };
+            if tables.contains_key(table_name) || Self::is_reserved_name(table_name) {
+                continue
+            }
+            if self.read_table_definition(table_name).is_ok_and(|table| table.name == table_name) {
+                log_info!("Removing definition left behind by dropped table '{}'", table_name);
+                fs::remove_file(entry.path()).map_err(|_| FailedDiskWrite)?;
+            } else {
+                log_info!("Leaving '{}' in the tables directory alone, it isn't a table's definition", entry.path().display());
+            }
+        }
+        Ok(())
+    }
+
+    fn get_metadata_path(&self, table_name: &str) -> PathBuf {
+        self.get_table_dir(table_name).join("metadata.etch")
+    }
+}
+
+
+// SUB_TABLES
+
+impl FileStorage {
+    fn get_sub_table_path(&self, table_name: &str, sub_table_index: usize) -> PathBuf {
+        self.get_table_dir(table_name).join(format!("sub_table_{}.etch", sub_table_index))
+    }
+
+    pub(super) fn sub_table_exists(&self, table_name: &str, sub_table_index: usize) -> bool {
+        self.get_sub_table_path(table_name, sub_table_index).is_file()
+    }
+
+    /// Append a row to a sub_table, returning where in the file it was written. A row which is
+    /// already in the sub_table is replaced by the newly appended one.
+    pub(super) fn insert_record_to_sub_table(&self, table_name: &str, sub_table_index: usize, record: &Value) -> Result<RecordLocation, TableError> {
+        let sub_table_path = self.get_sub_table_path(table_name, sub_table_index);
+        record_log::append(&sub_table_path, &Record::Put(record.clone())).map_err(|_| FailedDiskWrite)
+    }
+
+    /// Append a tombstone for a row to a sub_table, returning where in the file it was written
+    pub(super) fn delete_record_from_sub_table(&self, table_name: &str, sub_table_index: usize, id: &str) -> Result<RecordLocation, TableError> {
+        let sub_table_path = self.get_sub_table_path(table_name, sub_table_index);
+        record_log::append(&sub_table_path, &Record::Delete(id.to_string())).map_err(|_| FailedDiskWrite)
+    }
+
+    /// Overwrite the entire contents of a sub_table with the given list of rows, returning the
+    /// offset index of the new file.
+    fn write_sub_table(&self, table_name: &str, sub_table_index: usize, records: &[Value]) -> Result<OffsetIndex, TableError> {
+        let sub_table_path = self.get_sub_table_path(table_name, sub_table_index);
+        let locations = record_log::rewrite(&sub_table_path, records).map_err(|_| FailedDiskWrite)?;
+        let mut records_index = HashMap::with_capacity(records.len());
+        for (record, location) in records.iter().zip(locations.iter()) {
+            if let Some(Value::String(id)) = record.get("_id") {
+                records_index.insert(id.clone(), *location);
+            }
+        }
+        let data_length = locations.last().map_or(record_log::HEADER_LENGTH, RecordLocation::end);
+        Ok(OffsetIndex { data_length, records: records_index, dead_records: 0 })
+    }
+
+    /// Bring every sub_table of a table up to date on startup. Sub_tables still in the old JSON list
+    /// format are rewritten as record logs, and any record torn by a crash part way through an append
+    /// is truncated. A sub_table whose file is missing is recreated empty rather than stopping the
+    /// whole db from starting.
+    pub(super) fn recover_sub_tables(&self, table_name: &str) -> Result<(), TableError> {
+        let mut table_metadata = self.read_table_metadata(table_name)?;
+        let mut missing_sub_tables = false;
+        for sub_table_index in 0..table_metadata.sub_tables.len() {
+            let sub_table_path = self.get_sub_table_path(table_name, sub_table_index);
+            if !sub_table_path.exists() {
+                log_error!("Sub_table {} of table '{}' is missing, its {} rows are lost", sub_table_index, table_name, table_metadata.sub_tables[sub_table_index]);
+                self.create_table_sub_table(table_name, sub_table_index)?;
+                table_metadata.sub_tables[sub_table_index] = 0;
+                missing_sub_tables = true;
+                continue
+            }
+            if record_log::is_log(&sub_table_path).map_err(|_| FailedDiskRead)? {
+                if record_log::recover(&sub_table_path).map_err(|_| FailedDiskWrite)? {
+                    log_info!("Truncated a torn record from sub_table {} of table '{}'", sub_table_index, table_name);
+                }
+                continue
+            }
+
+            log_info!("Migrating sub_table {} of table '{}' to the record log format", sub_table_index, table_name);
+            let file = fs::read(&sub_table_path).map_err(|_| FailedDiskRead)?;
+            let rows: Vec<Value> = serde_json::from_slice(&file).map_err(|_| FailedDiskRead)?;
+            let offset_index = self.write_sub_table(table_name, sub_table_index, &rows)?;
+            self.write_offset_index(table_name, sub_table_index, &offset_index)?;
+        }
+        if missing_sub_tables {
+            self.replace_table_metadata(table_name, &table_metadata)?;
+        }
+        Ok(())
+    }
+}
+
+
+// OFFSET INDEXES
+
+/// The location of every live record in a sub_table by ID. The length of the sub_table file is
+/// stored alongside the locations, an index for a file of a different length is out of date.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct OffsetIndex {
+    pub data_length: u64,
+    pub records: HashMap<String, RecordLocation>,
+    // How many records in the sub_table have been superseded by a later update or delete
+    #[serde(default)]
+    pub dead_records: usize,
+}
+
+/// A sub_table's offset index as it is cached, `None` until it has been loaded
+type CachedOffsetIndex = Arc<Mutex<Option<OffsetIndex>>>;
+
+impl FileStorage {
+    fn get_offset_index_path(&self, table_name: &str, sub_table_index: usize) -> PathBuf {
+        self.get_table_dir(table_name).join(format!("sub_table_{}_offsets.etch", sub_table_index))
+    }
+
+    /// Offset indexes are written without being flushed, one which is lost or torn is rebuilt from its
+    /// sub_table the next time it is read
+    pub(super) fn write_offset_index(&self, table_name: &str, sub_table_index: usize, offset_index: &OffsetIndex) -> Result<(), TableError> {
+        let serialized = serde_json::to_string(offset_index).map_err(|_| FailedDiskWrite)?;
+        fs::write(self.get_offset_index_path(table_name, sub_table_index), serialized).map_err(|_| FailedDiskWrite)
+    }
+
+    /// Read a sub_table's offset index, returning `None` if it is missing, can't be read, or is out
+    /// of date with the sub_table file
+    fn read_offset_index(&self, table_name: &str, sub_table_index: usize) -> Option<OffsetIndex> {
+        let file = fs::read(self.get_offset_index_path(table_name, sub_table_index)).ok()?;
+        let offset_index: OffsetIndex = serde_json::from_slice(&file).ok()?;
+        let data_length = fs::metadata(self.get_sub_table_path(table_name, sub_table_index)).ok()?.len();
+        (offset_index.data_length == data_length).then_some(offset_index)
+    }
+
+    /// Build a sub_table's offset index by replaying the records of the sub_table file.
+    pub(super) fn build_offset_index(&self, table_name: &str, sub_table_index: usize) -> Result<OffsetIndex, TableError> {
+        let scan = record_log::scan(&self.get_sub_table_path(table_name, sub_table_index)).map_err(|_| FailedDiskRead)?;
+        let total_records = scan.records.len();
+        let mut records = HashMap::new();
+        for (location, record) in scan.records {
+            match record {
+                Record::Put(row) => {
+                    let Some(Value::String(id)) = row.get("_id") else {
+                        return Err(FailedDiskRead)
+                    };
+                    records.insert(id.clone(), location);
+                },
+                Record::Delete(id) => {
+                    records.remove(&id);
+                }
+            }
+        }
+        let dead_records = total_records - records.len();
+        Ok(OffsetIndex { data_length: scan.valid_length, records, dead_records })
+    }
+
+    /// Read the single row at a location in a sub_table file
+    pub(super) fn read_record_at(&self, table_name: &str, sub_table_index: usize, location: RecordLocation) -> Result<Value, TableError> {
+        match record_log::read_at(&self.get_sub_table_path(table_name, sub_table_index), location) {
+            Ok(Record::Put(row)) => Ok(row),
+            _ => Err(FailedDiskRead)
+        }
+    }
+
+    /// The cache slot for a sub_table's offset index, empty until the index is loaded. The map is
+    /// only locked long enough to find the slot.
+    fn offset_index_slot(&self, table_name: &str, sub_table_index: usize) -> CachedOffsetIndex {
+        let mut offset_indexes = self.offset_indexes.lock().expect("Offset index lock was poisoned");
+        offset_indexes.entry((table_name.to_string(), sub_table_index)).or_default().clone()
+    }
+
+    /// Run `f` on a sub_table's offset index, loading it first if it isn't cached. An index which
+    /// is missing or out of date on disk is rebuilt from the sub_table file. Only the one index is
+    /// locked while it is loaded.
+    fn with_offset_index<T>(&self, table_name: &str, sub_table_index: usize, f: impl FnOnce(&mut OffsetIndex) -> T) -> Result<T, TableError> {
+        let slot = self.offset_index_slot(table_name, sub_table_index);
+        let mut cached = slot.lock().expect("Offset index lock was poisoned");
+        let offset_index = match cached.take() {
+            Some(offset_index) => offset_index,
+            None => match self.read_offset_index(table_name, sub_table_index) {
+                Some(offset_index) => offset_index,
+                None => {
+                    let offset_index = self.build_offset_index(table_name, sub_table_index)?;
+                    self.write_offset_index(table_name, sub_table_index, &offset_index)?;
+                    offset_index
+                }
+            }
+        };
+        Ok(f(cached.insert(offset_index)))
+    }
+
+    /// Replace a sub_table's offset index after the sub_table has been rewritten
+    fn set_offset_index(&self, table_name: &str, sub_table_index: usize, offset_index: OffsetIndex) -> Result<(), TableError> {
+        let slot = self.offset_index_slot(table_name, sub_table_index);
+        let mut cached = slot.lock().expect("Offset index lock was poisoned");
+        // Whatever was cached is out of date with the rewritten sub_table, even if this write fails
+        *cached = None;
+        self.write_offset_index(table_name, sub_table_index, &offset_index)?;
+        *cached = Some(offset_index);
+        Ok(())
+    }
+
+    /// Record where a newly appended row was written. A row which was already in the sub_table
+    /// leaves its old record behind as dead space.
+    fn add_record_location(&self, table_name: &str, sub_table_index: usize, id: String, location: RecordLocation) -> Result<(), TableError> {
+        self.with_offset_index(table_name, sub_table_index, |offset_index| {
+            if offset_index.records.insert(id, location).is_some() {
+                offset_index.dead_records += 1;
+            }
+            offset_index.data_length = location.end();
+        })?;
+        self.finish_sub_table_write(table_name, sub_table_index)
+    }
+
+    /// Record that a row was deleted by the tombstone appended at `location`. Both the row's old
+    /// record and the tombstone are dead space from here on.
+    fn remove_record_location(&self, table_name: &str, sub_table_index: usize, id: &str, location: RecordLocation) -> Result<(), TableError> {
+        self.with_offset_index(table_name, sub_table_index, |offset_index| {
+            if offset_index.records.remove(id).is_some() {
+                offset_index.dead_records += 1;
+            }
+            offset_index.dead_records += 1;
+            offset_index.data_length = location.end();
+        })?;
+        self.finish_sub_table_write(table_name, sub_table_index)
+    }
+
+    /// Persist a sub_table's offset index after a write. If most of the sub_table has become
+    /// dead space it is compacted down to only its live rows first.
+    fn finish_sub_table_write(&self, table_name: &str, sub_table_index: usize) -> Result<(), TableError> {
+        let needs_compaction = self.with_offset_index(table_name, sub_table_index, |offset_index| {
+            offset_index.dead_records >= MIN_DEAD_RECORDS_TO_COMPACT && offset_index.dead_records > offset_index.records.len()
+        })?;
+        if needs_compaction {
+            let rows = self.read_sub_table(table_name, sub_table_index)?;
+            let offset_index = self.write_sub_table(table_name, sub_table_index, &rows)?;
+            return self.set_offset_index(table_name, sub_table_index, offset_index)
+        }
+        self.with_offset_index(table_name, sub_table_index, |offset_index| {
+            self.write_offset_index(table_name, sub_table_index, offset_index)
+        })?
+    }
+}
+
+
+// ROW WRITES
+
+impl FileStorage {
+    fn insert_row(&self, table_name: &str, sub_table_index: usize, row: &Value) -> Result<(), TableError> {
+        let Some(Value::String(id)) = row.get("_id") else {
+            return Err(FailedDiskWrite)
+        };
+        let mut table_metadata = self.read_table_metadata(table_name)?;
+        while table_metadata.sub_tables.len() <= sub_table_index {
+            self.create_table_sub_table(table_name, table_metadata.sub_tables.len())?;
+            table_metadata.sub_tables.push(0);
+        }
+        table_metadata.sub_tables[sub_table_index] += 1;
+        self.replace_table_metadata(table_name, &table_metadata)?;
+        let location = self.insert_record_to_sub_table(table_name, sub_table_index, row)?;
+        self.add_record_location(table_name, sub_table_index, id.clone(), location)
+    }
+
+    /// The updated row is appended to the sub_table, superseding the old one
+    fn update_row(&self, table_name: &str, sub_table_index: usize, row: &Value) -> Result<(), TableError> {
+        let Some(Value::String(id)) = row.get("_id") else {
+            return Err(FailedDiskWrite)
+        };
+        let location = self.insert_record_to_sub_table(table_name, sub_table_index, row)?;
+        self.add_record_location(table_name, sub_table_index, id.clone(), location)
+    }
+
+    fn delete_row(&self, table_name: &str, sub_table_index: usize, id: &str) -> Result<(), TableError> {
+        let location = self.delete_record_from_sub_table(table_name, sub_table_index, id)?;
+        self.remove_record_location(table_name, sub_table_index, id, location)?;
+        let mut table_metadata = self.read_table_metadata(table_name)?;
+        if let Some(count) = table_metadata.sub_tables.get_mut(sub_table_index) {
+            *count = count.saturating_sub(1);
+        }
+        self.replace_table_metadata(table_name, &table_metadata)
+    }
+
+    fn apply_row_writes(&self, writes: &[RowWrite]) -> Result<(), TableError> {
+        for write in writes {
+            match write {
+                RowWrite::Insert { table, sub_table_index, row } => self.insert_row(table, *sub_table_index, row)?,
+                RowWrite::Update { table, sub_table_index, row } => self.update_row(table, *sub_table_index, row)?,
+                RowWrite::Delete { table, sub_table_index, id } => self.delete_row(table, *sub_table_index, id)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+
+// SECONDARY INDEXES
+
+impl FileStorage {
+    fn get_index_path(&self, table_name: &str, field: &str) -> PathBuf {
+        self.get_table_dir(table_name).join(format!("index_{}.etch", field))
+    }
+}
+
+impl StorageEngine for FileStorage {
+    fn load_tables(&self) -> Result<Vec<Table>, TableError> {
+        // Finish off any changes which were cut off by the server stopping before loading tables
+        self.wal.replay(self)?;
+        let tables = self.load_tables_from_disk()?;
+        self.remove_orphaned_table_dirs(&tables)?;
+        for table_name in tables.keys() {
+            self.recover_sub_tables(table_name.as_str())?;
+        }
+        Ok(tables.into_values().collect())
+    }
+
+    fn create_table(&self, table: &Table, metadata: &TableMetadata) -> Result<(), TableError> {
+        if Self::is_reserved_name(table.name.as_str()) {
+            return Err(InvalidTableName(format!("'{}' is reserved", table.name)))
+        }
+        let definition = serde_json::to_value(table).map_err(|_| FailedDiskWrite)?;
+        let _pending = self.wal.log(&WalEntry::CreateTable { definition, metadata: metadata.clone() })?;
+        self.create_new_table_file_data(table, metadata)
+    }
+
+    fn drop_table(&self, table_name: &str) -> Result<(), TableError> {
+        // The table is removed from the table file first. If the server stops before its directory
+        // is deleted, the leftover directory is cleaned up on the next startup.
+        let _pending = self.wal.log(&WalEntry::DropTable { table: table_name.to_string() })?;
+        self.remove_table_from_table_file(table_name)?;
+        if let Err(e) = self.delete_table_dir(table_name) {
+            log_error!("Dropped table '{}' but failed to delete its files, they will be removed on next startup: {}", table_name, e);
+        }
+        self.offset_indexes.lock().expect("Offset index lock was poisoned").retain(|(table, _), _| table != table_name);
+        Ok(())
+    }
+
+    fn read_table_metadata(&self, table_name: &str) -> Result<TableMetadata, TableError> {
+        let file_contents = fs::read(self.get_metadata_path(table_name)).map_err(|_| FailedDiskRead)?;
+        serde_json::from_slice(&file_contents).map_err(|_| FailedDiskRead)
+    }
+
+    fn replace_table_metadata(&self, table_name: &str, metadata: &TableMetadata) -> Result<(), TableError> {
+        let serialized = serde_json::to_string(metadata).expect("serde_json Value should impl Serialize");
+        durability::write_atomic(&self.get_metadata_path(table_name), serialized.as_bytes()).map_err(|_| FailedDiskWrite)
+    }
+
+    fn write_rows(&self, writes: &[RowWrite]) -> Result<(), TableError> {
+        // The whole batch is logged as one entry before any file is touched. If the server stops
+        // part way through then the rest of the batch is finished off when the log is replayed.
+        let entry = WalEntry::Commit { entries: writes.iter().map(WalEntry::from).collect() };
+        let _pending = self.wal.log(&entry)?;
+        let Err(e) = self.apply_row_writes(writes) else {
+            return Ok(())
+        };
+
+        // Once logged the batch has to be made in full, so a write which fails part way through
+        // is finished off straight away by replaying its entry, rather than leaving the earlier
+        // writes in place until the next startup
+        log_error!("Failed part way through writing a batch of rows with error: {}, replaying it", e);
+        let replayed = Wal::reapply(self, &entry);
+        // The replay works from the sub_table files, so the cached offset indexes may be out of date
+        self.offset_indexes.lock().expect("Offset index lock was poisoned").clear();
+        if let Err(e) = &replayed {
+            log_error!("Failed to replay a batch of rows, it will be finished on next startup: {}", e);
+        }
+        replayed
+    }
+
+    /// Read a single row by ID, seeking straight to it using its sub_table's offset index
+    fn read_row(&self, table_name: &str, sub_table_index: usize, id: &str) -> Result<Option<Value>, TableError> {
+        let Some(location) = self.with_offset_index(table_name, sub_table_index, |offset_index| offset_index.records.get(id).copied())? else {
+            return Ok(None)
+        };
+        match self.read_record_at(table_name, sub_table_index, location) {
+            Ok(record) if record.get("_id").and_then(Value::as_str) == Some(id) => Ok(Some(record)),
+            // The index doesn't agree with the sub_table, so drop it and try again with one
+            // rebuilt from the sub_table file
+            _ => {
+                log_error!("Offset index for sub_table {} of table '{}' is out of date, rebuilding it", sub_table_index, table_name);
+                let offset_index = self.build_offset_index(table_name, sub_table_index)?;
+                let location = offset_index.records.get(id).copied();
+                self.set_offset_index(table_name, sub_table_index, offset_index)?;
+                match location {
+                    Some(location) => self.read_record_at(table_name, sub_table_index, location).map(Some),
+                    None => Ok(None)
+                }
+            }
+        }
+    }
+
+    fn read_sub_table(&self, table_name: &str, sub_table_index: usize) -> Result<Vec<Value>, TableError> {
+        let scan = record_log::scan(&self.get_sub_table_path(table_name, sub_table_index)).map_err(|_| FailedDiskRead)?;
+        let mut rows: Vec<Option<Value>> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for (_location, record) in scan.records {
+            match record {
+                Record::Put(row) => {
+                    let Some(Value::String(id)) = row.get("_id") else {
+                        return Err(FailedDiskRead)
+                    };
+                    match positions.get(id) {
+                        Some(position) => rows[*position] = Some(row),
+                        None => {
+                            positions.insert(id.clone(), rows.len());
+                            rows.push(Some(row));
+                        }
+                    }
+                },
+                Record::Delete(id) => {
+                    if let Some(position) = positions.remove(&id) {
+                        rows[position] = None;
+                    }
+                }
+            }
+        }
+        Ok(rows.into_iter().flatten().collect())
+    }
+
+    /// Like offset indexes, secondary indexes are written without being flushed as one which is lost
+    /// or torn is rebuilt from the table's rows on startup
+    fn write_index(&self, table_name: &str, field: &str, index: &Value) -> Result<(), TableError> {
+        // An index is kept as a record log, with the whole index as its first record and the
+        // changes appended to it since as the rest
+        record_log::rewrite(&self.get_index_path(table_name, field), std::slice::from_ref(index)).map_err(|_| FailedDiskWrite)?;
+        Ok(())
+    }
+
+    fn append_index_changes(&self, table_name: &str, field: &str, changes: &Value) -> Result<(), TableError> {
+        record_log::append(&self.get_index_path(table_name, field), &Record::Put(changes.clone())).map_err(|_| FailedDiskWrite)?;
+        Ok(())
+    }
+
+    fn read_index(&self, table_name: &str, field: &str) -> Result<(Value, Vec<Value>), TableError> {
+        let scan = record_log::scan(&self.get_index_path(table_name, field)).map_err(|_| FailedDiskRead)?;
+        // A torn change means the index is missing part of a write, so it has to be rebuilt
+        if scan.valid_length != scan.file_length {
+            return Err(FailedDiskRead)
+        }
+        let mut records = scan.records.into_iter().map(|(_location, record)| match record {
+            Record::Put(value) => Ok(value),
+            Record::Delete(_) => Err(FailedDiskRead)
+        });
+        let index = records.next().ok_or(FailedDiskRead)??;
+        Ok((index, records.collect::<Result<_, _>>()?))
+    }
+
+    fn delete_index(&self, table_name: &str, field: &str) -> Result<(), TableError> {
+        fs::remove_file(self.get_index_path(table_name, field)).map_err(|_| FailedDiskWrite)
+    }
+
+    fn read_users(&self) -> Result<Option<Value>, TableError> {
+        let file = match fs::read(self.root.join(USERS_FILE_NAME)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(_) => return Err(FailedDiskRead)
+        };
+        serde_json::from_slice(&file).map(Some).map_err(|_| FailedDiskRead)
+    }
+
+    fn write_users(&self, users: &Value) -> Result<(), TableError> {
+        let serialized = serde_json::to_string(users).map_err(|_| FailedDiskWrite)?;
+        durability::write_atomic(&self.root.join(USERS_FILE_NAME), serialized.as_bytes()).map_err(|_| FailedDiskWrite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp::response::ResponseError;
+    use serde_json::json;
+
+    const TABLE_NAME: &str = "people";
+
+    /// Open a db in a new temporary directory with an empty `people` table
+    fn new_storage(metadata: TableMetadata) -> (FileStorage, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("etch_file_{}", uuid::Uuid::new_v4()));
+        let storage = FileStorage::open(dir.clone()).unwrap();
+        storage.load_tables().unwrap();
+        let table: Table = serde_json::from_value(json!({"name": TABLE_NAME, "fields": [], "constraints": []})).unwrap();
+        storage.create_table(&table, &metadata).unwrap();
+        (storage, dir)
+    }
+
+    /// Open the db again as if the server had restarted, replaying the write-ahead log
+    fn reopen(storage: FileStorage, dir: &Path) -> FileStorage {
+        drop(storage);
+        let storage = FileStorage::open(dir.to_path_buf()).unwrap();
+        storage.load_tables().unwrap();
+        storage
+    }
+
+    fn insert(sub_table_index: usize, row: Value) -> RowWrite {
+        RowWrite::Insert { table: TABLE_NAME.to_string(), sub_table_index, row }
+    }
+
+    #[test]
+    fn half_applied_commit_is_finished_on_replay() {
+        let (storage, dir) = new_storage(TableMetadata::new(2));
+        let alice = json!({"_id": "0.alice", "name": "alice"});
+        storage.write_rows(&[insert(0, alice)]).unwrap();
+
+        // Cut off after only the first write of the commit was made
+        let bob = json!({"_id": "0.bob", "name": "bob"});
+        let carol = json!({"_id": "1.carol", "name": "carol"});
+        let writes = [
+            insert(0, bob.clone()),
+            insert(1, carol.clone()),
+            RowWrite::Delete { table: TABLE_NAME.to_string(), sub_table_index: 0, id: "0.alice".to_string() },
+        ];
+        let pending = storage.wal.log(&WalEntry::Commit { entries: writes.iter().map(WalEntry::from).collect() }).unwrap();
+        storage.insert_row(TABLE_NAME, 0, &bob).unwrap();
+        drop(pending);
+
+        let storage = reopen(storage, &dir);
+        assert_eq!(storage.read_sub_table(TABLE_NAME, 0).unwrap(), vec![bob]);
+        assert_eq!(storage.read_sub_table(TABLE_NAME, 1).unwrap(), vec![carol.clone()]);
+        assert_eq!(storage.read_row(TABLE_NAME, 1, "1.carol").unwrap(), Some(carol));
+        assert_eq!(storage.read_row(TABLE_NAME, 0, "0.alice").unwrap(), None);
+        assert_eq!(storage.read_table_metadata(TABLE_NAME).unwrap().sub_tables, vec![1, 1]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replaying_applied_entries_changes_nothing() {
+        let (storage, dir) = new_storage(TableMetadata::new(10));
+        let updated = json!({"_id": "0.alice", "name": "alice", "age": 30});
+        storage.write_rows(&[insert(0, json!({"_id": "0.alice", "name": "alice"})), insert(0, json!({"_id": "0.bob", "name": "bob"}))]).unwrap();
+        storage.write_rows(&[RowWrite::Update { table: TABLE_NAME.to_string(), sub_table_index: 0, row: updated.clone() }]).unwrap();
+        storage.write_rows(&[RowWrite::Delete { table: TABLE_NAME.to_string(), sub_table_index: 0, id: "0.bob".to_string() }]).unwrap();
+        let wal = fs::read(dir.join(WAL_FILE_NAME)).unwrap();
+
+        // Every entry was already applied, and is replayed twice over
+        let storage = reopen(storage, &dir);
+        fs::write(dir.join(WAL_FILE_NAME), wal).unwrap();
+        let storage = reopen(storage, &dir);
+
+        assert_eq!(storage.read_sub_table(TABLE_NAME, 0).unwrap(), vec![updated.clone()]);
+        assert_eq!(storage.read_row(TABLE_NAME, 0, "0.alice").unwrap(), Some(updated));
+        assert_eq!(storage.read_row(TABLE_NAME, 0, "0.bob").unwrap(), None);
+        assert_eq!(storage.read_table_metadata(TABLE_NAME).unwrap().sub_tables, vec![1]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replay_recounts_rows_and_drops_indexes() {
+        let metadata = TableMetadata { indexes: vec!["name".to_string()], ..TableMetadata::new(10) };
+        let (storage, dir) = new_storage(metadata);
+        storage.write_index(TABLE_NAME, "name", &json!({"alice": ["0.alice"]})).unwrap();
+        storage.write_rows(&[insert(0, json!({"_id": "0.alice", "name": "alice"}))]).unwrap();
+
+        // The counts are left wrong, as if the server stopped between writing a row and its metadata
+        let mut metadata = storage.read_table_metadata(TABLE_NAME).unwrap();
+        metadata.sub_tables = vec![5];
+        storage.replace_table_metadata(TABLE_NAME, &metadata).unwrap();
+
+        let storage = reopen(storage, &dir);
+        let metadata = storage.read_table_metadata(TABLE_NAME).unwrap();
+        assert_eq!(metadata.sub_tables, vec![1]);
+        assert_eq!(metadata.indexes, vec!["name".to_string()]);
+        assert!(storage.read_index(TABLE_NAME, "name").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn index_changes_are_read_back_until_the_index_is_torn() {
+        let (storage, dir) = new_storage(TableMetadata::new(10));
+        let changes = json!({"removed": [], "inserted": [["bob", "0.bob"]]});
+        storage.write_index(TABLE_NAME, "name", &json!([["alice", ["0.alice"]]])).unwrap();
+        storage.append_index_changes(TABLE_NAME, "name", &changes).unwrap();
+        assert_eq!(storage.read_index(TABLE_NAME, "name").unwrap(), (json!([["alice", ["0.alice"]]]), vec![changes]));
+
+        // Writing the whole index replaces the changes
+        storage.write_index(TABLE_NAME, "name", &json!([])).unwrap();
+        assert_eq!(storage.read_index(TABLE_NAME, "name").unwrap(), (json!([]), Vec::new()));
+
+        // A set of changes cut off part way leaves the index to be rebuilt
+        let index_path = storage.get_index_path(TABLE_NAME, "name");
+        storage.append_index_changes(TABLE_NAME, "name", &json!({"removed": [], "inserted": []})).unwrap();
+        let length = fs::metadata(&index_path).unwrap().len();
+        record_log::truncate(&index_path, length - 1).unwrap();
+        assert!(storage.read_index(TABLE_NAME, "name").is_err());
+
+        // As is an index in the format from before it was a record log
+        fs::write(&index_path, "[[\"alice\",[\"0.alice\"]]]").unwrap();
+        assert!(storage.read_index(TABLE_NAME, "name").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn json_list_sub_tables_are_migrated_to_record_logs() {
+        let (storage, dir) = new_storage(TableMetadata::new(10));
+        let rows = vec![json!({"_id": "0.alice", "name": "alice"}), json!({"_id": "0.bob", "name": "bob"})];
+
+        // Lay the sub_table out as it was before record logs, a single JSON list of rows
+        let sub_table_path = storage.get_sub_table_path(TABLE_NAME, 0);
+        fs::write(&sub_table_path, serde_json::to_vec(&rows).unwrap()).unwrap();
+        fs::remove_file(storage.get_offset_index_path(TABLE_NAME, 0)).unwrap();
+        storage.replace_table_metadata(TABLE_NAME, &TableMetadata { sub_tables: vec![2], ..TableMetadata::new(10) }).unwrap();
+
+        let storage = reopen(storage, &dir);
+        assert!(record_log::is_log(&sub_table_path).unwrap());
+        assert_eq!(storage.read_sub_table(TABLE_NAME, 0).unwrap(), rows);
+        assert_eq!(storage.read_row(TABLE_NAME, 0, "0.bob").unwrap(), Some(rows[1].clone()));
+        assert_eq!(storage.read_table_metadata(TABLE_NAME).unwrap().sub_tables, vec![2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_sub_table_is_recreated_empty() {
+        let (storage, dir) = new_storage(TableMetadata::new(1));
+        let alice = json!({"_id": "0.alice", "name": "alice"});
+        storage.write_rows(&[insert(0, alice.clone()), insert(1, json!({"_id": "1.bob", "name": "bob"}))]).unwrap();
+        // Empty the write-ahead log first, so the lost row isn't written back from it
+        let storage = reopen(storage, &dir);
+        fs::remove_file(storage.get_sub_table_path(TABLE_NAME, 1)).unwrap();
+
+        let storage = reopen(storage, &dir);
+        assert_eq!(storage.read_sub_table(TABLE_NAME, 0).unwrap(), vec![alice]);
+        assert_eq!(storage.read_sub_table(TABLE_NAME, 1).unwrap(), Vec::<Value>::new());
+        assert_eq!(storage.read_row(TABLE_NAME, 1, "1.bob").unwrap(), None);
+        assert_eq!(storage.read_table_metadata(TABLE_NAME).unwrap().sub_tables, vec![1, 0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn batch_cut_off_by_an_error_is_finished() {
+        let (storage, dir) = new_storage(TableMetadata::new(1));
+        storage.write_rows(&[insert(0, json!({"_id": "0.alice", "name": "alice"}))]).unwrap();
+
+        // Writing sub_table 0's offset index fails, after the update to alice has been appended
+        // but before bob has been inserted
+        let offset_index_path = storage.get_offset_index_path(TABLE_NAME, 0);
+        fs::remove_file(&offset_index_path).unwrap();
+        fs::create_dir(&offset_index_path).unwrap();
+        let updated = json!({"_id": "0.alice", "name": "alice", "age": 30});
+        let bob = json!({"_id": "1.bob", "name": "bob"});
+        let writes = [
+            RowWrite::Update { table: TABLE_NAME.to_string(), sub_table_index: 0, row: updated.clone() },
+            insert(1, bob.clone()),
+        ];
+        storage.write_rows(&writes).unwrap();
+        fs::remove_dir(&offset_index_path).unwrap();
+
+        assert_eq!(storage.read_row(TABLE_NAME, 0, "0.alice").unwrap(), Some(updated));
+        assert_eq!(storage.read_row(TABLE_NAME, 1, "1.bob").unwrap(), Some(bob));
+        assert_eq!(storage.read_table_metadata(TABLE_NAME).unwrap().sub_tables, vec![1, 1]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_table_dirs_are_removed_as_orphans() {
+        let (storage, dir) = new_storage(TableMetadata::new(10));
+        let table: Table = serde_json::from_value(json!({"name": "pets", "fields": [], "constraints": []})).unwrap();
+        storage.create_table(&table, &TableMetadata::new(10)).unwrap();
+        storage.write_index("pets", "name", &json!([])).unwrap();
+
+        // Drop `pets` as if its directory and definition couldn't be deleted at the time
+        let pets_dir = storage.get_table_dir("pets");
+        let pets_definition = fs::read(storage.get_table_definition_path("pets")).unwrap();
+        let backup = dir.join("pets_backup");
+        fs::rename(&pets_dir, &backup).unwrap();
+        storage.drop_table("pets").unwrap();
+        fs::rename(&backup, &pets_dir).unwrap();
+        fs::write(storage.get_table_definition_path("pets"), pets_definition).unwrap();
+
+        // Directories and files which the db didn't write
+        let photos = dir.join("photos");
+        fs::create_dir(&photos).unwrap();
+        fs::write(photos.join("cat.jpg"), "").unwrap();
+        let half_table = dir.join("notes");
+        fs::create_dir(&half_table).unwrap();
+        fs::write(half_table.join("metadata.etch"), "{}").unwrap();
+        fs::write(half_table.join("sub_table_0.etch"), "").unwrap();
+        fs::write(half_table.join("todo.txt"), "").unwrap();
+        fs::create_dir(dir.join("empty")).unwrap();
+        let readme = storage.get_tables_dir().join("readme.etch");
+        fs::write(&readme, "not a table").unwrap();
+
+        let storage = reopen(storage, &dir);
+        assert!(!pets_dir.exists());
+        assert!(!storage.get_table_definition_path("pets").exists());
+        assert!(storage.get_table_dir(TABLE_NAME).is_dir());
+        assert!(photos.join("cat.jpg").is_file());
+        assert!(half_table.join("todo.txt").is_file());
+        assert!(dir.join("empty").is_dir());
+        assert!(readme.is_file());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn directory_holding_other_files_is_not_opened() {
+        let dir = std::env::temp_dir().join(format!("etch_file_{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        fs::create_dir(dir.join("photos")).unwrap();
+        fs::write(dir.join("photos").join("metadata.etch"), "{}").unwrap();
+        fs::write(dir.join("photos").join("sub_table_0.etch"), "").unwrap();
+        fs::write(dir.join("notes.etch.tmp"), "").unwrap();
+
+        let error = FileStorage::open(dir.clone()).unwrap_err();
+        assert_eq!(error.code(), "not_a_data_dir");
+        assert!(dir.join("photos").join("metadata.etch").is_file());
+        assert!(dir.join("notes.etch.tmp").is_file());
+        assert!(!dir.join(WAL_FILE_NAME).exists());
+
+        // An empty directory is fine, and is a db from then on
+        let empty = dir.join("empty");
+        fs::create_dir(&empty).unwrap();
+        let storage = FileStorage::open(empty.clone()).unwrap();
+        storage.load_tables().unwrap();
+        drop(storage);
+        fs::write(empty.join("notes.txt"), "").unwrap();
+        FileStorage::open(empty).unwrap().load_tables().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn offset_indexes_are_locked_one_at_a_time() {
+        let (storage, dir) = new_storage(TableMetadata::new(1));
+        let alice = json!({"_id": "0.alice", "name": "alice"});
+        let bob = json!({"_id": "1.bob", "name": "bob"});
+        storage.write_rows(&[insert(0, alice.clone()), insert(1, bob.clone())]).unwrap();
+        let storage = reopen(storage, &dir);
+
+        // While sub_table 0's index is held, sub_table 1's can still be loaded and read from
+        let slot = storage.offset_index_slot(TABLE_NAME, 0);
+        let held = slot.lock().unwrap();
+        assert_eq!(storage.read_row(TABLE_NAME, 1, "1.bob").unwrap(), Some(bob));
+        drop(held);
+        assert_eq!(storage.read_row(TABLE_NAME, 0, "0.alice").unwrap(), Some(alice));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
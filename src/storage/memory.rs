@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use serde_json::Value;
+use super::{RowWrite, StorageEngine};
+use crate::tables::{Table, TableMetadata};
+use crate::tables::table_err::TableError;
+use crate::tables::table_err::TableError::{FailedDiskRead, FailedDiskWrite, TableAlreadyExists, TableDoesntExist};
+
+/// Keeps every table in memory, nothing is persisted once the engine is dropped. Mostly useful
+/// for tests.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    tables: Mutex<HashMap<String, MemoryTable>>,
+    users: Mutex<Option<Value>>,
+}
+
+#[derive(Debug)]
+struct MemoryTable {
+    definition: Value,
+    metadata: TableMetadata,
+    sub_tables: Vec<Vec<Value>>,
+    indexes: HashMap<String, (Value, Vec<Value>)>,
+}
+
+impl MemoryStorage {
+    /// Run `f` on a table, failing if it doesn't exist
+    fn with_table<T>(&self, table_name: &str, f: impl FnOnce(&mut MemoryTable) -> Result<T, TableError>) -> Result<T, TableError> {
+        let mut tables = self.tables.lock().expect("Memory storage lock was poisoned");
+        match tables.get_mut(table_name) {
+            Some(table) => f(table),
+            None => Err(TableDoesntExist)
+        }
+    }
+}
+
+impl MemoryTable {
+    fn sub_table(&mut self, sub_table_index: usize) -> Result<&mut Vec<Value>, TableError> {
+        self.sub_tables.get_mut(sub_table_index).ok_or(FailedDiskRead)
+    }
+}
+
+/// Get the position of a row in a sub_table by its `_id`
+fn position_of(sub_table: &[Value], id: &str) -> Option<usize> {
+    sub_table.iter().position(|row| row.get("_id").and_then(Value::as_str) == Some(id))
+}
+
+impl StorageEngine for MemoryStorage {
+    fn load_tables(&self) -> Result<Vec<Table>, TableError> {
+        let tables = self.tables.lock().expect("Memory storage lock was poisoned");
+        tables.values().map(|table| serde_json::from_value(table.definition.clone()).map_err(|_| FailedDiskRead)).collect()
+    }
+
+    fn create_table(&self, table: &Table, metadata: &TableMetadata) -> Result<(), TableError> {
+        let definition = serde_json::to_value(table).map_err(|_| FailedDiskWrite)?;
+        let mut tables = self.tables.lock().expect("Memory storage lock was poisoned");
+        if tables.contains_key(&table.name) {
+            return Err(TableAlreadyExists)
+        }
+        let memory_table = MemoryTable { definition, metadata: metadata.clone(), sub_tables: vec![Vec::new()], indexes: HashMap::new() };
+        tables.insert(table.name.clone(), memory_table);
+        Ok(())
+    }
+
+    fn drop_table(&self, table_name: &str) -> Result<(), TableError> {
+        let mut tables = self.tables.lock().expect("Memory storage lock was poisoned");
+        tables.remove(table_name).map(|_| ()).ok_or(TableDoesntExist)
+    }
+
+    fn read_table_metadata(&self, table_name: &str) -> Result<TableMetadata, TableError> {
+        self.with_table(table_name, |table| Ok(table.metadata.clone()))
+    }
+
+    fn replace_table_metadata(&self, table_name: &str, metadata: &TableMetadata) -> Result<(), TableError> {
+        self.with_table(table_name, |table| {
+            table.metadata = metadata.clone();
+            Ok(())
+        })
+    }
+
+    fn write_rows(&self, writes: &[RowWrite]) -> Result<(), TableError> {
+        let mut tables = self.tables.lock().expect("Memory storage lock was poisoned");
+        // Everything is checked before anything is written, so a batch is never left half made
+        for write in writes {
+            let (RowWrite::Insert { table, .. } | RowWrite::Update { table, .. } | RowWrite::Delete { table, .. }) = write;
+            if !tables.contains_key(table) {
+                return Err(TableDoesntExist)
+            }
+            if let RowWrite::Update { table, sub_table_index, row } = write {
+                let id = row.get("_id").and_then(Value::as_str).ok_or(FailedDiskWrite)?;
+                let sub_table = tables[table].sub_tables.get(*sub_table_index).ok_or(FailedDiskWrite)?;
+                position_of(sub_table, id).ok_or(FailedDiskWrite)?;
+            }
+        }
+
+        for write in writes {
+            match write {
+                RowWrite::Insert { table, sub_table_index, row } => {
+                    let table = tables.get_mut(table).ok_or(TableDoesntExist)?;
+                    while table.sub_tables.len() <= *sub_table_index {
+                        table.sub_tables.push(Vec::new());
+                        table.metadata.sub_tables.push(0);
+                    }
+                    table.sub_tables[*sub_table_index].push(row.clone());
+                    table.metadata.sub_tables[*sub_table_index] += 1;
+                },
+                RowWrite::Update { table, sub_table_index, row } => {
+                    let sub_table = tables.get_mut(table).ok_or(TableDoesntExist)?.sub_table(*sub_table_index)?;
+                    let id = row.get("_id").and_then(Value::as_str).ok_or(FailedDiskWrite)?;
+                    let position = position_of(sub_table, id).ok_or(FailedDiskWrite)?;
+                    sub_table[position] = row.clone();
+                },
+                RowWrite::Delete { table, sub_table_index, id } => {
+                    let table = tables.get_mut(table).ok_or(TableDoesntExist)?;
+                    if let Some(sub_table) = table.sub_tables.get_mut(*sub_table_index)
+                        && let Some(position) = position_of(sub_table, id)
+                    {
+                        sub_table.remove(position);
+                        table.metadata.sub_tables[*sub_table_index] = table.metadata.sub_tables[*sub_table_index].saturating_sub(1);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_row(&self, table_name: &str, sub_table_index: usize, id: &str) -> Result<Option<Value>, TableError> {
+        self.with_table(table_name, |table| {
+            let sub_table = table.sub_table(sub_table_index)?;
+            Ok(position_of(sub_table, id).map(|position| sub_table[position].clone()))
+        })
+    }
+
+    fn read_sub_table(&self, table_name: &str, sub_table_index: usize) -> Result<Vec<Value>, TableError> {
+        self.with_table(table_name, |table| Ok(table.sub_table(sub_table_index)?.clone()))
+    }
+
+    fn write_index(&self, table_name: &str, field: &str, index: &Value) -> Result<(), TableError> {
+        self.with_table(table_name, |table| {
+            table.indexes.insert(field.to_string(), (index.clone(), Vec::new()));
+            Ok(())
+        })
+    }
+
+    fn append_index_changes(&self, table_name: &str, field: &str, changes: &Value) -> Result<(), TableError> {
+        self.with_table(table_name, |table| {
+            let (_index, appended) = table.indexes.get_mut(field).ok_or(FailedDiskWrite)?;
+            appended.push(changes.clone());
+            Ok(())
+        })
+    }
+
+    fn read_index(&self, table_name: &str, field: &str) -> Result<(Value, Vec<Value>), TableError> {
+        self.with_table(table_name, |table| table.indexes.get(field).cloned().ok_or(FailedDiskRead))
+    }
+
+    fn delete_index(&self, table_name: &str, field: &str) -> Result<(), TableError> {
+        self.with_table(table_name, |table| {
+            table.indexes.remove(field);
+            Ok(())
+        })
+    }
+
+    fn read_users(&self) -> Result<Option<Value>, TableError> {
+        Ok(self.users.lock().expect("Memory storage lock was poisoned").clone())
+    }
+
+    fn write_users(&self, users: &Value) -> Result<(), TableError> {
+        *self.users.lock().expect("Memory storage lock was poisoned") = Some(users.clone());
+        Ok(())
+    }
+}
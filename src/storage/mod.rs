@@ -0,0 +1,89 @@
+pub mod durability;
+pub mod file;
+pub mod memory;
+mod record_log;
+mod wal;
+
+use std::fmt::Debug;
+use serde_json::Value;
+use crate::tables::{Table, TableMetadata};
+use crate::tables::table_err::TableError;
+
+/*
+    A storage engine persists tables and their rows. The rest of the db only talks to storage
+    through this trait, so that where and how data is kept can be swapped out, eg. for an
+    in-memory engine in tests.
+
+    Rows live in numbered sub_tables, and a row's sub_table is picked by the caller from the
+    table's metadata when it is inserted. The engine keeps the row count of each sub_table in the
+    metadata up to date as rows are inserted and deleted.
+
+    Rows are written in batches, see `write_rows`, which are made all at once or not at all.
+
+    Engines are shared between connections. Callers hold a table's lock around anything they do
+    to it, so an engine only has to be safe to use from several tables at once.
+*/
+
+/// A single write to a row, made as part of a batch passed to `StorageEngine::write_rows`
+#[derive(Debug)]
+pub enum RowWrite {
+    /// Store a new row. Any sub_tables up to and including the row's are created if they don't
+    /// exist yet.
+    Insert { table: String, sub_table_index: usize, row: Value },
+    /// Replace a row which is already in a sub_table with a new version of it
+    Update { table: String, sub_table_index: usize, row: Value },
+    Delete { table: String, sub_table_index: usize, id: String },
+}
+
+pub trait StorageEngine: Debug + Send + Sync {
+    // TABLES
+
+    /// Get the definition of every table. Called once on startup, before any other method, and
+    /// also where an engine recovers from anything left half done by the server stopping.
+    fn load_tables(&self) -> Result<Vec<Table>, TableError>;
+
+    /// Store a new table along with its metadata, with its first sub_table created and empty
+    fn create_table(&self, table: &Table, metadata: &TableMetadata) -> Result<(), TableError>;
+
+    /// Delete a table along with all of its rows and indexes
+    fn drop_table(&self, table_name: &str) -> Result<(), TableError>;
+
+    fn read_table_metadata(&self, table_name: &str) -> Result<TableMetadata, TableError>;
+
+    fn replace_table_metadata(&self, table_name: &str, metadata: &TableMetadata) -> Result<(), TableError>;
+
+    // ROWS
+
+    /// Make every write in a batch. If the server stops part way through, the engine must either
+    /// finish the whole batch or undo it when it is next loaded. A write which fails part way
+    /// through must be finished or undone in the same way before returning.
+    fn write_rows(&self, writes: &[RowWrite]) -> Result<(), TableError>;
+
+    /// Read a single row by ID, returning `None` if it isn't in the sub_table
+    fn read_row(&self, table_name: &str, sub_table_index: usize, id: &str) -> Result<Option<Value>, TableError>;
+
+    /// Read every row in a sub_table, in the order they were inserted
+    fn read_sub_table(&self, table_name: &str, sub_table_index: usize) -> Result<Vec<Value>, TableError>;
+
+    // SECONDARY INDEXES
+
+    /// Replace the whole of an index, along with any changes appended to it
+    fn write_index(&self, table_name: &str, field: &str, index: &Value) -> Result<(), TableError>;
+
+    /// Add a set of changes to an index last written with `write_index`, without rewriting it
+    fn append_index_changes(&self, table_name: &str, field: &str, changes: &Value) -> Result<(), TableError>;
+
+    /// Read an index as it was last written with `write_index`, along with every set of changes
+    /// appended to it since, in order
+    fn read_index(&self, table_name: &str, field: &str) -> Result<(Value, Vec<Value>), TableError>;
+
+    fn delete_index(&self, table_name: &str, field: &str) -> Result<(), TableError>;
+
+    // USERS
+
+    /// Read the user accounts last written with `write_users`, or `None` if they never have been
+    fn read_users(&self) -> Result<Option<Value>, TableError>;
+
+    /// Replace every user account at once
+    fn write_users(&self, users: &Value) -> Result<(), TableError>;
+}
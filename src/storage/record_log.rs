@@ -0,0 +1,318 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use super::durability;
+
+/*
+    Sub_tables are stored as append-only record logs. A log starts with a header:
+
+        | magic: b"ETCHLOG\0" | version: u16 |
+
+    and is followed by records, each of which is:
+
+        | length: u32 | crc32: u32 | kind: u8 | payload: `length` bytes |
+
+    All integers are big endian and the CRC covers the kind byte and the payload. A `Put` record's
+    payload is a row as JSON, and a `Delete` record's payload is the `_id` of a deleted row. The
+    live rows of a log are found by replaying it, the last record for an ID wins.
+
+    A crash part way through an append leaves a torn record at the end of the log, which fails
+    its length or checksum check. A bad record is only taken to be torn when no intact record
+    follows it, and is then truncated when the log is recovered. A bad record with intact ones
+    after it means the log was corrupted rather than cut off, and scanning it fails instead of
+    dropping the records past it.
+*/
+
+const MAGIC: &[u8; 8] = b"ETCHLOG\0";
+const VERSION: u16 = 1;
+pub const HEADER_LENGTH: u64 = 10;
+const RECORD_HEADER_LENGTH: usize = 9;
+
+const KIND_PUT: u8 = 1;
+const KIND_DELETE: u8 = 2;
+
+#[derive(Debug)]
+pub enum Record {
+    Put(Value),
+    Delete(String),
+}
+
+/// Where a single record sits within its log, covering its header and payload
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RecordLocation {
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl RecordLocation {
+    /// The offset just past the end of the record
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+/// Every intact record of a log, along with where the intact part of the log ends
+#[derive(Debug)]
+pub struct Scan {
+    pub records: Vec<(RecordLocation, Record)>,
+    pub valid_length: u64,
+    pub file_length: u64,
+}
+
+/// CRC-32 (IEEE), computed bit by bit as records are small enough for it not to matter
+fn crc32(chunks: &[&[u8]]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in chunks.iter().flat_map(|chunk| chunk.iter()) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn header() -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&VERSION.to_be_bytes());
+    header
+}
+
+fn encode(record: &Record) -> Result<Vec<u8>, serde_json::Error> {
+    let (kind, payload) = match record {
+        Record::Put(row) => (KIND_PUT, serde_json::to_vec(row)?),
+        Record::Delete(id) => (KIND_DELETE, id.as_bytes().to_vec()),
+    };
+    let mut bytes = Vec::with_capacity(RECORD_HEADER_LENGTH + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&crc32(&[&[kind], &payload]).to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+/// Decode the record at the start of `bytes`, returning it along with its length. Returns `None`
+/// if the record is cut off or fails its checksum.
+fn decode(bytes: &[u8]) -> Option<(Record, usize)> {
+    let record_header = bytes.get(..RECORD_HEADER_LENGTH)?;
+    let length = u32::from_be_bytes(record_header[0..4].try_into().ok()?) as usize;
+    let crc = u32::from_be_bytes(record_header[4..8].try_into().ok()?);
+    let kind = record_header[8];
+    if kind != KIND_PUT && kind != KIND_DELETE {
+        return None
+    }
+    let payload = bytes.get(RECORD_HEADER_LENGTH..RECORD_HEADER_LENGTH + length)?;
+    if crc32(&[&[kind], payload]) != crc {
+        return None
+    }
+    let record = match kind {
+        KIND_PUT => Record::Put(serde_json::from_slice(payload).ok()?),
+        KIND_DELETE => Record::Delete(String::from_utf8(payload.to_vec()).ok()?),
+        _ => return None
+    };
+    Some((record, RECORD_HEADER_LENGTH + length))
+}
+
+/// Whether the file at `path` starts with a record log header
+pub fn is_log(path: &Path) -> std::io::Result<bool> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 8];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e)
+    }
+}
+
+/// Create an empty log, returning its length
+pub fn create(path: &Path) -> std::io::Result<u64> {
+    let mut file = File::create(path)?;
+    file.write_all(&header())?;
+    durability::sync_file(&file, path)?;
+    durability::sync_parent_dir(path)?;
+    Ok(HEADER_LENGTH)
+}
+
+/// Append a record to the end of a log, returning where it was written. The record is flushed
+/// to disk following the durability policy.
+pub fn append(path: &Path, record: &Record) -> std::io::Result<RecordLocation> {
+    let bytes = encode(record)?;
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let offset = file.seek(SeekFrom::End(0))?;
+    file.write_all(&bytes)?;
+    durability::sync_file(&file, path)?;
+    Ok(RecordLocation { offset, length: bytes.len() as u64 })
+}
+
+/// Read the record at a location in a log, checking that it is intact
+pub fn read_at(path: &Path, location: RecordLocation) -> std::io::Result<Record> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(location.offset))?;
+    let mut bytes = vec![0u8; location.length as usize];
+    file.read_exact(&mut bytes)?;
+    match decode(&bytes) {
+        Some((record, length)) if length == bytes.len() => Ok(record),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Record failed its checksum"))
+    }
+}
+
+/// The offset of the first intact record starting after `position`, if there is one
+fn next_intact_record(bytes: &[u8], position: usize) -> Option<usize> {
+    (position + 1..bytes.len()).find(|offset| decode(&bytes[*offset..]).is_some())
+}
+
+/// Read every intact record of a log, stopping at the first record which is not. Fails if an
+/// intact record follows a bad one, as the log was then corrupted rather than torn.
+pub fn scan(path: &Path) -> std::io::Result<Scan> {
+    let bytes = fs::read(path)?;
+    if bytes.len() < HEADER_LENGTH as usize || bytes[..HEADER_LENGTH as usize] != header()[..] {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "File is not a record log"))
+    }
+
+    let mut records = Vec::new();
+    let mut position = HEADER_LENGTH as usize;
+    while let Some((record, length)) = decode(&bytes[position..]) {
+        records.push((RecordLocation { offset: position as u64, length: length as u64 }, record));
+        position += length;
+    }
+    if let Some(offset) = next_intact_record(&bytes, position) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Record at offset {} is corrupt but is followed by an intact record at offset {}", position, offset)
+        ))
+    }
+    Ok(Scan { records, valid_length: position as u64, file_length: bytes.len() as u64 })
+}
+
+/// Truncate any torn records from the end of a log, returning whether anything was removed. A
+/// corrupt log is left as it is and fails to recover.
+pub fn recover(path: &Path) -> std::io::Result<bool> {
+    let scan = scan(path)?;
+    if scan.valid_length == scan.file_length {
+        return Ok(false)
+    }
+    truncate(path, scan.valid_length)?;
+    Ok(true)
+}
+
+/// Cut a log off at `length`, dropping every record past it
+pub fn truncate(path: &Path, length: u64) -> std::io::Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(length)?;
+    durability::sync_file(&file, path)
+}
+
+/// Replace a log with one holding only the given rows. A crash leaves either the old or the new
+/// log in place.
+pub fn rewrite(path: &Path, rows: &[Value]) -> std::io::Result<Vec<RecordLocation>> {
+    let mut bytes = header();
+    let mut locations = Vec::with_capacity(rows.len());
+    for row in rows {
+        let encoded = encode(&Record::Put(row.clone()))?;
+        locations.push(RecordLocation { offset: bytes.len() as u64, length: encoded.len() as u64 });
+        bytes.extend_from_slice(&encoded);
+    }
+
+    durability::write_atomic(path, &bytes)?;
+    Ok(locations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use serde_json::json;
+
+    fn new_log() -> PathBuf {
+        let path = std::env::temp_dir().join(format!("etch_log_{}.etch", uuid::Uuid::new_v4()));
+        create(&path).unwrap();
+        path
+    }
+
+    fn ids(scan: &Scan) -> Vec<String> {
+        scan.records.iter().map(|(_location, record)| match record {
+            Record::Put(row) => row["_id"].as_str().unwrap().to_string(),
+            Record::Delete(id) => format!("-{}", id),
+        }).collect()
+    }
+
+    #[test]
+    fn torn_trailing_record_is_truncated() {
+        let path = new_log();
+        let first = append(&path, &Record::Put(json!({"_id": "0.a"}))).unwrap();
+        let second = append(&path, &Record::Delete("0.a".to_string())).unwrap();
+
+        // Cut off part way through the second record's payload
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(second.end() - 2).unwrap();
+        drop(file);
+
+        let scan = scan(&path).unwrap();
+        assert_eq!(ids(&scan), vec!["0.a"]);
+        assert_eq!(scan.valid_length, first.end());
+        assert!(recover(&path).unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().len(), first.end());
+        assert!(!recover(&path).unwrap());
+
+        // Appends carry on from the end of the last intact record
+        let third = append(&path, &Record::Put(json!({"_id": "0.b"}))).unwrap();
+        assert_eq!(third.offset, first.end());
+        assert_eq!(ids(&super::scan(&path).unwrap()), vec!["0.a", "0.b"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn trailing_record_failing_its_checksum_is_truncated() {
+        let path = new_log();
+        let first = append(&path, &Record::Put(json!({"_id": "0.a"}))).unwrap();
+        let second = append(&path, &Record::Put(json!({"_id": "0.b"}))).unwrap();
+
+        // Flip a bit in the last record's payload
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[second.end() as usize - 2] ^= 1;
+        fs::write(&path, bytes).unwrap();
+
+        assert!(read_at(&path, first).is_ok());
+        assert!(read_at(&path, second).is_err());
+        let scan = scan(&path).unwrap();
+        assert_eq!(ids(&scan), vec!["0.a"]);
+        assert_eq!(scan.valid_length, first.end());
+        assert!(recover(&path).unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().len(), first.end());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_record_followed_by_intact_ones_is_an_error() {
+        let path = new_log();
+        let first = append(&path, &Record::Put(json!({"_id": "0.a"}))).unwrap();
+        let second = append(&path, &Record::Put(json!({"_id": "0.b"}))).unwrap();
+        let third = append(&path, &Record::Put(json!({"_id": "0.c"}))).unwrap();
+
+        // Flip a bit in the middle record's payload
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[second.end() as usize - 2] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+
+        // The records either side of it can still be read, but the log as a whole is corrupt
+        assert!(read_at(&path, first).is_ok());
+        assert!(read_at(&path, second).is_err());
+        assert!(read_at(&path, third).is_ok());
+        assert_eq!(scan(&path).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(recover(&path).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn json_list_is_not_a_log() {
+        let path = std::env::temp_dir().join(format!("etch_log_{}.etch", uuid::Uuid::new_v4()));
+        fs::write(&path, "[]").unwrap();
+        assert!(!is_log(&path).unwrap());
+        assert!(scan(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
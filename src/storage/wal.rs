@@ -0,0 +1,313 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use super::{RowWrite, StorageEngine};
+use super::file::{FileStorage, OffsetIndex};
+use super::record_log;
+use super::record_log::Record;
+use crate::tables::{Table, TableMetadata};
+use crate::tables::table_err::TableError;
+use crate::tables::table_err::TableError::{FailedDiskRead, FailedDiskWrite};
+use crate::logging::{log_error, log_info};
+
+/*
+    Every change to a table is written to the write-ahead log before any of the table's files are
+    touched. A change which was cut off part way through by a crash is still in the log on the
+    next startup, and replaying the log finishes it off.
+
+    The log is a record log (see `record_log`) where every record holds one JSON entry. Entries
+    describe the state a change leaves a table in rather than how to get there, eg. an update is
+    logged as the whole updated row. This makes replaying an entry which was already applied a
+    no-op, so the log doesn't need to track which entries finished.
+
+    Once nothing is being written and the log has grown past `CHECKPOINT_LENGTH`, every entry in
+    it has been applied and the log is emptied.
+*/
+
+const CHECKPOINT_LENGTH: u64 = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum WalEntry {
+    CreateTable {
+        definition: Value,
+        // Logs written before tables could be created with their own metadata hold only the definition
+        #[serde(default)]
+        metadata: TableMetadata,
+    },
+    DropTable { table: String },
+    // Both inserts and updates are logged as the row they write
+    Put { table: String, row: Value },
+    Delete { table: String, id: String },
+    // A batch of puts and deletes, which is replayed as a whole
+    Commit { entries: Vec<WalEntry> },
+}
+
+impl From<&RowWrite> for WalEntry {
+    fn from(write: &RowWrite) -> Self {
+        match write {
+            RowWrite::Insert { table, row, .. } | RowWrite::Update { table, row, .. } => WalEntry::Put { table: table.clone(), row: row.clone() },
+            RowWrite::Delete { table, id, .. } => WalEntry::Delete { table: table.clone(), id: id.clone() },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    state: Mutex<WalState>,
+}
+
+#[derive(Debug)]
+struct WalState {
+    length: u64,
+    // How many logged changes are still being applied
+    pending: usize,
+}
+
+/// A logged change which is still being applied. The log can't be emptied until it is dropped.
+#[must_use]
+pub struct PendingEntry<'a> {
+    wal: &'a Wal,
+}
+
+impl Drop for PendingEntry<'_> {
+    fn drop(&mut self) {
+        self.wal.finish()
+    }
+}
+
+impl Wal {
+    /// Open the write-ahead log at `path`, creating it if it doesn't exist yet
+    pub fn open(path: PathBuf) -> Result<Self, TableError> {
+        let length = match path.is_file() && record_log::is_log(&path).map_err(|_| FailedDiskRead)? {
+            true => {
+                record_log::recover(&path).map_err(|_| FailedDiskWrite)?;
+                fs::metadata(&path).map_err(|_| FailedDiskRead)?.len()
+            },
+            false => record_log::create(&path).map_err(|_| FailedDiskWrite)?
+        };
+        Ok(Self { path, state: Mutex::new(WalState { length, pending: 0 }) })
+    }
+
+    /// Write a change to the log. The change must be applied before the returned entry is dropped.
+    pub fn log(&self, entry: &WalEntry) -> Result<PendingEntry<'_>, TableError> {
+        let entry = serde_json::to_value(entry).map_err(|_| FailedDiskWrite)?;
+        let mut state = self.state.lock().expect("Write-ahead log lock was poisoned");
+        let location = record_log::append(&self.path, &Record::Put(entry)).map_err(|_| FailedDiskWrite)?;
+        state.length = location.end();
+        state.pending += 1;
+        Ok(PendingEntry { wal: self })
+    }
+
+    fn finish(&self) {
+        let mut state = self.state.lock().expect("Write-ahead log lock was poisoned");
+        state.pending -= 1;
+        if state.pending == 0 && state.length >= CHECKPOINT_LENGTH {
+            match record_log::truncate(&self.path, record_log::HEADER_LENGTH) {
+                Ok(()) => state.length = record_log::HEADER_LENGTH,
+                Err(e) => log_error!("Failed to empty the write-ahead log: {}", e)
+            }
+        }
+    }
+
+    /// Apply every entry in the log, in order, then empty it. Must be called before any tables
+    /// are loaded.
+    pub fn replay(&self, storage: &FileStorage) -> Result<(), TableError> {
+        let scan = record_log::scan(&self.path).map_err(|_| FailedDiskRead)?;
+        if scan.records.is_empty() {
+            return Ok(())
+        }
+
+        log_info!("Replaying {} entries from the write-ahead log", scan.records.len());
+        let mut replay = Replay::default();
+        for (_location, record) in scan.records {
+            let Record::Put(entry) = record else {
+                return Err(FailedDiskRead)
+            };
+            let entry: WalEntry = serde_json::from_value(entry).map_err(|_| FailedDiskRead)?;
+            entry.replay(storage, &mut replay)?;
+        }
+        replay.finish(storage)?;
+
+        record_log::truncate(&self.path, record_log::HEADER_LENGTH).map_err(|_| FailedDiskWrite)?;
+        self.state.lock().expect("Write-ahead log lock was poisoned").length = record_log::HEADER_LENGTH;
+        Ok(())
+    }
+
+    /// Finish off a logged entry whose changes were cut off part way through by an error while
+    /// the server is running, the same way they would be if the server had stopped instead
+    pub fn reapply(storage: &FileStorage, entry: &WalEntry) -> Result<(), TableError> {
+        let mut replay = Replay::default();
+        entry.replay(storage, &mut replay)?;
+        replay.finish(storage)
+    }
+}
+
+/// What a replay keeps track of from one entry to the next
+#[derive(Default)]
+struct Replay {
+    // Tables which had rows written, which are repaired once every entry has been replayed
+    touched_tables: BTreeSet<String>,
+    // The offset index of every sub_table written to, built from the sub_table the first time it
+    // is written and kept up to date from then on, rather than being rebuilt for every entry
+    offset_indexes: HashMap<(String, usize), OffsetIndex>,
+}
+
+impl Replay {
+    fn offset_index(&mut self, storage: &FileStorage, table_name: &str, sub_table_index: usize) -> Result<&mut OffsetIndex, TableError> {
+        let key = (table_name.to_string(), sub_table_index);
+        if !self.offset_indexes.contains_key(&key) {
+            let offset_index = storage.build_offset_index(table_name, sub_table_index)?;
+            self.offset_indexes.insert(key.clone(), offset_index);
+        }
+        Ok(self.offset_indexes.get_mut(&key).expect("Offset index was just inserted"))
+    }
+
+    /// Write out the offset indexes the replay rebuilt and repair every table it wrote rows to
+    fn finish(self, storage: &FileStorage) -> Result<(), TableError> {
+        for ((table_name, sub_table_index), offset_index) in self.offset_indexes {
+            // Offset indexes are rebuilt from their sub_table whenever they are found out of date,
+            // so one which can't be written doesn't stop the replay
+            if let Err(e) = storage.write_offset_index(table_name.as_str(), sub_table_index, &offset_index) {
+                log_error!("Failed to write offset index for sub_table {} of table '{}': {}", sub_table_index, table_name, e);
+            }
+        }
+        for table_name in self.touched_tables {
+            repair_table(storage, table_name.as_str())?;
+        }
+        Ok(())
+    }
+
+    /// Forget everything about a table which was created or dropped
+    fn forget_table(&mut self, table_name: &str) {
+        self.touched_tables.remove(table_name);
+        self.offset_indexes.retain(|(table, _), _| table != table_name);
+    }
+}
+
+/// Get the index of the sub_table a row is stored in from its `_id`
+fn sub_table_of(id: &str) -> Result<usize, TableError> {
+    id.split(".").next().and_then(|index| index.parse().ok()).ok_or(FailedDiskRead)
+}
+
+impl WalEntry {
+    /// Apply the entry to the table files if it wasn't already
+    fn replay(&self, storage: &FileStorage, replay: &mut Replay) -> Result<(), TableError> {
+        match self {
+            WalEntry::CreateTable { definition, metadata } => {
+                let table: Table = serde_json::from_value(definition.clone()).map_err(|_| FailedDiskRead)?;
+                let table_name = table.name.as_str();
+                replay.forget_table(table_name);
+                let in_table_file = storage.load_tables_from_disk()?.contains_key(table_name);
+                let has_storage = storage.read_table_metadata(table_name).is_ok() && storage.sub_table_exists(table_name, 0);
+                if in_table_file && has_storage {
+                    return Ok(())
+                }
+                // Anything left behind by the cut off create is thrown away and created again
+                if storage.table_dir_exists(table_name) {
+                    storage.delete_table_dir(table_name)?;
+                }
+                match in_table_file {
+                    true => storage.create_table_storage(table_name, metadata),
+                    false => storage.create_new_table_file_data(&table, metadata)
+                }
+            },
+            WalEntry::DropTable { table } => {
+                replay.forget_table(table);
+                if storage.load_tables_from_disk()?.contains_key(table) {
+                    storage.remove_table_from_table_file(table)?;
+                }
+                match storage.table_dir_exists(table) {
+                    true => storage.delete_table_dir(table),
+                    false => Ok(())
+                }
+            },
+            WalEntry::Put { table, row } => {
+                let Some(Value::String(id)) = row.get("_id") else {
+                    return Err(FailedDiskRead)
+                };
+                let sub_table_index = sub_table_of(id)?;
+                if !start_table_replay(storage, table, replay)? {
+                    return Ok(())
+                }
+
+                // The insert may have been cut off before its new sub_tables were created
+                let mut table_metadata = storage.read_table_metadata(table)?;
+                if sub_table_index >= table_metadata.sub_tables.len() {
+                    table_metadata.sub_tables.resize(sub_table_index + 1, 0);
+                    storage.replace_table_metadata(table, &table_metadata)?;
+                }
+                for index in 0..=sub_table_index {
+                    if !storage.sub_table_exists(table, index) {
+                        storage.create_table_sub_table(table, index)?;
+                    }
+                }
+
+                let offset_index = replay.offset_index(storage, table, sub_table_index)?;
+                let applied = match offset_index.records.get(id) {
+                    Some(location) => storage.read_record_at(table, sub_table_index, *location)? == *row,
+                    None => false
+                };
+                if !applied {
+                    let location = storage.insert_record_to_sub_table(table, sub_table_index, row)?;
+                    if offset_index.records.insert(id.clone(), location).is_some() {
+                        offset_index.dead_records += 1;
+                    }
+                    offset_index.data_length = location.end();
+                }
+                Ok(())
+            },
+            WalEntry::Delete { table, id } => {
+                let sub_table_index = sub_table_of(id)?;
+                if !start_table_replay(storage, table, replay)? || !storage.sub_table_exists(table, sub_table_index) {
+                    return Ok(())
+                }
+                let offset_index = replay.offset_index(storage, table, sub_table_index)?;
+                if offset_index.records.remove(id).is_some() {
+                    let location = storage.delete_record_from_sub_table(table, sub_table_index, id)?;
+                    offset_index.dead_records += 2;
+                    offset_index.data_length = location.end();
+                }
+                Ok(())
+            },
+            WalEntry::Commit { entries } => {
+                for entry in entries {
+                    entry.replay(storage, replay)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Get a table ready to have rows replayed into it, returning false if the table doesn't exist.
+/// The first time a table is seen its sub_tables are recovered from any torn writes.
+fn start_table_replay(storage: &FileStorage, table_name: &str, replay: &mut Replay) -> Result<bool, TableError> {
+    if !storage.table_dir_exists(table_name) {
+        return Ok(false)
+    }
+    if replay.touched_tables.insert(table_name.to_string()) {
+        storage.recover_sub_tables(table_name)?;
+    }
+    Ok(true)
+}
+
+/// Bring the files derived from a table's rows back in line with them after a replay. The row
+/// count of each sub_table is recounted and secondary indexes are deleted so that they are
+/// rebuilt when the table is loaded.
+fn repair_table(storage: &FileStorage, table_name: &str) -> Result<(), TableError> {
+    let mut table_metadata = storage.read_table_metadata(table_name)?;
+    for sub_table_index in 0..table_metadata.sub_tables.len() {
+        table_metadata.sub_tables[sub_table_index] = storage.read_sub_table(table_name, sub_table_index)?.len();
+    }
+    storage.replace_table_metadata(table_name, &table_metadata)?;
+    for field in table_metadata.indexes.iter() {
+        // The index file may never have been written, in which case there is nothing to delete
+        storage.delete_index(table_name, field.as_str()).ok();
+    }
+    Ok(())
+}
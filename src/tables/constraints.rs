@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Map, Value};
+use crate::rows::query::get_path;
+use crate::tables::Field;
+use crate::tables::table_err::TableError;
+use crate::tcp::response::ResponseError;
+
+/*
+    Constraints are declared alongside fields when creating a table:
+        { "constraints": [
+            { "type": "unique", "fields": ["email"] },
+            { "type": "unique", "fields": ["order_id", "line"] },
+            { "type": "not_null", "field": "name" }
+        ] }
+
+    A unique constraint over several fields is composite, only the combination of values has to
+    be unique. Rows with a null or missing value in any field of a unique constraint are not
+    checked against it, the same as a SQL unique constraint.
+
+    Unique constraints are checked against an in-memory index of the values already in the table,
+    so that writes never need to scan the table's sub_tables. The indexes are built when the
+    table is loaded.
+*/
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Constraint {
+    Unique { fields: Vec<String> },
+    NotNull { field: String },
+}
+
+#[derive(Debug)]
+pub enum ConstraintViolation {
+    Unique { fields: Vec<String>, conflicting_id: String },
+    NotNull { field: String },
+}
+
+impl Display for ConstraintViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let err_msg: String = match self {
+            ConstraintViolation::Unique { fields, conflicting_id } => {
+                format!("Row has the same value for ({}) as row '{}'", fields.join(", "), conflicting_id)
+            },
+            ConstraintViolation::NotNull { field } => format!("Field '{}' cannot be null", field),
+        };
+        write!(f, "{}", err_msg)
+    }
+}
+
+impl std::error::Error for ConstraintViolation {}
+
+impl ResponseError for ConstraintViolation {
+    fn status(&self) -> u16 {
+        match self {
+            ConstraintViolation::Unique { .. } => 409,
+            ConstraintViolation::NotNull { .. } => 422,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            ConstraintViolation::Unique { .. } => "unique_violation",
+            ConstraintViolation::NotNull { .. } => "not_null_violation",
+        }
+    }
+
+    fn details(&self) -> Option<Value> {
+        match self {
+            ConstraintViolation::Unique { fields, conflicting_id } => Some(json!({
+                "fields": fields,
+                "conflicting_id": conflicting_id,
+            })),
+            ConstraintViolation::NotNull { field } => Some(json!({ "field": field })),
+        }
+    }
+}
+
+fn parse_path(field: &str) -> Vec<String> {
+    field.split('.').map(|segment| segment.to_string()).collect()
+}
+
+/// Parse the list of constraints given when creating a table. When the table has a schema, every
+/// field a constraint refers to must be a part of it.
+pub fn parse_constraints(data: &Map<String, Value>, fields: &[Field]) -> Result<Vec<Constraint>, TableError> {
+    let constraints = match data.get("constraints") {
+        None => return Ok(Vec::new()),
+        Some(constraints) => constraints,
+    };
+    let constraints: Vec<Constraint> = serde_json::from_value(constraints.clone())
+        .map_err(|e| TableError::InvalidSchema(format!("Constraints could not be parsed: {}", e)))?;
+
+    for constraint in &constraints {
+        let constrained: Vec<&String> = match constraint {
+            Constraint::Unique { fields } if fields.is_empty() => {
+                return Err(TableError::InvalidSchema("A unique constraint must name at least one field".to_string()))
+            },
+            Constraint::Unique { fields } => fields.iter().collect(),
+            Constraint::NotNull { field } => vec![field],
+        };
+        for name in constrained {
+            let top_level = name.split('.').next().unwrap_or_default();
+            if !fields.is_empty() && !fields.iter().any(|field| field.name == top_level) {
+                return Err(TableError::InvalidSchema(format!("Constraint refers to '{}' which is not a field of the table", name)))
+            }
+        }
+    }
+    Ok(constraints)
+}
+
+/// The values held by every unique constraint of a table, mapping the serialized values of a
+/// row's constrained fields to the ID of the row holding them
+#[derive(Debug, Default)]
+pub struct UniqueIndexes {
+    indexes: Vec<HashMap<String, String>>,
+}
+
+impl UniqueIndexes {
+    pub fn new(constraints: &[Constraint]) -> Self {
+        Self { indexes: Self::unique_fields(constraints).map(|_| HashMap::new()).collect() }
+    }
+
+    /// The key a row is stored under for a unique constraint, or `None` if any of the fields are
+    /// null, in which case the row is not held to the constraint
+    fn key(fields: &[String], row: &Value) -> Option<String> {
+        let values = fields.iter()
+            .map(|field| get_path(row, &parse_path(field)).filter(|value| !value.is_null()))
+            .collect::<Option<Vec<&Value>>>()?;
+        Some(serde_json::to_string(&values).expect("serde_json Value should impl Serialize"))
+    }
+
+    fn unique_fields(constraints: &[Constraint]) -> impl Iterator<Item = &Vec<String>> {
+        constraints.iter().filter_map(|constraint| match constraint {
+            Constraint::Unique { fields } => Some(fields),
+            Constraint::NotNull { .. } => None,
+        })
+    }
+
+    /// Check that a row can be written without breaking any constraint. When a row is being
+    /// updated its own ID is given, so it does not conflict with its current values.
+    pub fn check(&self, constraints: &[Constraint], row: &Value, own_id: Option<&str>) -> Result<(), ConstraintViolation> {
+        for constraint in constraints {
+            if let Constraint::NotNull { field } = constraint
+                && get_path(row, &parse_path(field)).is_none_or(Value::is_null)
+            {
+                return Err(ConstraintViolation::NotNull { field: field.clone() })
+            }
+        }
+        for (fields, index) in Self::unique_fields(constraints).zip(&self.indexes) {
+            let Some(key) = Self::key(fields, row) else {
+                continue
+            };
+            match index.get(&key) {
+                Some(existing_id) if Some(existing_id.as_str()) != own_id => {
+                    return Err(ConstraintViolation::Unique { fields: fields.clone(), conflicting_id: existing_id.clone() })
+                },
+                _ => ()
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a written row's values to the indexes
+    pub fn insert(&mut self, constraints: &[Constraint], row: &Value) {
+        let Some(Value::String(id)) = row.get("_id") else {
+            return
+        };
+        for (fields, index) in Self::unique_fields(constraints).zip(self.indexes.iter_mut()) {
+            if let Some(key) = Self::key(fields, row) {
+                index.insert(key, id.clone());
+            }
+        }
+    }
+
+    /// Remove a row's values from the indexes after it has been deleted or before it is updated
+    pub fn remove(&mut self, constraints: &[Constraint], row: &Value) {
+        for (fields, index) in Self::unique_fields(constraints).zip(self.indexes.iter_mut()) {
+            if let Some(key) = Self::key(fields, row) {
+                index.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rows;
+    use crate::rows::row_err::RowError;
+    use crate::storage::memory::MemoryStorage;
+    use crate::tables::{Table, DEFAULT_RECORDS_PER_SUB_TABLE};
+    use crate::tcp::frame::{Command, Frame};
+    use crate::transactions::Transaction;
+    use crate::State;
+
+    fn data(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("Request data must be an object")
+        }
+    }
+
+    fn constraints() -> Vec<Constraint> {
+        let data = data(json!({"constraints": [
+            {"type": "unique", "fields": ["email"]},
+            {"type": "unique", "fields": ["order.id", "line"]},
+            {"type": "not_null", "field": "name"},
+        ]}));
+        parse_constraints(&data, &[]).unwrap()
+    }
+
+    fn indexes_with(constraints: &[Constraint], rows: &[Value]) -> UniqueIndexes {
+        let mut indexes = UniqueIndexes::new(constraints);
+        rows.iter().for_each(|row| indexes.insert(constraints, row));
+        indexes
+    }
+
+    #[test]
+    fn unique_values_conflict_with_other_rows() {
+        let constraints = constraints();
+        let indexes = indexes_with(&constraints, &[json!({"_id": "0.a", "name": "alice", "email": "a@x.com"})]);
+
+        let violation = indexes.check(&constraints, &json!({"name": "bob", "email": "a@x.com"}), None).unwrap_err();
+        assert_eq!((violation.status(), violation.code()), (409, "unique_violation"));
+        assert_eq!(violation.details(), Some(json!({"fields": ["email"], "conflicting_id": "0.a"})));
+
+        // A row doesn't conflict with its own values, and rows without a value aren't checked
+        assert!(indexes.check(&constraints, &json!({"name": "alice", "email": "a@x.com"}), Some("0.a")).is_ok());
+        assert!(indexes.check(&constraints, &json!({"name": "bob", "email": "b@x.com"}), None).is_ok());
+        assert!(indexes.check(&constraints, &json!({"name": "bob", "email": null}), None).is_ok());
+        assert!(indexes.check(&constraints, &json!({"name": "bob"}), None).is_ok());
+    }
+
+    #[test]
+    fn removed_values_can_be_taken() {
+        let constraints = constraints();
+        let alice = json!({"_id": "0.a", "name": "alice", "email": "a@x.com"});
+        let mut indexes = indexes_with(&constraints, std::slice::from_ref(&alice));
+        indexes.remove(&constraints, &alice);
+        assert!(indexes.check(&constraints, &json!({"name": "bob", "email": "a@x.com"}), None).is_ok());
+    }
+
+    #[test]
+    fn composite_unique_only_checks_the_combination() {
+        let constraints = constraints();
+        let indexes = indexes_with(&constraints, &[json!({"_id": "0.a", "name": "a", "order": {"id": 7}, "line": 1})]);
+
+        assert!(indexes.check(&constraints, &json!({"name": "b", "order": {"id": 7}, "line": 2}), None).is_ok());
+        assert!(indexes.check(&constraints, &json!({"name": "b", "order": {"id": 8}, "line": 1}), None).is_ok());
+        assert!(indexes.check(&constraints, &json!({"name": "b", "order": {"id": 7}}), None).is_ok());
+        let violation = indexes.check(&constraints, &json!({"name": "b", "order": {"id": 7}, "line": 1}), None).unwrap_err();
+        assert_eq!(violation.details(), Some(json!({"fields": ["order.id", "line"], "conflicting_id": "0.a"})));
+    }
+
+    #[test]
+    fn not_null_fields_must_be_present() {
+        let constraints = constraints();
+        let indexes = UniqueIndexes::new(&constraints);
+        for row in [json!({}), json!({"name": null})] {
+            let violation = indexes.check(&constraints, &row, None).unwrap_err();
+            assert_eq!((violation.status(), violation.code()), (422, "not_null_violation"));
+            assert_eq!(violation.details(), Some(json!({"field": "name"})));
+        }
+        assert!(indexes.check(&constraints, &json!({"name": ""}), None).is_ok());
+    }
+
+    #[test]
+    fn malformed_constraints_are_rejected() {
+        let fields: Vec<Field> = serde_json::from_value(json!([{"name": "email", "type": "string"}])).unwrap();
+        let requests = [
+            json!({"constraints": [{"type": "unique", "fields": []}]}),
+            json!({"constraints": [{"type": "primary", "field": "email"}]}),
+            json!({"constraints": [{"type": "not_null"}]}),
+            json!({"constraints": [{"type": "not_null", "field": "name"}]}),
+            json!({"constraints": {"type": "unique", "fields": ["email"]}}),
+        ];
+        for request in requests {
+            let error = parse_constraints(&data(request.clone()), &fields).unwrap_err();
+            assert!(matches!(error, TableError::InvalidSchema(_)), "{} gave {:?}", request, error);
+        }
+        assert_eq!(parse_constraints(&data(json!({"constraints": [{"type": "unique", "fields": ["email.domain"]}]})), &fields).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn unique_violations_roll_back_the_whole_commit() {
+        let state = State::initialize(Box::new(MemoryStorage::default()), DEFAULT_RECORDS_PER_SUB_TABLE);
+        let frame = Frame {
+            command: Command::CreateTable,
+            table: "people".to_string(),
+            data: data(json!({"constraints": [{"type": "unique", "fields": ["email"]}]})),
+        };
+        Table::create_table(&state, frame).unwrap();
+        let insert = |transaction: Option<&mut Transaction>, email: &str| rows::insert_data(&state, transaction, "people", data(json!({"email": email})));
+        let alice = insert(None, "a@x.com").unwrap();
+        insert(None, "b@x.com").unwrap();
+
+        // Alice takes a new address, but the two rows inserted alongside share one. Writes are
+        // only checked against each other once the transaction commits.
+        let mut transaction = Transaction::begin(&state);
+        rows::update_data(&state, Some(&mut transaction), "people", data(json!({"_id": alice, "data": {"email": "c@x.com"}}))).unwrap();
+        insert(Some(&mut transaction), "e@x.com").unwrap();
+        insert(Some(&mut transaction), "e@x.com").unwrap();
+        let error = transaction.commit(&state).unwrap_err();
+        assert!(matches!(error, RowError::ConstraintViolation(ConstraintViolation::Unique { .. })), "{:?}", error);
+
+        // None of it was written, and the unique index holds alice's old address again
+        let row = rows::read_data_by_id(&state, None, "people", data(json!({"_id": alice}))).unwrap();
+        assert_eq!(row["email"], "a@x.com");
+        let rows = rows::query_data(&state, None, "people", data(json!({}))).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(insert(None, "a@x.com").unwrap_err().code(), "unique_violation");
+        insert(None, "c@x.com").unwrap();
+        insert(None, "e@x.com").unwrap();
+    }
+}
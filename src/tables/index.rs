@@ -0,0 +1,185 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use serde_json::{json, Value};
+use crate::rows::query::{get_path, total_order, Condition};
+use crate::tables::RowChange;
+
+/*
+    A secondary index maps every value of a field to the IDs of the rows holding it. Rows missing
+    the field are indexed under `null`. Values are kept in the same order used for sorting, so
+    the index can answer both equality and range conditions.
+
+    An index is stored whole as a list of `[value, [ids]]` pairs in order, and the fields a table
+    has indexes on are listed in its metadata. Rewriting the whole index on every write would get
+    slower as the table grows, so each write instead appends just what it changed:
+
+        {"removed": [[value, id], ...], "inserted": [[value, id], ...]}
+
+    and the changes are replayed on top of the whole index when it is loaded. Once more changes
+    have been appended than the index has values, it is written whole again in their place.
+*/
+
+// Fewer appended changes than this are never worth writing the whole index again for
+const MIN_CHANGES_TO_COMPACT: usize = 64;
+
+/// A JSON value ordered by `total_order`, so that it can be used as a key in a BTreeMap
+#[derive(Debug, Clone)]
+struct IndexKey(Value);
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for IndexKey {}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        total_order(&self.0, &other.0)
+    }
+}
+
+fn same_type(a: &Value, b: &Value) -> bool {
+    std::mem::discriminant(a) == std::mem::discriminant(b)
+}
+
+#[derive(Debug)]
+pub struct FieldIndex {
+    path: Vec<String>,
+    entries: BTreeMap<IndexKey, BTreeSet<String>>,
+    // How many sets of changes have been appended to the stored index since it was last written whole
+    appended_changes: usize,
+}
+
+impl FieldIndex {
+    pub fn new(field: &str) -> Self {
+        Self {
+            path: field.split('.').map(|segment| segment.to_string()).collect(),
+            entries: BTreeMap::new(),
+            appended_changes: 0,
+        }
+    }
+
+    fn key(&self, row: &Value) -> IndexKey {
+        IndexKey(get_path(row, &self.path).cloned().unwrap_or(Value::Null))
+    }
+
+    pub fn insert(&mut self, row: &Value) {
+        if let Some(Value::String(id)) = row.get("_id") {
+            self.entries.entry(self.key(row)).or_default().insert(id.clone());
+        }
+    }
+
+    pub fn remove(&mut self, row: &Value) {
+        if let (Some(Value::String(id)), key) = (row.get("_id"), self.key(row)) {
+            self.remove_entry(&key, id);
+        }
+    }
+
+    fn remove_entry(&mut self, key: &IndexKey, id: &str) {
+        if let Some(ids) = self.entries.get_mut(key) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.entries.remove(key);
+            }
+        }
+    }
+
+    /// Update the index for a set of row changes, returning what changed in the form it is
+    /// appended to the stored index in
+    pub fn apply_changes(&mut self, changes: &[RowChange]) -> Value {
+        let mut removed = Vec::new();
+        let mut inserted = Vec::new();
+        for change in changes {
+            if let Some(old) = &change.old {
+                self.remove(old);
+                removed.push(json!([self.key(old).0, change.id]));
+            }
+            if let Some(new) = &change.new {
+                self.insert(new);
+                inserted.push(json!([self.key(new).0, change.id]));
+            }
+        }
+        self.appended_changes += 1;
+        json!({"removed": removed, "inserted": inserted})
+    }
+
+    /// Whether enough changes have been appended to the stored index that it should be written
+    /// whole again, see `written`
+    pub fn needs_compaction(&self) -> bool {
+        self.appended_changes >= MIN_CHANGES_TO_COMPACT && self.appended_changes > self.entries.len()
+    }
+
+    /// Note that the index has just been written whole, so no changes are appended to it
+    pub fn written(&mut self) {
+        self.appended_changes = 0;
+    }
+
+    /// Find the IDs of every row which could match a condition on the indexed field. Returns
+    /// `None` if the index can't narrow down the rows for the condition.
+    pub fn lookup(&self, condition: &Condition) -> Option<BTreeSet<String>> {
+        let ids = match condition {
+            Condition::Eq(value) => self.entries.get(&IndexKey(value.clone())).cloned().unwrap_or_default(),
+            Condition::In(values) => values.iter()
+                .filter_map(|value| self.entries.get(&IndexKey(value.clone())))
+                .flatten()
+                .cloned()
+                .collect(),
+            // Range conditions only match values of the same type, so stop once the range runs
+            // into the next type
+            Condition::Gt(value) => self.entries
+                .range((Bound::Excluded(IndexKey(value.clone())), Bound::Unbounded))
+                .take_while(|(key, _ids)| same_type(&key.0, value))
+                .flat_map(|(_key, ids)| ids.iter().cloned())
+                .collect(),
+            Condition::Lt(value) => self.entries
+                .range((Bound::Unbounded, Bound::Excluded(IndexKey(value.clone()))))
+                .rev()
+                .take_while(|(key, _ids)| same_type(&key.0, value))
+                .flat_map(|(_key, ids)| ids.iter().cloned())
+                .collect(),
+            Condition::Ne(_) => return None,
+        };
+        Some(ids)
+    }
+
+    pub fn to_value(&self) -> Value {
+        let entries: Vec<Value> = self.entries.iter()
+            .map(|(key, ids)| json!([key.0, ids]))
+            .collect();
+        Value::Array(entries)
+    }
+
+    /// Load an index from its stored form, the whole index followed by the changes appended to
+    /// it since. Returns `None` if any of it is malformed.
+    pub fn from_stored(field: &str, value: Value, changes: Vec<Value>) -> Option<Self> {
+        let mut index = Self::new(field);
+        let Value::Array(entries) = value else {
+            return None
+        };
+        for entry in entries {
+            let (key, ids): (Value, BTreeSet<String>) = serde_json::from_value(entry).ok()?;
+            index.entries.insert(IndexKey(key), ids);
+        }
+        index.appended_changes = changes.len();
+        for mut change in changes {
+            let removed: Vec<(Value, String)> = serde_json::from_value(change.get_mut("removed")?.take()).ok()?;
+            let inserted: Vec<(Value, String)> = serde_json::from_value(change.get_mut("inserted")?.take()).ok()?;
+            for (key, id) in removed {
+                index.remove_entry(&IndexKey(key), id.as_str());
+            }
+            for (key, id) in inserted {
+                index.entries.entry(IndexKey(key)).or_default().insert(id);
+            }
+        }
+        Some(index)
+    }
+}
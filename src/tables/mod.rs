@@ -1,23 +1,33 @@
+pub mod constraints;
+pub mod index;
+pub mod schema;
 pub mod table_err;
 
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, RwLock};
 use serde::{Serialize, Deserialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 
 use crate::tcp::frame::Frame;
 use table_err::TableError;
 use crate::State;
-use crate::tables::table_err::TableError::{TableAlreadyExists};
-use crate::file_reader;
+use crate::tables::table_err::TableError::{IndexAlreadyExists, IndexDoesntExist, InvalidIndex, InvalidTableName, TableAlreadyExists, TableDoesntExist};
+use crate::rows::query::Filter;
+use crate::logging::{log_error, log_info};
+use crate::storage::StorageEngine;
+use constraints::{Constraint, ConstraintViolation, UniqueIndexes};
+use index::FieldIndex;
+use schema::{FieldType, SchemaViolation};
+
+pub const DEFAULT_RECORDS_PER_SUB_TABLE: usize = 1000;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Field {
     name: String,
-    field_type: Value,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Constraint {
-    field: String,
+    #[serde(rename = "type")]
+    field_type: FieldType,
+    #[serde(default)]
+    nullable: bool,
 }
 
 /// A database table, serialized into a JSON string for storage on disk.
@@ -25,28 +35,306 @@ pub struct Constraint {
 pub struct Table {
     pub name: String,
     fields: Vec<Field>,
-    constraints: Vec<Constraint>
+    constraints: Vec<Constraint>,
+    // Built from the table's rows when it is loaded rather than being stored
+    #[serde(skip)]
+    unique_indexes: UniqueIndexes,
+    // Secondary indexes by field, loaded from their own files
+    #[serde(skip)]
+    indexes: BTreeMap<String, FieldIndex>,
+    // Slots in the table's sub_tables held for rows which haven't been committed yet
+    #[serde(skip)]
+    reserved_slots: Arc<ReservedSlots>,
 }
 
 impl Table {
-    pub fn create_table(state: &mut State, frame: Frame) -> Result<(), TableError> {
-        if state.tables.contains_key(frame.table.as_str()) {
+    pub fn reserved_slots(&self) -> &Arc<ReservedSlots> {
+        &self.reserved_slots
+    }
+
+    pub fn create_table(state: &State, frame: Frame) -> Result<(), TableError> {
+        // Hold the write lock on the table map for the whole creation so two requests can't
+        // race to create the same table
+        let mut tables = state.tables.write().expect("Table map lock was poisoned");
+        if tables.contains_key(frame.table.as_str()) {
             return Err(TableAlreadyExists)
         }
 
-        // TODO: Actually create fields for the table
-        let table = Self{ name: frame.table.clone(), fields: Vec::new(), constraints: Vec::new() };
-
-        file_reader::create_new_table_file_data(&table)?;
+        Self::validate_name(frame.table.as_str())?;
+        let fields = schema::parse_fields(&frame.data)?;
+        let constraints = constraints::parse_constraints(&frame.data, &fields)?;
+        let unique_indexes = UniqueIndexes::new(&constraints);
+        let table = Self{
+            name: frame.table.clone(),
+            fields,
+            constraints,
+            unique_indexes,
+            indexes: BTreeMap::new(),
+            reserved_slots: Arc::default(),
+        };
+        state.storage.create_table(&table, &TableMetadata::new(state.records_per_sub_table))?;
 
         // Add new table to state
-        state.tables.insert(table.name.clone(), table);
+        tables.insert(table.name.clone(), Arc::new(RwLock::new(table)));
+        Ok(())
+    }
+
+    /// Check that a table name can be used as the name of the table's files
+    fn validate_name(table_name: &str) -> Result<(), TableError> {
+        if table_name.is_empty() || table_name == "." || table_name == ".." || table_name.contains(['/', '\\']) || table_name.contains(char::is_control) {
+            return Err(InvalidTableName(format!("'{}' can't be used as a table name", table_name.escape_default())))
+        }
+        Ok(())
+    }
+
+    /// Check that a row conforms to the table's schema
+    pub fn validate_row(&self, row: &Map<String, Value>) -> Result<(), Vec<SchemaViolation>> {
+        schema::validate_row(&self.fields, row)
+    }
+
+    /// Check that a row can be written without breaking any of the table's constraints. `own_id`
+    /// is the ID of the row being updated, if any.
+    pub fn check_constraints(&self, row: &Value, own_id: Option<&str>) -> Result<(), ConstraintViolation> {
+        self.unique_indexes.check(&self.constraints, row, own_id)
+    }
+
+    /// Update the in-memory indexes backing the table's unique constraints for a set of row
+    /// changes, checking that none of the changed rows break a constraint. The changes are
+    /// checked together, so a row can take a value which another change frees up. If any row
+    /// breaks a constraint the indexes are left as they were.
+    pub fn apply_unique_changes(&mut self, changes: &[RowChange]) -> Result<(), ConstraintViolation> {
+        for old in changes.iter().filter_map(|change| change.old.as_ref()) {
+            self.unique_indexes.remove(&self.constraints, old);
+        }
+        for (applied, change) in changes.iter().enumerate() {
+            let Some(new) = &change.new else {
+                continue
+            };
+            if let Err(violation) = self.unique_indexes.check(&self.constraints, new, Some(change.id.as_str())) {
+                for new in changes[..applied].iter().filter_map(|change| change.new.as_ref()) {
+                    self.unique_indexes.remove(&self.constraints, new);
+                }
+                for old in changes.iter().filter_map(|change| change.old.as_ref()) {
+                    self.unique_indexes.insert(&self.constraints, old);
+                }
+                return Err(violation)
+            }
+            self.unique_indexes.insert(&self.constraints, new);
+        }
+        Ok(())
+    }
+
+    /// Undo `apply_unique_changes` for changes which failed to be written
+    pub fn revert_unique_changes(&mut self, changes: &[RowChange]) {
+        for new in changes.iter().filter_map(|change| change.new.as_ref()) {
+            self.unique_indexes.remove(&self.constraints, new);
+        }
+        for old in changes.iter().filter_map(|change| change.old.as_ref()) {
+            self.unique_indexes.insert(&self.constraints, old);
+        }
+    }
+
+    /// Update the table's secondary indexes after a set of row changes has been written. The rows
+    /// are already written by this point, so an index file which can't be written is deleted to
+    /// have it rebuilt from the rows on next startup rather than failing the write.
+    pub fn apply_index_changes(&mut self, storage: &dyn StorageEngine, changes: &[RowChange]) {
+        for (field, index) in self.indexes.iter_mut() {
+            let index_changes = index.apply_changes(changes);
+            let res = match index.needs_compaction() {
+                true => storage.write_index(self.name.as_str(), field, &index.to_value()).map(|()| index.written()),
+                false => storage.append_index_changes(self.name.as_str(), field, &index_changes)
+            };
+            if let Err(e) = res {
+                log_error!("Failed to write index on '{}' for table '{}', it will be rebuilt on next startup: {}", field, self.name, e);
+                if let Err(e) = storage.delete_index(self.name.as_str(), field) {
+                    log_error!("Failed to delete out of date index on '{}' for table '{}': {}", field, self.name, e);
+                }
+            }
+        }
+    }
+
+    /// Call `f` on every row stored in the table
+    fn for_each_row(&self, storage: &dyn StorageEngine, mut f: impl FnMut(&Value)) -> Result<(), TableError> {
+        let table_metadata = storage.read_table_metadata(self.name.as_str())?;
+        for sub_table_index in 0..table_metadata.sub_tables.len() {
+            storage.read_sub_table(self.name.as_str(), sub_table_index)?.iter().for_each(&mut f);
+        }
+        Ok(())
+    }
+
+    /// Build the in-memory indexes backing the table's unique constraints from the rows on disk,
+    /// and load its secondary indexes. A secondary index which is missing or can't be read is
+    /// rebuilt from the table's rows.
+    pub fn load_indexes(&mut self, storage: &dyn StorageEngine) -> Result<(), TableError> {
+        let mut unique_indexes = UniqueIndexes::new(&self.constraints);
+        if !self.constraints.is_empty() {
+            self.for_each_row(storage, |row| unique_indexes.insert(&self.constraints, row))?;
+        }
+        self.unique_indexes = unique_indexes;
+
+        let table_metadata = storage.read_table_metadata(self.name.as_str())?;
+        for field in table_metadata.indexes {
+            let stored = storage.read_index(self.name.as_str(), field.as_str())
+                .ok()
+                .and_then(|(value, changes)| FieldIndex::from_stored(field.as_str(), value, changes));
+            let index = match stored {
+                Some(index) => index,
+                None => {
+                    log_info!("Rebuilding index on '{}' for table '{}'", field, self.name);
+                    let mut index = FieldIndex::new(field.as_str());
+                    self.for_each_row(storage, |row| index.insert(row))?;
+                    storage.write_index(self.name.as_str(), field.as_str(), &index.to_value())?;
+                    index
+                }
+            };
+            self.indexes.insert(field, index);
+        }
+        Ok(())
+    }
+
+    /// Find the IDs of every row which could match a filter, using an index on one of the fields
+    /// the filter requires. Returns `None` if no index can narrow down the rows, in which case
+    /// every row has to be checked.
+    pub fn candidate_ids(&self, filter: &Filter) -> Option<BTreeSet<String>> {
+        filter.required_conditions().into_iter()
+            .filter_map(|(path, condition)| self.indexes.get(path.join(".").as_str())?.lookup(condition))
+            .min_by_key(|ids| ids.len())
+    }
+
+    fn index_field(frame: &Frame) -> Result<String, TableError> {
+        match frame.data.get("field") {
+            Some(Value::String(field)) if field == "_id" => Err(InvalidIndex("Rows are already looked up by '_id'".to_string())),
+            Some(Value::String(field)) if field.is_empty() || field.contains(['/', '\\']) => {
+                Err(InvalidIndex(format!("'{}' is not a valid field to index", field)))
+            },
+            Some(Value::String(field)) => Ok(field.clone()),
+            _ => Err(InvalidIndex("Request was missing its 'field' string".to_string()))
+        }
+    }
+
+    /// Create an index on a field, building it from the rows already in the table
+    pub fn create_index(state: &State, frame: Frame) -> Result<(), TableError> {
+        let table = state.get_table(frame.table.as_str()).ok_or(TableDoesntExist)?;
+        let mut table = table.write().expect("Table lock was poisoned");
+        let field = Self::index_field(&frame)?;
+        if table.indexes.contains_key(&field) {
+            return Err(IndexAlreadyExists)
+        }
+
+        let mut index = FieldIndex::new(field.as_str());
+        table.for_each_row(state.storage.as_ref(), |row| index.insert(row))?;
+        // The index file is written before the metadata lists it, so a listed index always has
+        // a file unless it was deleted from outside of the db
+        state.storage.write_index(frame.table.as_str(), field.as_str(), &index.to_value())?;
+        let mut table_metadata = state.storage.read_table_metadata(frame.table.as_str())?;
+        table_metadata.indexes.push(field.clone());
+        state.storage.replace_table_metadata(frame.table.as_str(), &table_metadata)?;
+
+        table.indexes.insert(field, index);
+        Ok(())
+    }
+
+    pub fn drop_index(state: &State, frame: Frame) -> Result<(), TableError> {
+        let table = state.get_table(frame.table.as_str()).ok_or(TableDoesntExist)?;
+        let mut table = table.write().expect("Table lock was poisoned");
+        let field = Self::index_field(&frame)?;
+        if !table.indexes.contains_key(&field) {
+            return Err(IndexDoesntExist)
+        }
+
+        let mut table_metadata = state.storage.read_table_metadata(frame.table.as_str())?;
+        table_metadata.indexes.retain(|indexed| indexed != &field);
+        state.storage.replace_table_metadata(frame.table.as_str(), &table_metadata)?;
+        if let Err(e) = state.storage.delete_index(frame.table.as_str(), field.as_str()) {
+            log_error!("Dropped index on '{}' but failed to delete its file: {}", field, e);
+        }
+
+        table.indexes.remove(&field);
+        Ok(())
+    }
+
+    pub fn drop_table(state: &State, frame: Frame) -> Result<(), TableError> {
+        let mut tables = state.tables.write().expect("Table map lock was poisoned");
+        let table = tables.get(frame.table.as_str()).ok_or(TableDoesntExist)?;
+        // Wait for anything still working on the table to finish before removing its files
+        let table_guard = table.write().expect("Table lock was poisoned");
+
+        state.storage.drop_table(frame.table.as_str())?;
+        state.versions.forget_table(frame.table.as_str());
+
+        drop(table_guard);
+        tables.remove(frame.table.as_str());
         Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A change to a single row of a table. `old` is the row as it was before the change and `new` is
+/// the row as it is after, either is `None` for inserts and deletes.
+#[derive(Debug)]
+pub struct RowChange {
+    pub id: String,
+    pub sub_table_index: usize,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TableMetadata {
     pub records_per_sub_table: usize,
     pub sub_tables: Vec<usize>,
+    // Fields which have a secondary index
+    #[serde(default)]
+    pub indexes: Vec<String>,
+}
+
+impl TableMetadata {
+    /// The metadata of a new table, which has a single empty sub_table
+    pub fn new(records_per_sub_table: usize) -> Self {
+        Self { records_per_sub_table, sub_tables: vec![0], indexes: Vec::new() }
+    }
+}
+
+impl Default for TableMetadata {
+    fn default() -> Self {
+        Self::new(DEFAULT_RECORDS_PER_SUB_TABLE)
+    }
+}
+
+/// How many slots in each of a table's sub_tables are held for rows which transactions have
+/// inserted but not committed yet. A sub_table is picked for a new row and a slot in it reserved
+/// under one lock, so inserts racing each other can't overfill a sub_table.
+#[derive(Debug, Default)]
+pub struct ReservedSlots {
+    counts: Mutex<Vec<usize>>,
+}
+
+impl ReservedSlots {
+    /// Reserve a slot in the first sub_table with space for a new row, or in a new sub_table if
+    /// none have space, returning the sub_table's index. `read_metadata` is called with the
+    /// reservations locked, so it sees every row committed before the slot was picked. The slot
+    /// has to be released once the row is committed or thrown away.
+    pub fn reserve<E>(&self, read_metadata: impl FnOnce() -> Result<TableMetadata, E>) -> Result<usize, E> {
+        let mut counts = self.counts.lock().expect("Reserved slot lock was poisoned");
+        let metadata = read_metadata()?;
+        let sub_table_count = metadata.sub_tables.len().max(counts.len());
+        let sub_table_index = (0..sub_table_count)
+            .position(|index| {
+                let used = metadata.sub_tables.get(index).unwrap_or(&0) + counts.get(index).unwrap_or(&0);
+                used < metadata.records_per_sub_table
+            })
+            .unwrap_or(sub_table_count);
+        if sub_table_index >= counts.len() {
+            counts.resize(sub_table_index + 1, 0);
+        }
+        counts[sub_table_index] += 1;
+        Ok(sub_table_index)
+    }
+
+    /// Give back a slot taken by `reserve`
+    pub fn release(&self, sub_table_index: usize) {
+        let mut counts = self.counts.lock().expect("Reserved slot lock was poisoned");
+        if let Some(count) = counts.get_mut(sub_table_index) {
+            *count = count.saturating_sub(1);
+        }
+    }
 }
@@ -0,0 +1,270 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+use crate::tables::Field;
+use crate::tables::table_err::TableError;
+
+/*
+    A table's schema is the list of fields given to `create_table`:
+        { "fields": [
+            { "name": "email", "type": "string" },
+            { "name": "age", "type": "integer", "nullable": true },
+            { "name": "created", "type": "timestamp" }
+        ] }
+
+    A table created without fields is schemaless and accepts any row. Once a table has fields,
+    every row must hold exactly those fields (plus `_id` and `_version`) with values of the declared types. A
+    nullable field may be `null` or left out of a row entirely.
+
+    Timestamps are RFC 3339 strings, such as `2025-03-14T15:09:26Z` or
+    `2025-03-14T15:09:26.5+01:00`.
+*/
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    String,
+    Integer,
+    Float,
+    Bool,
+    Object,
+    Array,
+    Timestamp,
+}
+
+impl FieldType {
+    fn matches(&self, value: &Value) -> bool {
+        match self {
+            FieldType::String => value.is_string(),
+            FieldType::Integer => value.is_i64() || value.is_u64(),
+            FieldType::Float => value.is_number(),
+            FieldType::Bool => value.is_boolean(),
+            FieldType::Object => value.is_object(),
+            FieldType::Array => value.is_array(),
+            FieldType::Timestamp => value.as_str().is_some_and(is_rfc3339),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            FieldType::String => "string",
+            FieldType::Integer => "integer",
+            FieldType::Float => "float",
+            FieldType::Bool => "bool",
+            FieldType::Object => "object",
+            FieldType::Array => "array",
+            FieldType::Timestamp => "timestamp",
+        }
+    }
+}
+
+/// A single way in which a row does not conform to its table's schema
+#[derive(Serialize, Debug)]
+pub struct SchemaViolation {
+    pub field: String,
+    pub reason: String,
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Check whether a string is an RFC 3339 timestamp
+fn is_rfc3339(timestamp: &str) -> bool {
+    fn number(bytes: &[u8], range: std::ops::RangeInclusive<u32>) -> bool {
+        bytes.iter().all(u8::is_ascii_digit)
+            && range.contains(&bytes.iter().fold(0, |total, digit| total * 10 + (digit - b'0') as u32))
+    }
+
+    let bytes = timestamp.as_bytes();
+    if bytes.len() < 20 {
+        return false
+    }
+    let date_time_valid = number(&bytes[0..4], 0..=9999) && bytes[4] == b'-'
+        && number(&bytes[5..7], 1..=12) && bytes[7] == b'-'
+        && number(&bytes[8..10], 1..=31) && matches!(bytes[10], b'T' | b't' | b' ')
+        && number(&bytes[11..13], 0..=23) && bytes[13] == b':'
+        && number(&bytes[14..16], 0..=59) && bytes[16] == b':'
+        // 60 allows for leap seconds
+        && number(&bytes[17..19], 0..=60);
+    if !date_time_valid {
+        return false
+    }
+
+    let mut rest = &bytes[19..];
+    if rest.first() == Some(&b'.') {
+        let fraction_length = rest[1..].iter().take_while(|byte| byte.is_ascii_digit()).count();
+        if fraction_length == 0 {
+            return false
+        }
+        rest = &rest[1 + fraction_length..];
+    }
+    match rest {
+        [b'Z' | b'z'] => true,
+        [b'+' | b'-', hours @ .., b':', m1, m2] if hours.len() == 2 => number(hours, 0..=23) && number(&[*m1, *m2], 0..=59),
+        _ => false
+    }
+}
+
+/// Parse the list of fields given when creating a table
+pub fn parse_fields(data: &Map<String, Value>) -> Result<Vec<Field>, TableError> {
+    let fields = match data.get("fields") {
+        None => return Ok(Vec::new()),
+        Some(fields) => fields,
+    };
+    let fields: Vec<Field> = serde_json::from_value(fields.clone())
+        .map_err(|e| TableError::InvalidSchema(format!("Fields could not be parsed: {}", e)))?;
+    for (i, field) in fields.iter().enumerate() {
+        if field.name == "_id" || field.name == "_version" {
+            return Err(TableError::InvalidSchema(format!("'{}' is generated for every row and cannot be declared", field.name)))
+        }
+        if fields[..i].iter().any(|other| other.name == field.name) {
+            return Err(TableError::InvalidSchema(format!("Field '{}' was declared more than once", field.name)))
+        }
+    }
+    Ok(fields)
+}
+
+/// Check a row against a list of fields, returning every way in which it does not conform. A
+/// table without any fields accepts every row.
+pub fn validate_row(fields: &[Field], row: &Map<String, Value>) -> Result<(), Vec<SchemaViolation>> {
+    if fields.is_empty() {
+        return Ok(())
+    }
+
+    let mut violations = Vec::new();
+    for field in fields {
+        match row.get(&field.name) {
+            None | Some(Value::Null) if field.nullable => (),
+            None => violations.push(SchemaViolation {
+                field: field.name.clone(),
+                reason: "field is required".to_string(),
+            }),
+            Some(value) if !field.field_type.matches(value) => violations.push(SchemaViolation {
+                field: field.name.clone(),
+                reason: format!("expected {}, got {}", field.field_type.name(), type_name(value)),
+            }),
+            Some(_) => (),
+        }
+    }
+    for key in row.keys() {
+        if key != "_id" && key != "_version" && !fields.iter().any(|field| &field.name == key) {
+            violations.push(SchemaViolation {
+                field: key.clone(),
+                reason: "field is not part of the table's schema".to_string(),
+            });
+        }
+    }
+
+    match violations.is_empty() {
+        true => Ok(()),
+        false => Err(violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fields() -> Vec<Field> {
+        let data = json!({"fields": [
+            {"name": "email", "type": "string"},
+            {"name": "age", "type": "integer", "nullable": true},
+            {"name": "score", "type": "float"},
+            {"name": "created", "type": "timestamp"},
+        ]});
+        parse_fields(data.as_object().unwrap()).unwrap()
+    }
+
+    fn violations(row: Value) -> Vec<(String, String)> {
+        match validate_row(&fields(), row.as_object().unwrap()) {
+            Ok(()) => Vec::new(),
+            Err(violations) => violations.into_iter().map(|violation| (violation.field, violation.reason)).collect()
+        }
+    }
+
+    #[test]
+    fn rfc3339_timestamps() {
+        let valid = [
+            "2025-03-14T15:09:26Z",
+            "2025-03-14t15:09:26z",
+            "2025-03-14 15:09:26Z",
+            "2025-03-14T15:09:26.5+01:00",
+            "2025-03-14T15:09:26.123456-08:30",
+            "2016-12-31T23:59:60Z",
+        ];
+        for timestamp in valid {
+            assert!(is_rfc3339(timestamp), "{} should be valid", timestamp);
+        }
+        let invalid = [
+            "",
+            "2025-03-14",
+            "2025-03-14T15:09:26",
+            "2025-03-14T15:09Z",
+            "2025-13-14T15:09:26Z",
+            "2025-03-00T15:09:26Z",
+            "2025-03-14T24:09:26Z",
+            "2025-03-14T15:60:26Z",
+            "2025-03-14T15:09:26.Z",
+            "2025-03-14T15:09:26+0100",
+            "2025-03-14T15:09:26+24:00",
+            "2025-03-14T15:09:26Z ",
+            "2025/03/14T15:09:26Z",
+            "+025-03-14T15:09:26Z",
+        ];
+        for timestamp in invalid {
+            assert!(!is_rfc3339(timestamp), "{} should be invalid", timestamp);
+        }
+    }
+
+    #[test]
+    fn rows_must_hold_each_field_with_its_type() {
+        assert!(violations(json!({"_id": "0.a", "_version": 1, "email": "a@b.c", "age": 30, "score": 1, "created": "2025-03-14T15:09:26Z"})).is_empty());
+        assert_eq!(violations(json!({"email": 1, "age": 30.5, "score": "high", "created": "yesterday", "extra": true})), vec![
+            ("email".to_string(), "expected string, got number".to_string()),
+            ("age".to_string(), "expected integer, got number".to_string()),
+            ("score".to_string(), "expected float, got string".to_string()),
+            ("created".to_string(), "expected timestamp, got string".to_string()),
+            ("extra".to_string(), "field is not part of the table's schema".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn only_nullable_fields_may_be_null_or_missing() {
+        assert!(violations(json!({"email": "a@b.c", "age": null, "score": 1.5, "created": "2025-03-14T15:09:26Z"})).is_empty());
+        assert!(violations(json!({"email": "a@b.c", "score": 1.5, "created": "2025-03-14T15:09:26Z"})).is_empty());
+        assert_eq!(violations(json!({"email": null, "created": "2025-03-14T15:09:26Z"})), vec![
+            ("email".to_string(), "expected string, got null".to_string()),
+            ("score".to_string(), "field is required".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn schemaless_tables_accept_any_row() {
+        assert!(parse_fields(&Map::new()).unwrap().is_empty());
+        assert!(validate_row(&[], json!({"anything": [1, "two"]}).as_object().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn malformed_schemas_are_rejected() {
+        let schemas = [
+            json!({"fields": {"name": "email"}}),
+            json!({"fields": [{"name": "email", "type": "text"}]}),
+            json!({"fields": [{"type": "string"}]}),
+            json!({"fields": [{"name": "_id", "type": "string"}]}),
+            json!({"fields": [{"name": "_version", "type": "integer"}]}),
+            json!({"fields": [{"name": "email", "type": "string"}, {"name": "email", "type": "string"}]}),
+        ];
+        for schema in schemas {
+            let error = parse_fields(schema.as_object().unwrap()).unwrap_err();
+            assert!(matches!(error, TableError::InvalidSchema(_)), "{} gave {:?}", schema, error);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::fmt::{Display, Formatter};
+use crate::tcp::response::ResponseError;
 
 // TODO: These are only here because of a refactor, file operation errors should really not be a part of
 //       the table module
@@ -9,7 +10,15 @@ pub enum TableError {
     FailedDiskRead,
     FailedDiskWrite,
     TableAlreadyExists,
+    TableDoesntExist,
+    InvalidTableName(String),
+    InvalidSchema(String),
+    IndexAlreadyExists,
+    IndexDoesntExist,
+    InvalidIndex(String),
     FailedCreateDir,
+    FailedRemoveDir,
+    NotADataDir(String),
 }
 
 impl Display for TableError {
@@ -19,10 +28,56 @@ impl Display for TableError {
             TableError::FailedDiskWrite => "Failed to write table data to disk".to_string(),
             TableError::FailedDiskRead => "Failed to read tables from disk".to_string(),
             TableError::TableAlreadyExists => "Tried to create a table which already exists".to_string(),
+            TableError::TableDoesntExist => "Tried to operate on a table that does not exist".to_string(),
+            TableError::InvalidTableName(reason) => format!("Invalid table name: {}", reason),
+            TableError::InvalidSchema(reason) => format!("Invalid table schema: {}", reason),
+            TableError::IndexAlreadyExists => "Tried to create an index which already exists".to_string(),
+            TableError::IndexDoesntExist => "Tried to operate on an index that does not exist".to_string(),
+            TableError::InvalidIndex(reason) => format!("Invalid index: {}", reason),
             TableError::FailedCreateDir => "Failed to create a directory for table".to_string(),
+            TableError::FailedRemoveDir => "Failed to remove a table's directory".to_string(),
+            TableError::NotADataDir(dir) => format!("'{}' already holds files which weren't written by etch, refusing to use it as the data directory", dir),
         };
         write!(f, "{}", err_msg)
     }
 }
 
 impl std::error::Error for TableError {}
+
+impl ResponseError for TableError {
+    fn status(&self) -> u16 {
+        match self {
+            TableError::FailedOpenTableFile => 500,
+            TableError::FailedDiskRead => 500,
+            TableError::FailedDiskWrite => 500,
+            TableError::TableAlreadyExists => 409,
+            TableError::TableDoesntExist => 404,
+            TableError::InvalidTableName(_) => 400,
+            TableError::InvalidSchema(_) => 400,
+            TableError::IndexAlreadyExists => 409,
+            TableError::IndexDoesntExist => 404,
+            TableError::InvalidIndex(_) => 400,
+            TableError::FailedCreateDir => 500,
+            TableError::FailedRemoveDir => 500,
+            TableError::NotADataDir(_) => 500,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            TableError::FailedOpenTableFile => "table_file_unavailable",
+            TableError::FailedDiskRead => "disk_read_failed",
+            TableError::FailedDiskWrite => "disk_write_failed",
+            TableError::TableAlreadyExists => "table_already_exists",
+            TableError::TableDoesntExist => "table_not_found",
+            TableError::InvalidTableName(_) => "invalid_table_name",
+            TableError::InvalidSchema(_) => "invalid_schema",
+            TableError::IndexAlreadyExists => "index_already_exists",
+            TableError::IndexDoesntExist => "index_not_found",
+            TableError::InvalidIndex(_) => "invalid_index",
+            TableError::FailedCreateDir => "create_dir_failed",
+            TableError::FailedRemoveDir => "remove_dir_failed",
+            TableError::NotADataDir(_) => "not_a_data_dir",
+        }
+    }
+}
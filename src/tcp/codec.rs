@@ -0,0 +1,237 @@
+use bytes::{Buf, BufMut, BytesMut};
+use serde_json::Value;
+use super::TCPError;
+
+/*
+    Two packet headers are understood, told apart by their first byte.
+
+    v1: | 42 | length: u16 |
+    v2: | 0xE7 | version: u8 | flags: u8 | length: u32 |
+
+    Both are big endian and followed by `length` bytes of JSON. v1 is kept so that old clients
+    continue to work, but it cannot carry more than 64KiB of data. A response is always written
+    using the same header version as the request it answers.
+*/
+
+/// The first byte of a v1 packet, indicating the start of a transmission
+const V1_START_BYTE: u8 = 42;
+const V1_HEADER_LENGTH: usize = 3;
+
+/// The first byte of a v2 packet
+const V2_MAGIC_BYTE: u8 = 0xE7;
+const V2_VERSION: u8 = 2;
+const V2_HEADER_LENGTH: usize = 7;
+
+/// The largest frame accepted or sent when no other limit is configured
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    V1,
+    V2,
+}
+
+struct Header {
+    version: ProtocolVersion,
+    length: usize,
+    data_length: usize,
+}
+
+/// Read the header at the front of `buffer` without consuming it, returning `None` if the whole
+/// header has not arrived yet.
+fn peek_header(buffer: &BytesMut) -> Result<Option<Header>, TCPError> {
+    let Some(&start_byte) = buffer.first() else {
+        return Ok(None)
+    };
+    match start_byte {
+        V1_START_BYTE => {
+            if buffer.len() < V1_HEADER_LENGTH {
+                return Ok(None)
+            }
+            let data_length = u16::from_be_bytes([buffer[1], buffer[2]]) as usize;
+            Ok(Some(Header { version: ProtocolVersion::V1, length: V1_HEADER_LENGTH, data_length }))
+        },
+        V2_MAGIC_BYTE => {
+            if buffer.len() < V2_HEADER_LENGTH {
+                return Ok(None)
+            }
+            if buffer[1] != V2_VERSION {
+                return Err(TCPError::UnsupportedVersion(buffer[1]))
+            }
+            // No flags are defined yet, refuse any so that they can be given a meaning later
+            // without old servers silently ignoring them
+            if buffer[2] != 0 {
+                return Err(TCPError::UnsupportedFlags(buffer[2]))
+            }
+            let data_length = u32::from_be_bytes([buffer[3], buffer[4], buffer[5], buffer[6]]) as usize;
+            Ok(Some(Header { version: ProtocolVersion::V2, length: V2_HEADER_LENGTH, data_length }))
+        },
+        _ => Err(TCPError::InvalidStart)
+    }
+}
+
+/// Try to decode a single packet from the front of `buffer`. Returns `None` if the buffer does
+/// not yet hold a complete packet, in which case nothing is consumed and the caller should read
+/// more data from the connection and try again. On success the packet's bytes are removed from
+/// the buffer, leaving any following packets in place.
+///
+/// A packet claiming to be longer than `max_frame_size` is rejected as soon as its header has
+/// been read, without waiting for the rest of it.
+pub fn decode(buffer: &mut BytesMut, max_frame_size: usize) -> Result<Option<(ProtocolVersion, Value)>, TCPError> {
+    let Some(header) = peek_header(buffer)? else {
+        return Ok(None)
+    };
+    if header.data_length > max_frame_size {
+        return Err(TCPError::FrameTooLarge(header.data_length, max_frame_size))
+    }
+    let packet_length = header.length + header.data_length;
+    if buffer.len() < packet_length {
+        // Make room for the rest of the packet up front rather than growing on each read
+        buffer.reserve(packet_length - buffer.len());
+        return Ok(None)
+    }
+
+    buffer.advance(header.length);
+    let data = buffer.split_to(header.data_length);
+    let value = serde_json::from_slice::<Value>(&data).map_err(|_| TCPError::MalformedJSON)?;
+    Ok(Some((header.version, value)))
+}
+
+/// Encode `value` as a packet using the given header version, appending it to `buffer`. Fails
+/// rather than truncating if the data is too long for the header or larger than `max_frame_size`.
+pub fn encode(value: &Value, version: ProtocolVersion, max_frame_size: usize, buffer: &mut BytesMut) -> Result<(), TCPError> {
+    let serialized = serde_json::to_vec(value).map_err(|_| TCPError::SerializeResponse)?;
+    let limit = match version {
+        ProtocolVersion::V1 => max_frame_size.min(u16::MAX as usize),
+        ProtocolVersion::V2 => max_frame_size.min(u32::MAX as usize),
+    };
+    if serialized.len() > limit {
+        return Err(TCPError::FrameTooLarge(serialized.len(), limit))
+    }
+
+    match version {
+        ProtocolVersion::V1 => {
+            buffer.reserve(V1_HEADER_LENGTH + serialized.len());
+            buffer.put_u8(V1_START_BYTE);
+            buffer.put_u16(serialized.len() as u16);
+        },
+        ProtocolVersion::V2 => {
+            buffer.reserve(V2_HEADER_LENGTH + serialized.len());
+            buffer.put_u8(V2_MAGIC_BYTE);
+            buffer.put_u8(V2_VERSION);
+            buffer.put_u8(0);
+            buffer.put_u32(serialized.len() as u32);
+        },
+    }
+    buffer.put_slice(&serialized);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const MAX: usize = DEFAULT_MAX_FRAME_SIZE;
+
+    fn encoded(value: &Value, version: ProtocolVersion) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        encode(value, version, MAX, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn decode_byte_by_byte() {
+        let value = json!({"command": "read", "table": "foo", "data": {"_id": "0.abc"}});
+        for version in [ProtocolVersion::V1, ProtocolVersion::V2] {
+            let packet = encoded(&value, version);
+
+            let mut buffer = BytesMut::new();
+            for (i, byte) in packet.iter().enumerate() {
+                buffer.put_u8(*byte);
+                let decoded = decode(&mut buffer, MAX).unwrap();
+                if i + 1 < packet.len() {
+                    assert!(decoded.is_none());
+                    assert_eq!(buffer.len(), i + 1);
+                } else {
+                    assert_eq!(decoded, Some((version, value.clone())));
+                }
+            }
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn decode_back_to_back() {
+        let values = [
+            (ProtocolVersion::V1, json!({"n": 1})),
+            (ProtocolVersion::V2, json!({"n": 2})),
+            (ProtocolVersion::V1, json!({"n": "three"})),
+        ];
+        let mut buffer = BytesMut::new();
+        for (version, value) in &values {
+            encode(value, *version, MAX, &mut buffer).unwrap();
+        }
+        // Leave half of a fourth packet trailing in the buffer
+        let partial = encoded(&json!({"n": 4}), ProtocolVersion::V2);
+        buffer.put_slice(&partial[..partial.len() / 2]);
+
+        for (version, value) in &values {
+            assert_eq!(decode(&mut buffer, MAX).unwrap(), Some((*version, value.clone())));
+        }
+        assert!(decode(&mut buffer, MAX).unwrap().is_none());
+        assert_eq!(buffer.len(), partial.len() / 2);
+    }
+
+    #[test]
+    fn decode_invalid_start() {
+        let mut buffer = BytesMut::from(&[7u8, 0, 0][..]);
+        assert!(matches!(decode(&mut buffer, MAX), Err(TCPError::InvalidStart)));
+    }
+
+    #[test]
+    fn decode_malformed_json_consumes_packet() {
+        let mut buffer = BytesMut::new();
+        buffer.put_u8(V1_START_BYTE);
+        buffer.put_u16(3);
+        buffer.put_slice(b"{{{");
+        buffer.put_slice(&encoded(&json!({"ok": true}), ProtocolVersion::V1));
+
+        assert!(matches!(decode(&mut buffer, MAX), Err(TCPError::MalformedJSON)));
+        assert_eq!(decode(&mut buffer, MAX).unwrap(), Some((ProtocolVersion::V1, json!({"ok": true}))));
+    }
+
+    #[test]
+    fn decode_v2_frame_larger_than_v1_limit() {
+        let value = json!({"big": "x".repeat(100_000)});
+        let mut buffer = encoded(&value, ProtocolVersion::V2);
+        assert_eq!(decode(&mut buffer, MAX).unwrap(), Some((ProtocolVersion::V2, value)));
+    }
+
+    #[test]
+    fn decode_rejects_frame_over_max_size_from_header() {
+        let mut buffer = BytesMut::new();
+        buffer.put_u8(V2_MAGIC_BYTE);
+        buffer.put_u8(V2_VERSION);
+        buffer.put_u8(0);
+        buffer.put_u32(1025);
+        assert!(matches!(decode(&mut buffer, 1024), Err(TCPError::FrameTooLarge(1025, 1024))));
+    }
+
+    #[test]
+    fn decode_rejects_unknown_version_and_flags() {
+        let mut buffer = BytesMut::from(&[V2_MAGIC_BYTE, 3, 0, 0, 0, 0, 2][..]);
+        assert!(matches!(decode(&mut buffer, MAX), Err(TCPError::UnsupportedVersion(3))));
+        let mut buffer = BytesMut::from(&[V2_MAGIC_BYTE, V2_VERSION, 1, 0, 0, 0, 2][..]);
+        assert!(matches!(decode(&mut buffer, MAX), Err(TCPError::UnsupportedFlags(1))));
+    }
+
+    #[test]
+    fn encode_refuses_to_truncate() {
+        let value = json!({"big": "x".repeat(70_000)});
+        let mut buffer = BytesMut::new();
+        assert!(matches!(encode(&value, ProtocolVersion::V1, MAX, &mut buffer), Err(TCPError::FrameTooLarge(_, 65535))));
+        assert!(matches!(encode(&value, ProtocolVersion::V2, 1024, &mut buffer), Err(TCPError::FrameTooLarge(_, 1024))));
+        assert!(buffer.is_empty());
+    }
+}
@@ -1,102 +1,166 @@
-use tokio::io::AsyncReadExt;
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
 use tokio::net::TcpStream;
 use super::TCPError;
-use serde_json::Value;
+use super::codec;
+use super::codec::ProtocolVersion;
 use super::frame::Frame;
+use super::response::Response;
 
 #[derive(Debug)]
 pub struct Connection {
     // TcpStream is decorated with a BufWriter, which provides write level buffering
-    stream: TcpStream,//BufWriter<TcpStream>,
+    stream: BufWriter<TcpStream>,
 
-    // TODO: Use this buffer instead of the two allocated ones in read_frame
-    // Buffer for reading frames
-    //buffer: BytesMut,
+    // Buffer for reading frames. Data is read off of the socket into this buffer until it holds
+    // at least one whole frame, any bytes past the end of that frame are kept for the next read
+    buffer: BytesMut,
+
+    // Header version of the last frame read, responses are written back using the same version
+    version: ProtocolVersion,
+
+    // Largest frame, in bytes, that will be read from or written to the connection
+    max_frame_size: usize,
 }
 
 impl Connection {
-    pub fn new(socket: TcpStream) -> Self {
+    pub fn new(socket: TcpStream, max_frame_size: usize) -> Self {
         Self {
-            stream: socket,//BufWriter::new(socket),
+            stream: BufWriter::new(socket),
             // 64KB is probably fine
-            //buffer: BytesMut::with_capacity(64 * 1024),
+            buffer: BytesMut::with_capacity(64 * 1024),
+            version: ProtocolVersion::V1,
+            max_frame_size,
         }
     }
 
-    pub async fn read_frame(&mut self) -> Result<Frame, TCPError> {
-        // TODO: This method is very fragile and reading a packet here will cause a panic if
-        //       all data is not transmitted at once, will need to be re-done but it's good
-        //       enough for a hackathon
+    /// Read the next frame off of the connection. Returns `None` if the peer closed the
+    /// connection cleanly between frames.
+    pub async fn read_frame(&mut self) -> Result<Option<Frame>, TCPError> {
+        loop {
+            if let Some((version, value)) = codec::decode(&mut self.buffer, self.max_frame_size)? {
+                self.version = version;
+                return Frame::from_json(value).map(Some)
+            }
 
-        // First 3 bytes are packet metadata
-        let mut buffer = [0u8; 3];
-        match self.stream.read_exact(&mut buffer).await {
-            Ok(_size) => (),
-            Err(_e) => return Err(TCPError::FailedReadHeader)
+            // There is not enough buffered data for a whole frame, try to read more
+            let read = self.stream.read_buf(&mut self.buffer).await.map_err(|_| TCPError::FailedRead)?;
+            if read == 0 {
+                // The peer closed the connection. This is only clean if it did so between frames,
+                // otherwise it hung up part way through sending one.
+                return match self.buffer.is_empty() {
+                    true => Ok(None),
+                    false => Err(TCPError::MalformedPacket)
+                }
+            }
         }
+    }
 
-        // First byte indicates start of transmission
-        // Bytes 2 and 3 are a u16 indicating the length of the data to follow
-        let start_byte = buffer[0];
-        let data_length = u16::from_be_bytes([buffer[1], buffer[2]]) as usize;
+    /// Write a whole response frame to the connection, returning the number of bytes written.
+    /// Nothing is written if the response is too large to be sent.
+    pub async fn respond(&mut self, response: Response) -> Result<usize, TCPError> {
+        let data = serde_json::to_value(response).map_err(|_| TCPError::SerializeResponse)?;
+        let mut bytes = BytesMut::new();
+        codec::encode(&data, self.version, self.max_frame_size, &mut bytes)?;
+        self.stream.write_all(&bytes).await.map_err(|_| TCPError::FailedWrite)?;
+        self.stream.flush().await.map_err(|_| TCPError::FailedWrite)?;
+        Ok(bytes.len())
+    }
+}
 
-        // The first byte being 42 indicates start of transmission
-        if start_byte != 42 {
-            return Err(TCPError::InvalidStart)
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    async fn connected_pair() -> (TcpStream, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _address) = listener.accept().await.unwrap();
+        (client, Connection::new(server, codec::DEFAULT_MAX_FRAME_SIZE))
+    }
+
+    fn encoded(value: &Value, version: ProtocolVersion) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        codec::encode(value, version, codec::DEFAULT_MAX_FRAME_SIZE, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[tokio::test]
+    async fn read_frame_sent_byte_by_byte() {
+        let (mut client, mut connection) = connected_pair().await;
+        let packet = encoded(&json!({"command": "insert", "table": "foo", "data": {"a": 1}}), ProtocolVersion::V2);
+        tokio::spawn(async move {
+            for byte in packet.iter() {
+                client.write_all(&[*byte]).await.unwrap();
+                client.flush().await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
 
-        let mut data_buffer = vec![0u8; data_length];
-        match self.stream.read_exact(&mut data_buffer).await {
-            Ok(_size) => (),
-            Err(_e) => return Err(TCPError::MalformedPacket)
+        let frame = connection.read_frame().await.unwrap().unwrap();
+        assert_eq!(frame.table, "foo");
+        assert_eq!(frame.data.get("a"), Some(&json!(1)));
+        assert!(connection.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn read_frames_sent_back_to_back() {
+        let (mut client, mut connection) = connected_pair().await;
+        let mut bytes = BytesMut::new();
+        for table in ["a", "b", "c"] {
+            bytes.extend_from_slice(&encoded(&json!({"command": "read", "table": table, "data": {}}), ProtocolVersion::V1));
         }
+        client.write_all(&bytes).await.unwrap();
+        drop(client);
 
-        match serde_json::from_slice::<Value>(&data_buffer) {
-            Ok(value) => Frame::from_json(value),
-            Err(_e) => Err(TCPError::MalformedJSON)
+        for table in ["a", "b", "c"] {
+            assert_eq!(connection.read_frame().await.unwrap().unwrap().table, table);
         }
+        assert!(connection.read_frame().await.unwrap().is_none());
     }
 
-    pub async fn respond(&mut self, data: Value) -> Result<usize, TCPError> {
-        let serialized = serde_json::to_string(&data).map_err(|_| TCPError::SerializeResponse)?;
-        let mut serialized_as_bytes = serialized.as_bytes().to_vec();
+    #[tokio::test]
+    async fn read_frame_cut_off() {
+        let (mut client, mut connection) = connected_pair().await;
+        let packet = encoded(&json!({"command": "read", "table": "a", "data": {}}), ProtocolVersion::V1);
+        client.write_all(&packet[..packet.len() - 1]).await.unwrap();
+        drop(client);
 
-        // There is no way this is the correct way to do this
-        let mut bytes: Vec<u8> = vec![42];
-        let res_length = serialized_as_bytes.len() as u16;
-        let res_length_bytes: [u8; 2] = res_length.to_be_bytes();
-        bytes.push(res_length_bytes[0]);
-        bytes.push(res_length_bytes[1]);
-        bytes.append(&mut serialized_as_bytes);
+        assert!(matches!(connection.read_frame().await, Err(TCPError::MalformedPacket)));
+    }
 
-        self.stream.writable().await.map_err(|_| TCPError::ConnectionNotWritable)?;
-        self.stream.try_write(&bytes).map_err(|_| TCPError::FailedWrite)
+    #[tokio::test]
+    async fn respond_uses_version_of_request() {
+        let (mut client, mut connection) = connected_pair().await;
+        client.write_all(&encoded(&json!({"command": "read", "table": "a", "data": {}}), ProtocolVersion::V2)).await.unwrap();
+        connection.read_frame().await.unwrap().unwrap();
+
+        // Too large for a v1 header, but fine for v2
+        let response = Response::ok(json!({"big": "x".repeat(100_000)}));
+        let expected = serde_json::to_value(&response).unwrap();
+        connection.respond(response).await.unwrap();
+        drop(connection);
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        let mut buffer = BytesMut::from(&received[..]);
+        assert_eq!(codec::decode(&mut buffer, codec::DEFAULT_MAX_FRAME_SIZE).unwrap(), Some((ProtocolVersion::V2, expected)));
     }
 
-    // pub async fn read_data(&mut self) -> Result<(), TCPError> {
-    //     //let mut buf = Cursor::new(&self.buffer[..]);
-    //     loop {
-    //         // Wait for the socket to be readable
-    //         match self.stream.readable().await {
-    //             Ok(()) => (),
-    //             Err(_) => return Err(UnknownError("Stream was not readable".to_string()))
-    //         }
-    //
-    //         // Try to read data, this may still fail with `WouldBlock`
-    //         // if the readiness event is a false positive.
-    //         match self.stream.try_read(&mut self.buffer) {
-    //             Ok(n) => {
-    //                 self.buffer.truncate(n);
-    //                 break;
-    //             }
-    //             Err(ref e) if e.kind() == WouldBlock => {
-    //                 continue;
-    //             }
-    //             Err(_e) => {
-    //                 return Err(UnknownError("Failed to read data from stream".to_string()));
-    //             }
-    //         }
-    //     }
-    //     println!("{:?}", self.buffer);
-    // }
+    #[tokio::test]
+    async fn respond_writes_whole_frame() {
+        let (mut client, mut connection) = connected_pair().await;
+        let response = Response::ok(json!({"big": "x".repeat(60_000)}));
+        let expected = serde_json::to_value(&response).unwrap();
+        let written = connection.respond(response).await.unwrap();
+        drop(connection);
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), written);
+        let mut buffer = BytesMut::from(&received[..]);
+        assert_eq!(codec::decode(&mut buffer, codec::DEFAULT_MAX_FRAME_SIZE).unwrap(), Some((ProtocolVersion::V1, expected)));
+    }
 }
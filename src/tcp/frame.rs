@@ -5,10 +5,21 @@ use super::TCPError;
 pub enum Command {
     Insert,
     Read,
+    Query,
+    Aggregate,
     Update,
     Delete,
     CreateTable,
     DropTable,
+    CreateIndex,
+    DropIndex,
+    Begin,
+    Commit,
+    Rollback,
+    Auth,
+    CreateUser,
+    DropUser,
+    ChangePassword,
 }
 
 impl Command {
@@ -17,15 +28,37 @@ impl Command {
             Value::String(string) => match string.as_str() {
                 "insert" => Ok(Self::Insert),
                 "read" => Ok(Self::Read),
+                "query" => Ok(Self::Query),
+                "aggregate" => Ok(Self::Aggregate),
                 "update" => Ok(Self::Update),
                 "delete" => Ok(Self::Delete),
                 "create_table" => Ok(Self::CreateTable),
                 "drop_table" => Ok(Self::DropTable),
+                "create_index" => Ok(Self::CreateIndex),
+                "drop_index" => Ok(Self::DropIndex),
+                "begin" => Ok(Self::Begin),
+                "commit" => Ok(Self::Commit),
+                "rollback" => Ok(Self::Rollback),
+                "auth" => Ok(Self::Auth),
+                "create_user" => Ok(Self::CreateUser),
+                "drop_user" => Ok(Self::DropUser),
+                "change_password" => Ok(Self::ChangePassword),
                 _ => Err(TCPError::ParseFrame("Command was not a valid value".to_string())),
             },
             _ => Err(TCPError::ParseFrame("Command was not a string".to_string()))
         }
     }
+
+    /// Whether the command works on a table. Frames for commands which don't can leave out their
+    /// `table` and `data` keys.
+    pub fn targets_table(&self) -> bool {
+        !matches!(self, Self::Begin | Self::Commit | Self::Rollback | Self::Auth | Self::CreateUser | Self::DropUser | Self::ChangePassword)
+    }
+
+    /// Whether the command creates or drops a table or an index
+    pub fn changes_schema(&self) -> bool {
+        matches!(self, Self::CreateTable | Self::DropTable | Self::CreateIndex | Self::DropIndex)
+    }
 }
 
 #[derive(Debug)]
@@ -40,13 +73,17 @@ impl Frame {
         match value {
             Value::Object(map) => {
                 let command = Command::from_value(map.get("command").ok_or(TCPError::ParseFrame("Frame did not have a 'command' key".to_string()))?)?;
-                let table = match map.get("table").ok_or(TCPError::ParseFrame("Frame did not have a 'table' key".to_string()))? {
-                    Value::String(table_name) => table_name.to_owned(),
-                    _ => return Err(TCPError::ParseFrame("Frame 'table' key was not a string".to_string()))
+                let table = match map.get("table") {
+                    Some(Value::String(table_name)) => table_name.to_owned(),
+                    Some(_) => return Err(TCPError::ParseFrame("Frame 'table' key was not a string".to_string())),
+                    None if !command.targets_table() => String::new(),
+                    None => return Err(TCPError::ParseFrame("Frame did not have a 'table' key".to_string()))
                 };
-                let data = match map.get("data").ok_or(TCPError::ParseFrame("Frame did not have a 'data' key".to_string()))? {
-                    Value::Object(obj) => obj.to_owned(),
-                    _ => return Err(TCPError::ParseFrame("Frame 'data' key was not an object".to_string()))
+                let data = match map.get("data") {
+                    Some(Value::Object(obj)) => obj.to_owned(),
+                    Some(_) => return Err(TCPError::ParseFrame("Frame 'data' key was not an object".to_string())),
+                    None if !command.targets_table() => Map::new(),
+                    None => return Err(TCPError::ParseFrame("Frame did not have a 'data' key".to_string()))
                 };
                 Ok(Self { command, table, data })
             },
@@ -1,7 +1,10 @@
 use std::fmt::{Display, Formatter};
+use response::ResponseError;
 
+pub mod codec;
 pub mod connection;
 pub mod frame;
+pub mod response;
 
 #[derive(Debug)]
 pub enum TCPError {
@@ -10,7 +13,11 @@ pub enum TCPError {
     MalformedJSON,
     MalformedPacket,
     FailedReadHeader,
+    FailedRead,
     ParseFrame(String),
+    UnsupportedVersion(u8),
+    UnsupportedFlags(u8),
+    FrameTooLarge(usize, usize),
     SerializeResponse,
     ConnectionNotWritable,
     FailedWrite,
@@ -24,7 +31,11 @@ impl Display for TCPError {
             TCPError::MalformedJSON => "Received packet with invalid JSON".to_string(),
             TCPError::MalformedPacket => "Received packet with a length that did not match header metadata".to_string(),
             TCPError::FailedReadHeader => "Failed to read the header of an incoming packet".to_string(),
+            TCPError::FailedRead => "Failed to read data from TCP connection".to_string(),
             TCPError::ParseFrame(reason) => format!("Failed to parse a frame with reason: {}", reason),
+            TCPError::UnsupportedVersion(version) => format!("Received packet with unsupported protocol version {}", version),
+            TCPError::UnsupportedFlags(flags) => format!("Received packet with unsupported header flags {:#010b}", flags),
+            TCPError::FrameTooLarge(size, max) => format!("Frame of {} bytes is larger than the maximum frame size of {} bytes", size, max),
             TCPError::SerializeResponse => "Failed to serialize a response to the requester".to_string(),
             TCPError::ConnectionNotWritable => "Failed to confirm TCP connection was writable to respond on".to_string(),
             TCPError::FailedWrite => "Failed to write response on TCP connection".to_string()
@@ -34,3 +45,39 @@ impl Display for TCPError {
 }
 
 impl std::error::Error for TCPError {}
+
+impl ResponseError for TCPError {
+    fn status(&self) -> u16 {
+        match self {
+            TCPError::InvalidStart => 400,
+            TCPError::MalformedJSON => 400,
+            TCPError::MalformedPacket => 400,
+            TCPError::FailedReadHeader => 400,
+            TCPError::FailedRead => 500,
+            TCPError::ParseFrame(_) => 400,
+            TCPError::UnsupportedVersion(_) => 400,
+            TCPError::UnsupportedFlags(_) => 400,
+            TCPError::FrameTooLarge(_, _) => 413,
+            TCPError::SerializeResponse => 500,
+            TCPError::ConnectionNotWritable => 500,
+            TCPError::FailedWrite => 500,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            TCPError::InvalidStart => "invalid_start_byte",
+            TCPError::MalformedJSON => "malformed_json",
+            TCPError::MalformedPacket => "malformed_packet",
+            TCPError::FailedReadHeader => "malformed_header",
+            TCPError::FailedRead => "connection_read_failed",
+            TCPError::ParseFrame(_) => "malformed_frame",
+            TCPError::UnsupportedVersion(_) => "unsupported_protocol_version",
+            TCPError::UnsupportedFlags(_) => "unsupported_header_flags",
+            TCPError::FrameTooLarge(_, _) => "frame_too_large",
+            TCPError::SerializeResponse => "serialize_response_failed",
+            TCPError::ConnectionNotWritable => "connection_not_writable",
+            TCPError::FailedWrite => "connection_write_failed",
+        }
+    }
+}
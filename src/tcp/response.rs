@@ -0,0 +1,45 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// An error which can be sent back to a client. Every error has an HTTP-like status and a
+/// machine-readable code, the code must never change once released as clients match on it.
+pub trait ResponseError: std::error::Error {
+    fn status(&self) -> u16;
+    fn code(&self) -> &'static str;
+
+    /// Structured information about the error for clients to act on, beyond the code
+    fn details(&self) -> Option<Value> {
+        None
+    }
+}
+
+/// A response to a single frame. Serialized as `{"code": <status>, "data": <data>}`, where the
+/// data of an error response is `{"error": <code>, "msg": <human readable message>}`, along with a
+/// `details` key for errors which have them.
+#[derive(Serialize, Debug)]
+pub struct Response {
+    #[serde(rename = "code")]
+    pub status: u16,
+    pub data: Value,
+}
+
+impl Response {
+    pub fn ok(data: Value) -> Self {
+        Self { status: 200, data }
+    }
+
+    pub fn created(data: Value) -> Self {
+        Self { status: 201, data }
+    }
+
+    pub fn error<E: ResponseError>(e: &E) -> Self {
+        let mut data = json!({
+            "error": e.code(),
+            "msg": e.to_string(),
+        });
+        if let Some(details) = e.details() {
+            data["details"] = details;
+        }
+        Self { status: e.status(), data }
+    }
+}
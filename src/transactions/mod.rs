@@ -0,0 +1,451 @@
+pub mod transaction_err;
+pub mod versions;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLockWriteGuard};
+use serde_json::Value;
+use crate::State;
+use crate::logging::log_error;
+use crate::rows::row_err::RowError;
+use crate::rows::row_err::RowError::{FailedCommit, FailedRead, FailedToFindRecord, TableConflict, TableDoesntExist, WriteConflict};
+use crate::storage::RowWrite;
+use crate::tables::{ReservedSlots, RowChange, Table};
+use versions::{Snapshot, VersionStore};
+
+/*
+    A transaction groups writes to any number of rows, across any number of tables, so that either
+    all of them are made or none are. A transaction is opened on a connection with `begin` and
+    belongs to that connection:
+
+        {"command": "begin"}
+        {"command": "insert", "table": "orders", "data": {...}}
+        {"command": "insert", "table": "line_items", "data": {...}}
+        {"command": "commit"}
+
+    Writes made inside a transaction are held in it rather than being written to storage. Reads
+    on the connection see them, but nobody else does until `commit` makes every write at once.
+    `rollback`, a failed commit, or the connection closing throws them all away. Writes made
+    outside of a transaction are made in one of their own which is committed straight away.
+
+    Every read made in a transaction sees the db as it was when the transaction began, see
+    `versions`, so rows committed by others in the meantime don't show up part way through.
+    Writes are checked against the table's schema and constraints when they are made, and then
+    again against the rows as they are when committing. If a row the transaction updates or
+    deletes has been changed by someone else's commit since the transaction began, the commit
+    fails with a conflict rather than overwriting their change, and can be retried.
+*/
+
+#[derive(Debug)]
+pub struct Transaction {
+    snapshot: Snapshot,
+    // The state the transaction leaves each row it wrote to in, by table and then `_id`
+    writes: BTreeMap<String, BTreeMap<String, PendingWrite>>,
+    // Sub_table slots held for the rows the transaction inserted, given back when it ends
+    reserved_slots: Vec<(Arc<ReservedSlots>, usize)>,
+}
+
+#[derive(Debug)]
+struct PendingWrite {
+    sub_table_index: usize,
+    // Whether the row existed before the transaction first wrote to it
+    existed: bool,
+    // `None` once the row has been deleted
+    row: Option<Value>,
+}
+
+impl Transaction {
+    /// Begin a transaction which sees the db as of the last commit
+    pub fn begin(state: &State) -> Self {
+        Self { snapshot: VersionStore::snapshot(&state.versions), writes: BTreeMap::new(), reserved_slots: Vec::new() }
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    /// Check that a table is still the one the transaction's snapshot saw, and hasn't been
+    /// dropped since, even if a new table has been created under its name
+    pub fn check_table(&self, state: &State, table_name: &str) -> Result<(), RowError> {
+        match state.versions.dropped_since(&self.snapshot, table_name) {
+            true => Err(TableConflict(table_name.to_string())),
+            false => Ok(())
+        }
+    }
+
+    /// Record a write to a row, `None` deleting it. `existed` is whether the row existed before the
+    /// transaction, and is ignored if the transaction already wrote to the row.
+    pub fn write(&mut self, table_name: &str, id: &str, sub_table_index: usize, existed: bool, row: Option<Value>) {
+        let writes = self.writes.entry(table_name.to_string()).or_default();
+        match writes.get_mut(id) {
+            Some(write) => write.row = row,
+            None => {
+                writes.insert(id.to_string(), PendingWrite { sub_table_index, existed, row });
+            }
+        }
+    }
+
+    /// Get a row as the transaction left it. Returns `None` if the transaction hasn't written to
+    /// the row, or `Some(None)` if it deleted it.
+    pub fn written_row(&self, table_name: &str, id: &str) -> Option<Option<&Value>> {
+        self.writes.get(table_name)?.get(id).map(|write| write.row.as_ref())
+    }
+
+    /// Every row of a table the transaction has written to, as it left them
+    pub fn written_rows(&self, table_name: &str) -> impl Iterator<Item = (&str, Option<&Value>)> {
+        self.writes.get(table_name).into_iter().flatten().map(|(id, write)| (id.as_str(), write.row.as_ref()))
+    }
+
+    /// Hold a sub_table slot reserved for a row the transaction inserted until the transaction
+    /// ends, by which point the row has either been counted in the table's metadata or thrown away
+    pub fn hold_slot(&mut self, reserved_slots: &Arc<ReservedSlots>, sub_table_index: usize) {
+        self.reserved_slots.push((reserved_slots.clone(), sub_table_index));
+    }
+
+    /// Make every write in the transaction at once. The tables written to are locked for the
+    /// whole commit so nobody sees only some of the writes. If any write can't be made, eg.
+    /// because it breaks a constraint or the row it updates has been changed since the
+    /// transaction began, then none are.
+    pub fn commit(mut self, state: &State) -> Result<(), RowError> {
+        // Tables are locked in order of name, so two commits can never be stuck waiting on each other
+        let handles = self.writes.keys()
+            .map(|table_name| state.get_table(table_name).ok_or(TableDoesntExist))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut tables: Vec<RwLockWriteGuard<Table>> = handles.iter().map(|table| table.write().expect("Table lock was poisoned")).collect();
+
+        for (table_name, writes) in &self.writes {
+            self.check_table(state, table_name)?;
+            let changed = writes.iter()
+                .find(|(id, write)| write.existed && state.versions.changed_since(&self.snapshot, table_name, id));
+            if let Some((id, _write)) = changed {
+                return Err(WriteConflict(id.clone()))
+            }
+        }
+
+        let mut changes = Vec::with_capacity(tables.len());
+        for (table_name, writes) in std::mem::take(&mut self.writes) {
+            changes.push(Self::changes_to(state, table_name.as_str(), writes)?);
+        }
+
+        for applied in 0..tables.len() {
+            if let Err(violation) = tables[applied].apply_unique_changes(&changes[applied]) {
+                for (table, changes) in tables[..applied].iter_mut().zip(&changes) {
+                    table.revert_unique_changes(changes);
+                }
+                return Err(RowError::ConstraintViolation(violation))
+            }
+        }
+
+        let row_writes: Vec<RowWrite> = tables.iter().zip(&changes)
+            .flat_map(|(table, changes)| changes.iter().map(|change| Self::row_write(table.name.as_str(), change)))
+            .collect();
+        if let Err(e) = state.storage.write_rows(&row_writes) {
+            log_error!("Failed to commit a transaction with error: {}", e);
+            for (table, changes) in tables.iter_mut().zip(&changes) {
+                table.revert_unique_changes(changes);
+            }
+            return Err(FailedCommit)
+        }
+        state.versions.record_commit(tables.iter().map(|table| table.name.as_str()).zip(changes.iter().map(Vec::as_slice)));
+        for (table, changes) in tables.iter_mut().zip(&changes) {
+            table.apply_index_changes(state.storage.as_ref(), changes);
+        }
+        Ok(())
+    }
+
+    /// Work out what the writes to a table change, from the rows as they are now
+    fn changes_to(state: &State, table_name: &str, writes: BTreeMap<String, PendingWrite>) -> Result<Vec<RowChange>, RowError> {
+        let mut changes = Vec::with_capacity(writes.len());
+        for (id, write) in writes {
+            let old = match write.existed {
+                true => {
+                    let old = state.storage.read_row(table_name, write.sub_table_index, id.as_str()).map_err(|_| FailedRead)?;
+                    Some(old.ok_or(FailedToFindRecord)?)
+                },
+                // A new row has a new ID, so there is nothing there to read
+                false => None
+            };
+            // The row was inserted and then deleted again, so there is nothing to write
+            if old.is_none() && write.row.is_none() {
+                continue
+            }
+            changes.push(RowChange { id, sub_table_index: write.sub_table_index, old, new: write.row });
+        }
+        Ok(changes)
+    }
+
+    fn row_write(table_name: &str, change: &RowChange) -> RowWrite {
+        let table = table_name.to_string();
+        let sub_table_index = change.sub_table_index;
+        match (&change.old, &change.new) {
+            (None, Some(row)) => RowWrite::Insert { table, sub_table_index, row: row.clone() },
+            (Some(_), Some(row)) => RowWrite::Update { table, sub_table_index, row: row.clone() },
+            (_, None) => RowWrite::Delete { table, sub_table_index, id: change.id.clone() },
+        }
+    }
+}
+
+impl Drop for Transaction {
+    // A committed transaction is dropped after its rows have been written along with the table's
+    // metadata, so its rows are never missing from both the metadata and the reservations
+    fn drop(&mut self) {
+        for (reserved_slots, sub_table_index) in self.reserved_slots.drain(..) {
+            reserved_slots.release(sub_table_index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use serde_json::{json, Map};
+    use crate::rows;
+    use crate::storage::memory::MemoryStorage;
+    use crate::tables::DEFAULT_RECORDS_PER_SUB_TABLE;
+    use crate::tcp::frame::{Command, Frame};
+
+    const THREADS: usize = 4;
+    const ROUNDS: usize = 200;
+
+    fn data(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("Request data must be an object")
+        }
+    }
+
+    fn new_state() -> State {
+        let state = State::initialize(Box::new(MemoryStorage::default()), DEFAULT_RECORDS_PER_SUB_TABLE);
+        let frame = Frame { command: Command::CreateTable, table: "accounts".to_string(), data: Map::new() };
+        Table::create_table(&state, frame).unwrap();
+        state
+    }
+
+    fn insert(state: &State, n: i64) -> String {
+        rows::insert_data(state, None, "accounts", data(json!({"n": n}))).unwrap()
+    }
+
+    fn read(state: &State, transaction: Option<&Transaction>, id: &str) -> i64 {
+        let row = rows::read_data_by_id(state, transaction, "accounts", data(json!({"_id": id}))).unwrap();
+        row["n"].as_i64().unwrap()
+    }
+
+    fn update(state: &State, transaction: Option<&mut Transaction>, id: &str, n: i64) -> Result<Value, RowError> {
+        rows::update_data(state, transaction, "accounts", data(json!({"_id": id, "data": {"n": n}})))
+    }
+
+    /// Every row of the table as a transaction sees it, sorted by `_id`
+    fn query(state: &State, transaction: Option<&Transaction>) -> Vec<Value> {
+        rows::query_data(state, transaction, "accounts", data(json!({"sort": [{"field": "_id"}]}))).unwrap()
+    }
+
+    fn count(state: &State, transaction: Option<&Transaction>) -> Value {
+        rows::aggregate_data(state, transaction, "accounts", data(json!({"aggregates": [{"op": "count"}]}))).unwrap()["count"].clone()
+    }
+
+    /// Move an amount between two rows, retrying until the commit doesn't conflict
+    fn transfer(state: &State, from: &str, to: &str, amount: i64) {
+        loop {
+            let mut transaction = Transaction::begin(state);
+            let from_n = read(state, Some(&transaction), from);
+            let to_n = read(state, Some(&transaction), to);
+            update(state, Some(&mut transaction), from, from_n - amount).unwrap();
+            update(state, Some(&mut transaction), to, to_n + amount).unwrap();
+            match transaction.commit(state) {
+                Ok(()) => return,
+                Err(WriteConflict(_)) => continue,
+                Err(e) => panic!("Transfer failed with error: {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn no_dirty_reads() {
+        let state = new_state();
+        let id = insert(&state, 0);
+
+        thread::scope(|scope| {
+            for writer in 0..THREADS {
+                let (state, id) = (&state, id.as_str());
+                scope.spawn(move || {
+                    for round in 0..ROUNDS {
+                        // Nobody else should ever see a negative row, as they are never committed
+                        let mut transaction = Transaction::begin(state);
+                        update(state, Some(&mut transaction), id, -1).unwrap();
+                        rows::insert_data(state, Some(&mut transaction), "accounts", data(json!({"n": -1}))).unwrap();
+                        if round % 2 == 0 {
+                            drop(transaction);
+                            continue
+                        }
+                        let mut transaction = Transaction::begin(state);
+                        update(state, Some(&mut transaction), id, (writer * ROUNDS + round) as i64).unwrap();
+                        match transaction.commit(state) {
+                            Ok(()) | Err(WriteConflict(_)) => (),
+                            Err(e) => panic!("Commit failed with error: {}", e)
+                        }
+                    }
+                });
+            }
+            for _reader in 0..THREADS {
+                let (state, id) = (&state, id.as_str());
+                scope.spawn(move || {
+                    for _round in 0..ROUNDS {
+                        assert!(read(state, None, id) >= 0);
+                        let transaction = Transaction::begin(state);
+                        assert!(read(state, Some(&transaction), id) >= 0);
+                        assert!(query(state, Some(&transaction)).iter().all(|row| row["n"].as_i64().unwrap() >= 0));
+                        assert_eq!(count(state, Some(&transaction)), 1);
+                    }
+                });
+            }
+        });
+        assert!(read(&state, None, id.as_str()) > 0);
+        assert_eq!(count(&state, None), 1);
+    }
+
+    #[test]
+    fn no_non_repeatable_reads() {
+        let state = new_state();
+        let ids: Vec<String> = (0..4).map(|_| insert(&state, 100)).collect();
+
+        thread::scope(|scope| {
+            for writer in 0..THREADS {
+                let (state, ids) = (&state, &ids);
+                scope.spawn(move || {
+                    for round in 0..ROUNDS {
+                        let from = ids[(writer + round) % ids.len()].as_str();
+                        let to = ids[(writer + round + 1) % ids.len()].as_str();
+                        transfer(state, from, to, 7);
+                        // Rows come and go outside of transactions too
+                        let id = insert(state, 0);
+                        rows::delete_data(state, None, "accounts", data(json!({"_id": id}))).unwrap();
+                    }
+                });
+            }
+            for _reader in 0..THREADS {
+                let (state, ids) = (&state, &ids);
+                scope.spawn(move || {
+                    for _round in 0..ROUNDS / 4 {
+                        let transaction = Transaction::begin(state);
+                        let first_reads: Vec<i64> = ids.iter().map(|id| read(state, Some(&transaction), id)).collect();
+                        let first_query = query(state, Some(&transaction));
+                        let first_count = count(state, Some(&transaction));
+                        // Every snapshot has all of a transfer or none of it
+                        assert_eq!(first_reads.iter().sum::<i64>(), 400);
+                        for _again in 0..5 {
+                            thread::yield_now();
+                            let reads: Vec<i64> = ids.iter().map(|id| read(state, Some(&transaction), id)).collect();
+                            assert_eq!(reads, first_reads);
+                            assert_eq!(query(state, Some(&transaction)), first_query);
+                            assert_eq!(count(state, Some(&transaction)), first_count);
+                        }
+                    }
+                });
+            }
+        });
+        let total: i64 = ids.iter().map(|id| read(&state, None, id)).sum();
+        assert_eq!(total, 400);
+    }
+
+    #[test]
+    fn no_lost_updates() {
+        let state = new_state();
+        let id = insert(&state, 0);
+
+        thread::scope(|scope| {
+            for _writer in 0..THREADS {
+                let (state, id) = (&state, id.as_str());
+                scope.spawn(move || {
+                    for _round in 0..ROUNDS {
+                        loop {
+                            let mut transaction = Transaction::begin(state);
+                            let n = read(state, Some(&transaction), id);
+                            update(state, Some(&mut transaction), id, n + 1).unwrap();
+                            match transaction.commit(state) {
+                                Ok(()) => break,
+                                Err(WriteConflict(_)) => continue,
+                                Err(e) => panic!("Increment failed with error: {}", e)
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(read(&state, None, id.as_str()), (THREADS * ROUNDS) as i64);
+    }
+
+    #[test]
+    fn conflicting_commit_is_rejected() {
+        let state = new_state();
+        let id = insert(&state, 0);
+
+        let mut first = Transaction::begin(&state);
+        let mut second = Transaction::begin(&state);
+        update(&state, Some(&mut first), id.as_str(), 1).unwrap();
+        update(&state, Some(&mut second), id.as_str(), 2).unwrap();
+        first.commit(&state).unwrap();
+        assert!(matches!(second.commit(&state), Err(WriteConflict(conflicting_id)) if conflicting_id == id));
+        assert_eq!(read(&state, None, id.as_str()), 1);
+
+        // Writes outside of a transaction retry rather than conflict
+        let mut third = Transaction::begin(&state);
+        update(&state, None, id.as_str(), 3).unwrap();
+        update(&state, Some(&mut third), id.as_str(), 4).unwrap();
+        assert!(matches!(third.commit(&state), Err(WriteConflict(_))));
+        assert_eq!(read(&state, None, id.as_str()), 3);
+    }
+
+    #[test]
+    fn recreated_table_conflicts_with_open_transactions() {
+        let state = new_state();
+        let id = insert(&state, 1);
+        let mut transaction = Transaction::begin(&state);
+        assert_eq!(read(&state, Some(&transaction), id.as_str()), 1);
+        rows::insert_data(&state, Some(&mut transaction), "accounts", data(json!({"n": 2}))).unwrap();
+
+        // The table is dropped and created again with a row of its own under the same name
+        Table::drop_table(&state, Frame { command: Command::DropTable, table: "accounts".to_string(), data: Map::new() }).unwrap();
+        let frame = Frame { command: Command::CreateTable, table: "accounts".to_string(), data: Map::new() };
+        Table::create_table(&state, frame).unwrap();
+        insert(&state, 3);
+
+        let conflict = |error: RowError| matches!(error, TableConflict(table) if table == "accounts");
+        assert!(conflict(rows::read_data_by_id(&state, Some(&transaction), "accounts", data(json!({"_id": id}))).unwrap_err()));
+        assert!(conflict(rows::query_data(&state, Some(&transaction), "accounts", Map::new()).unwrap_err()));
+        assert!(conflict(rows::aggregate_data(&state, Some(&transaction), "accounts", data(json!({"aggregates": [{"op": "count"}]}))).unwrap_err()));
+        assert!(conflict(transaction.commit(&state).unwrap_err()));
+
+        // Nothing the transaction wrote made it into the new table
+        assert_eq!(query(&state, None).iter().map(|row| row["n"].clone()).collect::<Vec<_>>(), vec![json!(3)]);
+        let fresh = Transaction::begin(&state);
+        assert_eq!(count(&state, Some(&fresh)), json!(1));
+    }
+
+    #[test]
+    fn superseded_versions_are_collected() {
+        let state = new_state();
+        let id = insert(&state, 0);
+        let deleted = insert(&state, 0);
+
+        let transaction = Transaction::begin(&state);
+        for n in 1..=3 {
+            update(&state, None, id.as_str(), n).unwrap();
+        }
+        rows::delete_data(&state, None, "accounts", data(json!({"_id": deleted}))).unwrap();
+        insert(&state, 4);
+
+        // The open transaction still sees the table as it was, so nothing can be collected
+        state.versions.collect_garbage();
+        assert_eq!(read(&state, Some(&transaction), id.as_str()), 0);
+        assert_eq!(read(&state, Some(&transaction), deleted.as_str()), 0);
+        assert_eq!(query(&state, Some(&transaction)).len(), 2);
+        assert_eq!(count(&state, Some(&transaction)), 2);
+        assert_eq!(state.versions.len(), 5);
+
+        drop(transaction);
+        assert_eq!(state.versions.collect_garbage(), 5);
+        assert_eq!(state.versions.len(), 0);
+        assert_eq!(read(&state, None, id.as_str()), 3);
+        assert_eq!(count(&state, None), 2);
+    }
+}
@@ -0,0 +1,40 @@
+use std::fmt::{Display, Formatter};
+use crate::tcp::response::ResponseError;
+
+#[derive(Debug)]
+pub enum TransactionError {
+    AlreadyOpen,
+    NotOpen,
+    SchemaChange,
+}
+
+impl Display for TransactionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let err_msg: String = match self {
+            TransactionError::AlreadyOpen => "Tried to begin a transaction while one is already open".to_string(),
+            TransactionError::NotOpen => "Tried to end a transaction when none is open".to_string(),
+            TransactionError::SchemaChange => "Tables and indexes can't be created or dropped inside a transaction".to_string(),
+        };
+        write!(f, "{}", err_msg)
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+impl ResponseError for TransactionError {
+    fn status(&self) -> u16 {
+        match self {
+            TransactionError::AlreadyOpen => 409,
+            TransactionError::NotOpen => 409,
+            TransactionError::SchemaChange => 400,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            TransactionError::AlreadyOpen => "transaction_already_open",
+            TransactionError::NotOpen => "no_transaction",
+            TransactionError::SchemaChange => "schema_change_in_transaction",
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use serde_json::Value;
+use crate::tables::RowChange;
+
+/*
+    Storage only ever holds the latest version of a row. Every commit is given a timestamp, one
+    higher than the last, and each row it changes has the version it replaced kept here along
+    with that timestamp. A transaction reads as of the timestamp of the last commit before it
+    began, its snapshot, so for any row changed since then it sees the version kept here rather
+    than the one in storage:
+
+        row 0.abc: stored {"n": 3}, superseded [(ts 4, {"n": 1}), (ts 7, {"n": 2})]
+
+        snapshot 3 sees {"n": 1}, snapshot 5 sees {"n": 2}, snapshot 7 or later sees {"n": 3}
+
+    An inserted row replaces `None`, so snapshots from before its insert don't see it, and a
+    deleted row is replaced by `None`, so they still do.
+
+    Dropping a table throws away every version of its rows and takes a timestamp of its own. A
+    snapshot from before the drop can no longer see the table as it was, and mustn't mistake a
+    new table created under the same name for it, so `dropped_since` tells it the table is gone.
+
+    A version is only kept while some open snapshot is older than the commit that replaced it,
+    anything older than every open snapshot is thrown away by `collect_garbage`.
+*/
+
+#[derive(Debug, Default)]
+pub struct VersionStore {
+    inner: Mutex<Versions>,
+}
+
+#[derive(Debug, Default)]
+struct Versions {
+    // The timestamp of the last commit
+    last_commit: u64,
+    // How many open snapshots there are at each timestamp
+    open_snapshots: BTreeMap<u64, usize>,
+    // The versions each row has had replaced, by table and then `_id`, oldest first
+    superseded: HashMap<String, HashMap<String, Vec<Superseded>>>,
+    // The timestamp each table was last dropped at
+    dropped: HashMap<String, u64>,
+}
+
+#[derive(Debug)]
+struct Superseded {
+    // The timestamp of the commit which replaced the version
+    at: u64,
+    row: Option<Value>,
+}
+
+/// A view of the db as of the last commit before it was taken, held open until it is dropped
+#[derive(Debug)]
+pub struct Snapshot {
+    versions: Arc<VersionStore>,
+    pub timestamp: u64,
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut versions = self.versions.inner.lock().expect("Version store lock was poisoned");
+        if let Some(count) = versions.open_snapshots.get_mut(&self.timestamp) {
+            *count -= 1;
+            if *count == 0 {
+                versions.open_snapshots.remove(&self.timestamp);
+            }
+        }
+    }
+}
+
+impl Versions {
+    fn superseded(&self, table_name: &str, id: &str) -> &[Superseded] {
+        self.superseded.get(table_name).and_then(|rows| rows.get(id)).map(Vec::as_slice).unwrap_or_default()
+    }
+}
+
+impl VersionStore {
+    /// Take a snapshot of the db as of the last commit
+    pub fn snapshot(versions: &Arc<VersionStore>) -> Snapshot {
+        let mut inner = versions.inner.lock().expect("Version store lock was poisoned");
+        let timestamp = inner.last_commit;
+        *inner.open_snapshots.entry(timestamp).or_default() += 1;
+        Snapshot { versions: Arc::clone(versions), timestamp }
+    }
+
+    /// Keep the versions a commit replaced, giving the commit the next timestamp. Must be called
+    /// while the tables written to are still locked, so nobody reads the new versions from
+    /// storage before the old ones are kept.
+    pub fn record_commit<'a>(&self, changes: impl IntoIterator<Item = (&'a str, &'a [RowChange])>) {
+        let mut inner = self.inner.lock().expect("Version store lock was poisoned");
+        inner.last_commit += 1;
+        let at = inner.last_commit;
+        for (table_name, changes) in changes {
+            let rows = inner.superseded.entry(table_name.to_string()).or_default();
+            for change in changes {
+                rows.entry(change.id.clone()).or_default().push(Superseded { at, row: change.old.clone() });
+            }
+        }
+    }
+
+    /// Whether a row has been changed by a commit made after a snapshot was taken
+    pub fn changed_since(&self, snapshot: &Snapshot, table_name: &str, id: &str) -> bool {
+        let inner = self.inner.lock().expect("Version store lock was poisoned");
+        inner.superseded(table_name, id).last().is_some_and(|version| version.at > snapshot.timestamp)
+    }
+
+    /// Whether any row of a table has been changed by a commit made after a snapshot was taken
+    pub fn table_changed_since(&self, snapshot: &Snapshot, table_name: &str) -> bool {
+        let inner = self.inner.lock().expect("Version store lock was poisoned");
+        inner.superseded.get(table_name).is_some_and(|rows| {
+            rows.values().any(|versions| versions.last().is_some_and(|version| version.at > snapshot.timestamp))
+        })
+    }
+
+    /// Get the version of a row a snapshot sees, given the version in storage
+    pub fn visible(&self, snapshot: &Snapshot, table_name: &str, id: &str, stored: Option<Value>) -> Option<Value> {
+        let inner = self.inner.lock().expect("Version store lock was poisoned");
+        // The first version replaced after the snapshot was taken is the one it saw
+        match inner.superseded(table_name, id).iter().find(|version| version.at > snapshot.timestamp) {
+            Some(version) => version.row.clone(),
+            None => stored
+        }
+    }
+
+    /// Every row of a table changed since a snapshot was taken, with the version the snapshot
+    /// sees. These are the rows whose stored version can't be used by the snapshot.
+    pub fn visible_changes(&self, snapshot: &Snapshot, table_name: &str) -> Vec<(String, Option<Value>)> {
+        let inner = self.inner.lock().expect("Version store lock was poisoned");
+        let Some(rows) = inner.superseded.get(table_name) else {
+            return Vec::new()
+        };
+        rows.iter()
+            .filter_map(|(id, versions)| {
+                let version = versions.iter().find(|version| version.at > snapshot.timestamp)?;
+                Some((id.clone(), version.row.clone()))
+            })
+            .collect()
+    }
+
+    /// Whether a table has been dropped since a snapshot was taken, even if it has been created
+    /// again since
+    pub fn dropped_since(&self, snapshot: &Snapshot, table_name: &str) -> bool {
+        let inner = self.inner.lock().expect("Version store lock was poisoned");
+        inner.dropped.get(table_name).is_some_and(|at| *at > snapshot.timestamp)
+    }
+
+    /// Forget every version of a dropped table's rows, giving the drop the next timestamp
+    pub fn forget_table(&self, table_name: &str) {
+        let mut inner = self.inner.lock().expect("Version store lock was poisoned");
+        inner.last_commit += 1;
+        let at = inner.last_commit;
+        inner.superseded.remove(table_name);
+        inner.dropped.insert(table_name.to_string(), at);
+    }
+
+    /// Throw away every version no open snapshot can see, returning how many were thrown away
+    pub fn collect_garbage(&self) -> usize {
+        let mut inner = self.inner.lock().expect("Version store lock was poisoned");
+        let oldest = inner.open_snapshots.keys().next().copied().unwrap_or(inner.last_commit);
+        let mut collected = 0;
+        inner.superseded.retain(|_table_name, rows| {
+            rows.retain(|_id, versions| {
+                let before = versions.len();
+                versions.retain(|version| version.at > oldest);
+                collected += before - versions.len();
+                !versions.is_empty()
+            });
+            !rows.is_empty()
+        });
+        inner.dropped.retain(|_table_name, at| *at > oldest);
+        collected
+    }
+
+    /// How many versions are being kept
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        let inner = self.inner.lock().expect("Version store lock was poisoned");
+        inner.superseded.values().flat_map(HashMap::values).map(Vec::len).sum()
+    }
+}